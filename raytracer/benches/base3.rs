@@ -0,0 +1,148 @@
+// Elementwise `Vec3` arithmetic against the same kernel on hand-written SSE
+// vectors padded to whole registers, which is what a SIMD backend for
+// `Base3` would have to beat. Run with `cargo bench --bench base3`.
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+use raytracer::*;
+
+const COUNT: usize = 1 << 16;
+const ROUNDS: u32 = 20;
+
+fn main() {
+    let vector = |i: usize, offset: Real| {
+        let i = i as Real + offset;
+        [i.sin(), i.cos(), 0.5 * i.sin() * i.cos()]
+    };
+    let a: Vec<[Real; 3]> = (0..COUNT).map(|i| vector(i, 0.0)).collect();
+    let b: Vec<[Real; 3]> = (0..COUNT).map(|i| vector(i, 0.3)).collect();
+    let c: Vec<[Real; 3]> = (0..COUNT).map(|i| vector(i, 0.7)).collect();
+    let scale = black_box(0.75);
+
+    let (a3, b3, c3) = (to_vec3(&a), to_vec3(&b), to_vec3(&c));
+    let mut scalar_sum = 0.0;
+    let scalar = time(|| {
+        let mut sum = 0.0;
+        for ((a, b), c) in a3.iter().zip(&b3).zip(&c3) {
+            sum += (a + &(b * scale)).dot(c);
+        }
+        scalar_sum = black_box(sum);
+    });
+    report("vec3", scalar);
+
+    #[cfg(target_arch = "x86_64")]
+    {
+        let (a4, b4, c4) = (sse::load(&a), sse::load(&b), sse::load(&c));
+        let mut packed_sum = 0.0;
+        let packed = time(|| {
+            let mut sum = 0.0;
+            for ((a, b), c) in a4.iter().zip(&b4).zip(&c4) {
+                sum += a.add(&b.scale(scale)).dot(c);
+            }
+            packed_sum = black_box(sum);
+        });
+        report("sse", packed);
+        let error = ((packed_sum - scalar_sum) / scalar_sum).abs();
+        assert!(
+            error < 1e-4,
+            "sse sum {} against {}",
+            packed_sum,
+            scalar_sum
+        );
+    }
+}
+
+fn to_vec3(values: &[[Real; 3]]) -> Vec<Vec3<Real>> {
+    values.iter().map(|&value| Vec3(value.into())).collect()
+}
+
+fn time<F: FnMut()>(mut f: F) -> Duration {
+    f();
+    (0..ROUNDS)
+        .map(|_| {
+            let start = Instant::now();
+            f();
+            start.elapsed()
+        })
+        .min()
+        .unwrap()
+}
+
+fn report(name: &str, elapsed: Duration) {
+    println!(
+        "{:<6} {:>10.2} M/s ({:?})",
+        name,
+        COUNT as f64 / elapsed.as_secs_f64() / 1e6,
+        elapsed
+    );
+}
+
+// x and y share a register and z gets one of its own, half wasted.
+#[cfg(all(target_arch = "x86_64", not(feature = "f32")))]
+mod sse {
+    use std::arch::x86_64::*;
+
+    #[derive(Clone, Copy)]
+    pub struct Packed(__m128d, __m128d);
+
+    pub fn load(values: &[[f64; 3]]) -> Vec<Packed> {
+        values
+            .iter()
+            .map(|&[x, y, z]| unsafe { Packed(_mm_set_pd(y, x), _mm_set_pd(0.0, z)) })
+            .collect()
+    }
+
+    impl Packed {
+        pub fn add(&self, other: &Self) -> Self {
+            unsafe { Packed(_mm_add_pd(self.0, other.0), _mm_add_pd(self.1, other.1)) }
+        }
+
+        pub fn scale(&self, value: f64) -> Self {
+            unsafe {
+                let value = _mm_set1_pd(value);
+                Packed(_mm_mul_pd(self.0, value), _mm_mul_pd(self.1, value))
+            }
+        }
+
+        pub fn dot(&self, other: &Self) -> f64 {
+            unsafe {
+                let sum = _mm_add_pd(_mm_mul_pd(self.0, other.0), _mm_mul_pd(self.1, other.1));
+                _mm_cvtsd_f64(_mm_add_sd(sum, _mm_unpackhi_pd(sum, sum)))
+            }
+        }
+    }
+}
+
+// All three in one register, the fourth lane zero.
+#[cfg(all(target_arch = "x86_64", feature = "f32"))]
+mod sse {
+    use std::arch::x86_64::*;
+
+    #[derive(Clone, Copy)]
+    pub struct Packed(__m128);
+
+    pub fn load(values: &[[f32; 3]]) -> Vec<Packed> {
+        values
+            .iter()
+            .map(|&[x, y, z]| unsafe { Packed(_mm_set_ps(0.0, z, y, x)) })
+            .collect()
+    }
+
+    impl Packed {
+        pub fn add(&self, other: &Self) -> Self {
+            unsafe { Packed(_mm_add_ps(self.0, other.0)) }
+        }
+
+        pub fn scale(&self, value: f32) -> Self {
+            unsafe { Packed(_mm_mul_ps(self.0, _mm_set1_ps(value))) }
+        }
+
+        pub fn dot(&self, other: &Self) -> f32 {
+            unsafe {
+                let product = _mm_mul_ps(self.0, other.0);
+                let pairs = _mm_add_ps(product, _mm_movehl_ps(product, product));
+                _mm_cvtss_f32(_mm_add_ss(pairs, _mm_shuffle_ps(pairs, pairs, 1)))
+            }
+        }
+    }
+}
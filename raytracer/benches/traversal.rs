@@ -0,0 +1,191 @@
+// Scalar against packet traversal on a field of spheres around a triangle
+// mesh, all traced by coherent primary rays. Run with `cargo bench`.
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+use rand::prelude::*;
+use raytracer::*;
+
+const WIDTH: u32 = 256;
+const HEIGHT: u32 = 256;
+const ROUNDS: u32 = 5;
+
+fn main() {
+    let world = scene();
+    let bvh = Bvh::new(&world);
+    let rays = primary_rays();
+    let count = rays.len() as u32 * 4;
+
+    let linear = time(|| {
+        for packet in &rays {
+            for ray in packet {
+                black_box((&world).hit(ray, 0.0, Real::INFINITY));
+            }
+        }
+    });
+    let scalar = time(|| {
+        for packet in &rays {
+            for ray in packet {
+                black_box((&bvh).hit(ray, 0.0, Real::INFINITY));
+            }
+        }
+    });
+    let packets = time(|| {
+        for packet in &rays {
+            black_box(bvh.hit_packet(packet, 0.0, Real::INFINITY));
+        }
+    });
+    report("linear", count, linear);
+    report("bvh", count, scalar);
+    report("bvh packets", count, packets);
+
+    // Both traversals must agree on what every ray hits, up to rays grazing a
+    // sphere that rounding alone can flip between hit and miss in f32
+    let mut mismatches = 0;
+    for packet in &rays {
+        let hits = bvh.hit_packet(packet, 0.0, Real::INFINITY);
+        for (ray, hit) in packet.iter().zip(&hits) {
+            let expected = (&bvh).hit(ray, 0.0, Real::INFINITY);
+            match (hit, &expected) {
+                (Some(a), Some(b)) if (a.t - b.t).abs() <= 1e-4 * b.t.max(1.0) => {}
+                (None, None) => {}
+                _ => mismatches += 1,
+            }
+        }
+    }
+    println!("{} of {} rays disagree", mismatches, count);
+
+    let vectors: Vec<Vec3<Real>> = (0..4096)
+        .map(|i| Vec3([i as Real, 1.0 - i as Real, 0.5].into()))
+        .collect();
+    let dots = time(|| {
+        for pair in vectors.chunks(2) {
+            black_box(pair[0].dot(&pair[1]).sqrt());
+        }
+    });
+    // Lanes are filled up front, as packets are: gathering four vectors into
+    // lanes costs more than the dot products themselves
+    let lanes: Vec<Vec3x4> = vectors
+        .chunks(4)
+        .map(|group| Vec3x4::new([&group[0].0, &group[1].0, &group[2].0, &group[3].0]))
+        .collect();
+    let wide_dots = time(|| {
+        for pair in lanes.chunks(2) {
+            black_box(pair[0].dot(&pair[1]).sqrt());
+        }
+    });
+    report("vec3 dot", vectors.len() as u32 / 2, dots);
+    report("vec3x4 dot", vectors.len() as u32 / 2, wide_dots);
+}
+
+fn time<F: FnMut()>(mut f: F) -> Duration {
+    f();
+    (0..ROUNDS)
+        .map(|_| {
+            let start = Instant::now();
+            f();
+            start.elapsed()
+        })
+        .min()
+        .unwrap()
+}
+
+fn report(name: &str, count: u32, elapsed: Duration) {
+    println!(
+        "{:<12} {:>10.2} M/s ({:?})",
+        name,
+        count as f64 / elapsed.as_secs_f64() / 1e6,
+        elapsed
+    );
+}
+
+// Rays through 2x2 pixel blocks, one packet per block.
+fn primary_rays() -> Vec<[Ray<Real>; 4]> {
+    let origin = Point3([13.0, 2.0, 3.0].into());
+    let ray = |x: u32, y: u32| {
+        let u = (x as Real + 0.5) / WIDTH as Real - 0.5;
+        let v = (y as Real + 0.5) / HEIGHT as Real - 0.5;
+        Ray {
+            origin: origin.clone(),
+            direction: Vec3([-1.0, 0.4 * v - 0.15, 0.4 * u - 0.2].into()),
+        }
+    };
+    let mut rays = Vec::new();
+    for y in (0..HEIGHT).step_by(2) {
+        for x in (0..WIDTH).step_by(2) {
+            rays.push([ray(x, y), ray(x + 1, y), ray(x, y + 1), ray(x + 1, y + 1)]);
+        }
+    }
+    rays
+}
+
+fn scene() -> HittableVec<Real> {
+    let material = Materials::Lambertian(Lambertian {
+        albedo: Color([0.5, 0.5, 0.5].into()),
+    });
+    let mut world = HittableVec::default();
+    world.push(Hittable::Sphere(Sphere::new(
+        Point3([0.0, -1000.0, 0.0].into()),
+        1000.0,
+        material.clone(),
+    )));
+    let mut rng = StdRng::seed_from_u64(0);
+    for a in -11..11 {
+        for b in -11..11 {
+            world.push(Hittable::Sphere(Sphere::new(
+                Point3(
+                    [
+                        a as Real + 0.9 * rng.gen_range(0.0, 1.0),
+                        0.2,
+                        b as Real + 0.9 * rng.gen_range(0.0, 1.0),
+                    ]
+                    .into(),
+                ),
+                0.2,
+                material.clone(),
+            )));
+        }
+    }
+    world.push(Hittable::Mesh(sphere_mesh(64, 32, material)));
+    world
+}
+
+// Latitude-longitude sphere of radius 1 resting on the ground.
+fn sphere_mesh(segments: usize, rings: usize, material: Materials<Real>) -> Mesh<Real> {
+    let mut positions = Vec::new();
+    for ring in 0..=rings {
+        let theta = consts::PI * ring as Real / rings as Real;
+        for segment in 0..=segments {
+            let phi = 2.0 * consts::PI * segment as Real / segments as Real;
+            positions.push(Point3(
+                [
+                    theta.sin() * phi.cos(),
+                    1.0 + theta.cos(),
+                    theta.sin() * phi.sin(),
+                ]
+                .into(),
+            ));
+        }
+    }
+    let mut triangles = Vec::new();
+    for ring in 0..rings {
+        for segment in 0..segments {
+            let a = ring * (segments + 1) + segment;
+            let b = a + segments + 1;
+            for positions in [[a, a + 1, b + 1], [a, b + 1, b]] {
+                triangles.push(MeshTriangle {
+                    positions,
+                    ..Default::default()
+                });
+            }
+        }
+    }
+    Mesh::new(
+        positions,
+        Vec::new(),
+        Vec::new(),
+        Vec::new(),
+        triangles,
+        material,
+    )
+}
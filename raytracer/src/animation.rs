@@ -0,0 +1,366 @@
+use crate::base::color::Color;
+use crate::base::point3::Point3;
+use crate::base::quaternion::Quaternion;
+use crate::base::vec3::Vec3;
+use crate::base::Real;
+use crate::camera::{Camera, PhysicalCamera};
+use crate::hittable::{Hittable, HittableVec, Transform, Transformed};
+use crate::materials::Materials;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Interpolation {
+    Linear,
+    // Catmull-Rom through the keys, so the value passes through every key
+    // with a continuous first derivative.
+    Cubic,
+}
+
+pub trait Interpolate: Clone {
+    fn weighted_sum(values: [&Self; 4], weights: [Real; 4]) -> Self;
+
+    // Value a fraction `t` of the way from `values[1]` to `values[2]`, with
+    // the keys either side of them for cubic interpolation.
+    fn interpolate(values: [&Self; 4], t: Real, interpolation: Interpolation) -> Self {
+        let weights = match interpolation {
+            Interpolation::Linear => [0.0, 1.0 - t, t, 0.0],
+            Interpolation::Cubic => {
+                let (t2, t3) = (t * t, t * t * t);
+                [
+                    (-t3 + 2.0 * t2 - t) / 2.0,
+                    (3.0 * t3 - 5.0 * t2 + 2.0) / 2.0,
+                    (-3.0 * t3 + 4.0 * t2 + t) / 2.0,
+                    (t3 - t2) / 2.0,
+                ]
+            }
+        };
+        Self::weighted_sum(values, weights)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Track<V> {
+    keys: Vec<(Real, V)>,
+    pub interpolation: Interpolation,
+}
+
+#[derive(Debug, Clone)]
+pub struct CameraAnimation {
+    pub look_from: Track<Point3<Real>>,
+    pub look_at: Track<Point3<Real>>,
+    pub up: Vec3<Real>,
+    pub vertical_fov: Track<Real>,
+    pub aspect_ratio: Real,
+    pub aperture: Track<Real>,
+    pub focus_dist: Track<Real>,
+    // Replaces the field of view and aperture tracks when set
+    pub physical: Option<PhysicalCamera<Real>>,
+}
+
+#[derive(Debug, Clone)]
+pub struct ObjectAnimation {
+    pub object: Hittable<Real>,
+    pub translation: Track<Vec3<Real>>,
+    pub rotation: Track<Quaternion<Real>>,
+    pub scale: Track<Real>,
+    pub albedo: Option<Track<Color<Real>>>,
+    pub fuzz: Option<Track<Real>>,
+    pub ref_idx: Option<Track<Real>>,
+}
+
+#[derive(Debug, Clone)]
+pub struct SceneAnimation {
+    pub camera: CameraAnimation,
+    pub world: HittableVec<Real>,
+    pub objects: Vec<ObjectAnimation>,
+}
+
+impl Interpolate for Real {
+    fn weighted_sum(values: [&Self; 4], weights: [Real; 4]) -> Self {
+        values
+            .iter()
+            .zip(&weights)
+            .map(|(value, weight)| *value * weight)
+            .sum()
+    }
+}
+
+macro_rules! impl_interpolate {
+    ($name:ident) => {
+        impl Interpolate for $name<Real> {
+            fn weighted_sum(values: [&Self; 4], weights: [Real; 4]) -> Self {
+                values[0] * weights[0]
+                    + values[1] * weights[1]
+                    + values[2] * weights[2]
+                    + values[3] * weights[3]
+            }
+        }
+    };
+}
+
+impl_interpolate!(Vec3);
+impl_interpolate!(Point3);
+impl_interpolate!(Color);
+
+// Rotations always slerp between the two keys around `t`: a weighted sum of
+// quaternions leaves the unit sphere, and one of Euler angles takes a
+// different path for every order the angles could be applied in.
+impl Interpolate for Quaternion<Real> {
+    fn weighted_sum(values: [&Self; 4], weights: [Real; 4]) -> Self {
+        let (v, w) = values
+            .iter()
+            .zip(&weights)
+            .fold((Vec3::default(), 0.0), |(v, w), (value, weight)| {
+                (v + &value.v * *weight, w + value.w * weight)
+            });
+        Quaternion { v, w }.normalized()
+    }
+
+    fn interpolate(values: [&Self; 4], t: Real, _interpolation: Interpolation) -> Self {
+        values[1].slerp(values[2], t)
+    }
+}
+
+impl<V: Interpolate> Track<V> {
+    pub fn new(interpolation: Interpolation) -> Self {
+        Self {
+            keys: Vec::new(),
+            interpolation,
+        }
+    }
+
+    pub fn constant(value: V) -> Self {
+        Self::new(Interpolation::Linear).key(0.0, value)
+    }
+
+    pub fn key(mut self, time: Real, value: V) -> Self {
+        let index = self.keys.partition_point(|(key_time, _)| *key_time <= time);
+        self.keys.insert(index, (time, value));
+        self
+    }
+
+    // Holds the first and last key outside the keyed range.
+    pub fn at(&self, time: Real) -> V {
+        let last = self.keys.len().checked_sub(1).expect("track has no keys");
+        let next = self.keys.partition_point(|(key_time, _)| *key_time <= time);
+        if next == 0 {
+            return self.keys[0].1.clone();
+        }
+        if next > last {
+            return self.keys[last].1.clone();
+        }
+
+        let (i1, i2) = (next - 1, next);
+        let (t1, t2) = (self.keys[i1].0, self.keys[i2].0);
+        let t = (time - t1) / (t2 - t1);
+        let value = |index: usize| &self.keys[index.min(last)].1;
+        V::interpolate(
+            [
+                value(i1.saturating_sub(1)),
+                value(i1),
+                value(i2),
+                value(i2 + 1),
+            ],
+            t,
+            self.interpolation,
+        )
+    }
+}
+
+impl CameraAnimation {
+    pub fn new(
+        look_from: Point3<Real>,
+        look_at: Point3<Real>,
+        up: Vec3<Real>,
+        vfov: Real,
+        aspect_ratio: Real,
+        aperture: Real,
+        focus_dist: Real,
+    ) -> Self {
+        Self {
+            look_from: Track::constant(look_from),
+            look_at: Track::constant(look_at),
+            up,
+            vertical_fov: Track::constant(vfov),
+            aspect_ratio,
+            aperture: Track::constant(aperture),
+            focus_dist: Track::constant(focus_dist),
+            physical: None,
+        }
+    }
+
+    pub fn at(&self, time: Real) -> Camera<Real> {
+        let look_from = self.look_from.at(time);
+        let look_at = self.look_at.at(time);
+        let focus_dist = self.focus_dist.at(time);
+        match &self.physical {
+            Some(physical) => {
+                Camera::physical(look_from, look_at, self.up.clone(), physical, focus_dist)
+            }
+            None => Camera::new(
+                look_from,
+                look_at,
+                self.up.clone(),
+                self.vertical_fov.at(time),
+                self.aspect_ratio,
+                self.aperture.at(time),
+                focus_dist,
+            ),
+        }
+    }
+}
+
+impl ObjectAnimation {
+    pub fn new(object: Hittable<Real>) -> Self {
+        Self {
+            object,
+            translation: Track::constant(Vec3::default()),
+            rotation: Track::constant(Quaternion::identity()),
+            scale: Track::constant(1.0),
+            albedo: None,
+            fuzz: None,
+            ref_idx: None,
+        }
+    }
+
+    pub fn at(&self, time: Real) -> Hittable<Real> {
+        let mut object = self.object.clone();
+        self.animate_materials(&mut object, time);
+        Hittable::Transformed(Transformed {
+            object: Box::new(object),
+            transform: Transform::new(
+                self.translation.at(time),
+                &self.rotation.at(time),
+                self.scale.at(time),
+            ),
+        })
+    }
+
+    // Material tracks only touch the materials that have the parameter, so
+    // a fuzz track leaves the lambertian parts of a group alone.
+    fn animate_materials(&self, object: &mut Hittable<Real>, time: Real) {
+        match object {
+            Hittable::Sphere(sphere) => self.animate_material(&mut sphere.material, time),
+            Hittable::Mesh(mesh) => self.animate_material(&mut mesh.material, time),
+            Hittable::HittableVec(vec) => {
+                for object in vec.objects.iter_mut() {
+                    self.animate_materials(object, time);
+                }
+            }
+            Hittable::Transformed(transformed) => {
+                self.animate_materials(&mut transformed.object, time)
+            }
+        }
+    }
+
+    fn animate_material(&self, material: &mut Materials<Real>, time: Real) {
+        match material {
+            Materials::Lambertian(lambertian) => {
+                if let Some(albedo) = &self.albedo {
+                    lambertian.albedo = albedo.at(time);
+                }
+            }
+            Materials::Metal(metal) => {
+                if let Some(albedo) = &self.albedo {
+                    metal.albedo = albedo.at(time);
+                }
+                if let Some(fuzz) = &self.fuzz {
+                    metal.fuzz = fuzz.at(time);
+                }
+            }
+            Materials::Dielectric(dielectric) => {
+                if let Some(ref_idx) = &self.ref_idx {
+                    dielectric.ref_idx = ref_idx.at(time);
+                }
+            }
+            Materials::DiffuseLight(_) => {}
+        }
+    }
+}
+
+impl SceneAnimation {
+    pub fn world_at(&self, time: Real) -> HittableVec<Real> {
+        let mut world = self.world.clone();
+        for object in &self.objects {
+            world.push(object.at(time));
+        }
+        world
+    }
+
+    pub fn camera_at(&self, time: Real) -> Camera<Real> {
+        self.camera.at(time)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::base::vec3::Length;
+
+    fn assert_close(a: Real, b: Real) {
+        assert!((a - b).abs() < 1e-4, "{} != {}", a, b);
+    }
+
+    #[test]
+    fn cubic_tracks_pass_through_their_keys() {
+        let track = Track::new(Interpolation::Cubic)
+            .key(2.0, 1.0)
+            .key(0.0, 0.0)
+            .key(1.0, 2.0)
+            .key(3.0, 5.0);
+        for (time, value) in [(0.0, 0.0), (1.0, 2.0), (2.0, 1.0), (3.0, 5.0)] {
+            assert_close(track.at(time), value);
+        }
+        // Weights of -1/16, 9/16, 9/16 and -1/16 halfway
+        assert_close(track.at(1.5), 1.375);
+        // The first key stands in for the missing one before it
+        assert_close(track.at(0.5), (9.0 * 2.0 - 1.0) / 16.0);
+        // Held outside the keys
+        assert_close(track.at(-1.0), 0.0);
+        assert_close(track.at(7.0), 5.0);
+
+        // Keys on a line stay on it away from the ends
+        let line = (0..4).fold(Track::new(Interpolation::Cubic), |track, key| {
+            track.key(key as Real, 3.0 * key as Real - 1.0)
+        });
+        for time in [1.0, 1.3, 1.5, 1.9] {
+            assert_close(line.at(time), 3.0 * time - 1.0);
+        }
+    }
+
+    #[test]
+    fn linear_tracks_interpolate_between_keys() {
+        let track = Track::new(Interpolation::Linear)
+            .key(0.0, Vec3([0.0, 0.0, 0.0].into()))
+            .key(2.0, Vec3([2.0, -4.0, 1.0].into()));
+        let middle = track.at(0.5);
+        assert!((&middle - &Vec3([0.5, -1.0, 0.25].into())).length() < 1e-4);
+    }
+
+    #[test]
+    fn rotation_tracks_slerp_between_keys() {
+        let about_y = |degrees: Real| Quaternion::from_euler(&Vec3([0.0, degrees, 0.0].into()));
+        // Cubic or not, rotations slerp
+        let track = Track::new(Interpolation::Cubic)
+            .key(0.0, about_y(0.0))
+            .key(1.0, about_y(90.0))
+            .key(2.0, about_y(180.0));
+        let x = Vec3([1.0, 0.0, 0.0].into());
+        for (time, degrees) in [
+            (0.0, 0.0),
+            (0.5, 45.0),
+            (1.0, 90.0),
+            (1.25, 112.5),
+            (2.0, 180.0),
+        ] {
+            let rotation = track.at(time);
+            assert_close(rotation.dot(&rotation), 1.0);
+            let expected = about_y(degrees).rotate(&x);
+            assert!(
+                (&rotation.rotate(&x) - &expected).length() < 1e-4,
+                "{} degrees at {}",
+                degrees,
+                time
+            );
+        }
+    }
+}
@@ -0,0 +1,294 @@
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::Path;
+
+use image::codecs::hdr::HdrEncoder;
+use image::{GrayImage, ImageResult, Luma, Rgb};
+use num_traits::AsPrimitive;
+use rayon::prelude::*;
+
+use crate::base::color::Color;
+use crate::base::consts::PI;
+use crate::base::frame::Frame;
+use crate::base::vec3::Vec3;
+use crate::base::Real;
+use crate::hittable::Hit;
+use crate::integrator::Integrator;
+use crate::mesh::{Mesh, SurfacePoint, UvSet};
+use crate::sampler::Sampler;
+use crate::stats::{self, Counter};
+use crate::utils::clamp;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BakeMode {
+    AmbientOcclusion,
+    // Convex edges above mid grey, concave ones below
+    Curvature,
+    // Irradiance from everything the mesh sees, into its lightmap layout
+    Lightmap,
+}
+
+// Bakes into the uv layout of a mesh instead of through a camera. Every
+// texel whose centre a triangle covers gets the surface under it; the
+// others stay empty and are left black in the image.
+#[derive(Debug, Clone)]
+pub struct Baker {
+    pub width: u32,
+    pub height: u32,
+    pub samples: u32,
+    pub distance: Real,
+    pub uv_set: UvSet,
+    // How many texels to grow the baked islands by, so that filtering across
+    // their edges does not pick up the empty texels around them
+    pub dilation: u32,
+}
+
+impl Baker {
+    pub fn new(width: u32, height: u32, samples: u32) -> Self {
+        Self {
+            width,
+            height,
+            samples,
+            distance: 1.0,
+            uv_set: UvSet::Texture,
+            dilation: 0,
+        }
+    }
+
+    // Later triangles win where uv islands overlap. Texel rows run top to
+    // bottom while v runs bottom to top.
+    pub fn rasterize(&self, mesh: &Mesh<Real>) -> Vec<Option<SurfacePoint>> {
+        let (width, height) = (self.width as Real, self.height as Real);
+        let mut texels = vec![None; (self.width * self.height) as usize];
+        for triangle in 0..mesh.triangles().len() {
+            let corners = match (0..3)
+                .map(|corner| mesh.uv(self.uv_set, triangle, corner))
+                .collect::<Option<Vec<_>>>()
+            {
+                Some(uvs) => uvs
+                    .iter()
+                    .map(|&(u, v)| (u * width, (1.0 - v) * height))
+                    .collect::<Vec<_>>(),
+                None => continue,
+            };
+            // Triangles collapsed to a line have no surface to bake
+            if mesh
+                .face_normal(triangle)
+                .iter()
+                .any(|value| value.is_nan())
+            {
+                continue;
+            }
+            let edge = |a: (Real, Real), b: (Real, Real), p: (Real, Real)| {
+                (b.0 - a.0) * (p.1 - a.1) - (b.1 - a.1) * (p.0 - a.0)
+            };
+            let area = edge(corners[0], corners[1], corners[2]);
+            if area == 0.0 {
+                continue;
+            }
+
+            let bound = |axis: fn(&(Real, Real)) -> Real, limit: Real| {
+                let values = corners.iter().map(axis);
+                let min = values.clone().fold(Real::INFINITY, Real::min);
+                let max = values.fold(Real::NEG_INFINITY, Real::max);
+                (
+                    (min - 0.5).ceil().max(0.0) as u32,
+                    ((max - 0.5).floor() + 1.0).clamp(0.0, limit) as u32,
+                )
+            };
+            let (x0, x1) = bound(|corner| corner.0, width);
+            let (y0, y1) = bound(|corner| corner.1, height);
+            for y in y0..y1 {
+                for x in x0..x1 {
+                    let center = (x as Real + 0.5, y as Real + 0.5);
+                    let barycentric = [
+                        edge(corners[1], corners[2], center) / area,
+                        edge(corners[2], corners[0], center) / area,
+                        edge(corners[0], corners[1], center) / area,
+                    ];
+                    if barycentric.iter().all(|&weight| weight >= 0.0) {
+                        texels[(y * self.width + x) as usize] =
+                            Some(mesh.surface_point(triangle, barycentric));
+                    }
+                }
+            }
+        }
+        texels
+    }
+
+    // Fraction of `samples` cosine-distributed rays from each texel that
+    // travel `distance` without hitting anything in `world`.
+    pub fn ambient_occlusion<'a, T, S>(
+        &self,
+        texels: &[Option<SurfacePoint>],
+        world: &'a T,
+        sampler: &S,
+    ) -> Vec<Option<Real>>
+    where
+        T: Sync,
+        &'a T: Hit<Real>,
+        S: Sampler + Clone + Send + Sync,
+    {
+        let samples = self.samples.max(1);
+        texels
+            .par_iter()
+            .enumerate()
+            .map_init(
+                || sampler.clone(),
+                |sampler, (index, texel)| {
+                    let texel = texel.as_ref()?;
+                    let (x, y) = (index as u32 % self.width, index as u32 / self.width);
+                    // Around the face rather than the interpolated normal, so
+                    // no ray starts out below the surface
+                    let frame = Frame::from_normal(&texel.geometric_normal);
+                    let open = (0..samples)
+                        .filter(|&sample| {
+                            sampler.start_pixel_sample(x, y, sample);
+                            let ray =
+                                texel.spawn_ray(frame.to_world(&Vec3::random_cosine(sampler)));
+                            stats::count(Counter::ShadowRays);
+                            world.hit(&ray, 0.0, self.distance).is_none()
+                        })
+                        .count();
+                    Some(open as Real / samples as Real)
+                },
+            )
+            .collect()
+    }
+
+    // Interpolated vertex curvature, scaled so that the 95th percentile of
+    // the mesh maps to white or black.
+    pub fn curvature(
+        &self,
+        texels: &[Option<SurfacePoint>],
+        mesh: &Mesh<Real>,
+    ) -> Vec<Option<Real>> {
+        let curvature = mesh.vertex_curvature();
+        let mut magnitudes: Vec<Real> = curvature.iter().map(|value| value.abs()).collect();
+        magnitudes.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        let scale = match magnitudes.get(magnitudes.len() * 95 / 100) {
+            Some(&scale) if scale > 0.0 => scale,
+            _ => 1.0,
+        };
+
+        texels
+            .iter()
+            .map(|texel| {
+                let texel = texel.as_ref()?;
+                let corners = &mesh.triangles()[texel.triangle].positions;
+                let value: Real = (0..3)
+                    .map(|corner| curvature[corners[corner]] * texel.barycentric[corner])
+                    .sum();
+                Some(0.5 + 0.5 * clamp(value / scale, -1.0, 1.0))
+            })
+            .collect()
+    }
+
+    // Irradiance at each texel: `samples` cosine-distributed rays around the
+    // face, each carrying the radiance `integrator` finds along it.
+    pub fn irradiance<'a, T, I, S>(
+        &self,
+        texels: &[Option<SurfacePoint>],
+        integrator: &I,
+        world: &'a T,
+        sampler: &S,
+    ) -> Vec<Option<Color<Real>>>
+    where
+        T: Sync,
+        &'a T: Hit<Real>,
+        I: Integrator + Sync,
+        S: Sampler + Clone + Send + Sync,
+    {
+        let samples = self.samples.max(1);
+        texels
+            .par_iter()
+            .enumerate()
+            .map_init(
+                || sampler.clone(),
+                |sampler, (index, texel)| {
+                    let texel = texel.as_ref()?;
+                    let (x, y) = (index as u32 % self.width, index as u32 / self.width);
+                    // As for `ambient_occlusion`, so no ray starts out below
+                    // the surface
+                    let frame = Frame::from_normal(&texel.geometric_normal);
+                    let mut sum = Color::default();
+                    for sample in 0..samples {
+                        sampler.start_pixel_sample(x, y, sample);
+                        let ray = texel.spawn_ray(frame.to_world(&Vec3::random_cosine(sampler)));
+                        stats::count(Counter::CameraRays);
+                        sum += &integrator.radiance(&ray, world, sampler);
+                    }
+                    // cos / pdf is pi for cosine-distributed directions
+                    Some(&sum * (PI / samples as Real))
+                },
+            )
+            .collect()
+    }
+
+    // Fills empty texels next to baked ones with the mean of those
+    // neighbours, one ring of texels per step.
+    pub fn dilate(&self, values: &mut [Option<Color<Real>>]) {
+        let (width, height) = (self.width as i64, self.height as i64);
+        for _ in 0..self.dilation {
+            let previous = values.to_vec();
+            for y in 0..height {
+                for x in 0..width {
+                    let index = (y * width + x) as usize;
+                    if previous[index].is_some() {
+                        continue;
+                    }
+                    let mut sum = Color::default();
+                    let mut count = 0;
+                    for (dx, dy) in NEIGHBOURS.iter() {
+                        let (nx, ny) = (x + dx, y + dy);
+                        if nx < 0 || ny < 0 || nx >= width || ny >= height {
+                            continue;
+                        }
+                        if let Some(value) = &previous[(ny * width + nx) as usize] {
+                            sum += value;
+                            count += 1;
+                        }
+                    }
+                    if count > 0 {
+                        values[index] = Some(&sum / count as Real);
+                    }
+                }
+            }
+        }
+    }
+
+    // Writes linear radiance as a Radiance RGBE file; empty texels are black.
+    pub fn save_hdr<P: AsRef<Path>>(
+        &self,
+        values: &[Option<Color<Real>>],
+        path: P,
+    ) -> ImageResult<()> {
+        let pixels: Vec<Rgb<f32>> = values
+            .iter()
+            .map(|value| {
+                let color = value.clone().unwrap_or_default();
+                Rgb([color[0].as_(), color[1].as_(), color[2].as_()])
+            })
+            .collect();
+        let file = BufWriter::new(File::create(path)?);
+        HdrEncoder::new(file).encode(&pixels, self.width as usize, self.height as usize)
+    }
+
+    pub fn to_image(&self, values: &[Option<Real>]) -> GrayImage {
+        GrayImage::from_fn(self.width, self.height, |x, y| {
+            let value = values[(y * self.width + x) as usize].unwrap_or(0.0);
+            Luma([(clamp(value, 0.0, 1.0) * 255.0).round() as u8])
+        })
+    }
+}
+
+const NEIGHBOURS: [(i64, i64); 8] = [
+    (-1, -1),
+    (0, -1),
+    (1, -1),
+    (-1, 0),
+    (1, 0),
+    (-1, 1),
+    (0, 1),
+    (1, 1),
+];
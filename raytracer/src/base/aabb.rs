@@ -0,0 +1,94 @@
+use crate::base::matrix::Mat4;
+use crate::base::point3::Point3;
+use crate::base::vec3::Vec3;
+use crate::base::Real;
+use crate::ray::Ray;
+
+#[derive(Debug, Default, Clone)]
+pub struct Aabb<T> {
+    pub min: Point3<T>,
+    pub max: Point3<T>,
+}
+
+impl Aabb<Real> {
+    pub fn new(min: Point3<Real>, max: Point3<Real>) -> Self {
+        Self { min, max }
+    }
+
+    pub fn union(&self, other: &Aabb<Real>) -> Aabb<Real> {
+        Aabb {
+            min: [
+                self.min[0].min(other.min[0]),
+                self.min[1].min(other.min[1]),
+                self.min[2].min(other.min[2]),
+            ]
+            .into(),
+            max: [
+                self.max[0].max(other.max[0]),
+                self.max[1].max(other.max[1]),
+                self.max[2].max(other.max[2]),
+            ]
+            .into(),
+        }
+    }
+
+    // None when the boxes do not overlap.
+    pub fn intersection(&self, other: &Aabb<Real>) -> Option<Aabb<Real>> {
+        let min: [Real; 3] = [0, 1, 2].map(|axis| self.min[axis].max(other.min[axis]));
+        let max: [Real; 3] = [0, 1, 2].map(|axis| self.max[axis].min(other.max[axis]));
+        if (0..3).any(|axis| min[axis] > max[axis]) {
+            return None;
+        }
+        Some(Aabb::new(min.into(), max.into()))
+    }
+
+    // Box around the image of this one under `matrix`.
+    pub fn transformed(&self, matrix: &Mat4<Real>) -> Aabb<Real> {
+        (0..8)
+            .map(|corner| {
+                let pick = |axis: usize| {
+                    if corner & (1 << axis) == 0 {
+                        self.min[axis]
+                    } else {
+                        self.max[axis]
+                    }
+                };
+                let point = matrix.point(&Point3([pick(0), pick(1), pick(2)].into()));
+                Aabb::new(point.clone(), point)
+            })
+            .reduce(|a, b| a.union(&b))
+            .unwrap()
+    }
+
+    pub fn center(&self) -> Point3<Real> {
+        &self.min + &(self.diagonal() * 0.5)
+    }
+
+    pub fn diagonal(&self) -> Vec3<Real> {
+        self.max.vec_from(&self.min)
+    }
+
+    pub fn surface_area(&self) -> Real {
+        let d = self.diagonal();
+        2.0 * (d[0] * d[1] + d[1] * d[2] + d[2] * d[0])
+    }
+
+    // Slab test; a ray parallel to a flat box still passes when it lies in
+    // its plane.
+    pub fn hit(&self, ray: &Ray<Real>, mut t_min: Real, mut t_max: Real) -> bool {
+        for axis in 0..3 {
+            let inverse = 1.0 / ray.direction[axis];
+            let mut t0 = (self.min[axis] - ray.origin[axis]) * inverse;
+            let mut t1 = (self.max[axis] - ray.origin[axis]) * inverse;
+            if inverse < 0.0 {
+                std::mem::swap(&mut t0, &mut t1);
+            }
+            t_min = t0.max(t_min);
+            t_max = t1.min(t_max);
+            if t_max < t_min {
+                return false;
+            }
+        }
+        true
+    }
+}
@@ -5,6 +5,7 @@ use std::ops;
 use std::slice::{Iter, IterMut};
 
 use crate::base::Base3;
+use crate::base::Real;
 use crate::utils::clamp;
 use raytracer_derive::Base3Ops;
 
@@ -49,7 +50,15 @@ impl<T: Float + Into<f64>> Color<T> {
     }
 }
 
+impl Color<Real> {
+    // Rec. 709 luminance of linear rgb
+    pub fn luminance(&self) -> Real {
+        0.2126 * self[0] + 0.7152 * self[1] + 0.0722 * self[2]
+    }
+}
+
 pub trait AsColor<T> {
+    #[allow(clippy::wrong_self_convention)]
     fn as_color(self) -> Color<T>;
 }
 
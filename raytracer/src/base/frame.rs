@@ -0,0 +1,57 @@
+use crate::base::matrix::Mat3;
+use crate::base::normal3::Normal3;
+use crate::base::vec3::{Vec3, Vec3Operations};
+use crate::base::Real;
+
+// Orthonormal basis; `n` is the axis the frame is built around and maps to
+// local z, with `s` and `t` along local x and y.
+#[derive(Debug, Clone, Default)]
+pub struct Frame<T> {
+    pub s: Vec3<T>,
+    pub t: Vec3<T>,
+    pub n: Vec3<T>,
+}
+
+impl Frame<Real> {
+    // Any basis around the unit `normal`, without branching on its
+    // direction (Duff et al., "Building an Orthonormal Basis, Revisited").
+    pub fn from_normal(normal: &Normal3<Real>) -> Self {
+        let n = Vec3::from(normal.clone());
+        let sign = (1.0 as Real).copysign(n[2]);
+        let a = -1.0 / (sign + n[2]);
+        let b = n[0] * n[1] * a;
+        Self {
+            s: Vec3([1.0 + sign * n[0] * n[0] * a, sign * b, -sign * n[0]].into()),
+            t: Vec3([b, sign + n[1] * n[1] * a, -n[1]].into()),
+            n,
+        }
+    }
+
+    // Looking along `forward`, with `t` as close to `up` as the two allow.
+    pub fn looking(forward: &Vec3<Real>, up: &Vec3<Real>) -> Self {
+        let n = forward.unit();
+        let s = up.cross(&n).unit();
+        let t = n.cross(&s);
+        Self { s, t, n }
+    }
+
+    pub fn to_local(&self, vector: &Vec3<Real>) -> Vec3<Real> {
+        Vec3(
+            [
+                vector.dot(&self.s),
+                vector.dot(&self.t),
+                vector.dot(&self.n),
+            ]
+            .into(),
+        )
+    }
+
+    pub fn to_world(&self, vector: &Vec3<Real>) -> Vec3<Real> {
+        &self.s * vector[0] + &self.t * vector[1] + &self.n * vector[2]
+    }
+
+    // Matrix taking local coordinates to world ones.
+    pub fn to_mat3(&self) -> Mat3<Real> {
+        Mat3::from_columns(&self.s, &self.t, &self.n)
+    }
+}
@@ -0,0 +1,245 @@
+use std::ops;
+
+use crate::base::normal3::Normal3;
+use crate::base::point3::Point3;
+use crate::base::vec3::Vec3;
+use crate::base::Real;
+
+// Row-major; vectors are columns, so `a * b` applies `b` first.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Mat3<T>(pub [[T; 3]; 3]);
+
+// Affine transforms in homogeneous coordinates, row-major like `Mat3`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Mat4<T>(pub [[T; 4]; 4]);
+
+impl Mat3<Real> {
+    pub fn identity() -> Self {
+        Self::scale(&Vec3([1.0, 1.0, 1.0].into()))
+    }
+
+    pub fn scale(factors: &Vec3<Real>) -> Self {
+        Mat3([
+            [factors[0], 0.0, 0.0],
+            [0.0, factors[1], 0.0],
+            [0.0, 0.0, factors[2]],
+        ])
+    }
+
+    // `angles` are Euler angles in degrees, applied about x, then y, then z.
+    pub fn rotation(angles: &Vec3<Real>) -> Self {
+        let (sx, cx) = angles[0].to_radians().sin_cos();
+        let (sy, cy) = angles[1].to_radians().sin_cos();
+        let (sz, cz) = angles[2].to_radians().sin_cos();
+        Mat3([
+            [cy * cz, sx * sy * cz - cx * sz, cx * sy * cz + sx * sz],
+            [cy * sz, sx * sy * sz + cx * cz, cx * sy * sz - sx * cz],
+            [-sy, sx * cy, cx * cy],
+        ])
+    }
+
+    pub fn from_columns(x: &Vec3<Real>, y: &Vec3<Real>, z: &Vec3<Real>) -> Self {
+        Mat3([0, 1, 2].map(|row| [x[row], y[row], z[row]]))
+    }
+
+    pub fn column(&self, index: usize) -> Vec3<Real> {
+        Vec3([self.0[0][index], self.0[1][index], self.0[2][index]].into())
+    }
+
+    pub fn transpose(&self) -> Self {
+        let m = &self.0;
+        Mat3([0, 1, 2].map(|row| [m[0][row], m[1][row], m[2][row]]))
+    }
+
+    pub fn determinant(&self) -> Real {
+        let m = &self.0;
+        m[0][0] * (m[1][1] * m[2][2] - m[1][2] * m[2][1])
+            - m[0][1] * (m[1][0] * m[2][2] - m[1][2] * m[2][0])
+            + m[0][2] * (m[1][0] * m[2][1] - m[1][1] * m[2][0])
+    }
+
+    // None for singular matrices.
+    pub fn inverse(&self) -> Option<Self> {
+        let determinant = self.determinant();
+        if determinant == 0.0 {
+            return None;
+        }
+        let m = &self.0;
+        // Cofactor of the transposed entry, so the result is the adjugate
+        let cofactor = |row: usize, column: usize| {
+            let (r0, r1) = ((column + 1) % 3, (column + 2) % 3);
+            let (c0, c1) = ((row + 1) % 3, (row + 2) % 3);
+            m[r0][c0] * m[r1][c1] - m[r0][c1] * m[r1][c0]
+        };
+        Some(Mat3([0, 1, 2].map(|row| {
+            [0, 1, 2].map(|column| cofactor(row, column) / determinant)
+        })))
+    }
+
+    pub fn vector(&self, vector: &Vec3<Real>) -> Vec3<Real> {
+        let m = &self.0;
+        Vec3(
+            [0, 1, 2]
+                .map(|row| m[row][0] * vector[0] + m[row][1] * vector[1] + m[row][2] * vector[2])
+                .into(),
+        )
+    }
+}
+
+impl ops::Mul for &Mat3<Real> {
+    type Output = Mat3<Real>;
+
+    fn mul(self, other: &Mat3<Real>) -> Self::Output {
+        let (a, b) = (&self.0, &other.0);
+        Mat3(
+            [0, 1, 2]
+                .map(|row| [0, 1, 2].map(|column| (0..3).map(|k| a[row][k] * b[k][column]).sum())),
+        )
+    }
+}
+
+impl Mat4<Real> {
+    pub fn identity() -> Self {
+        Self::affine(&Mat3::identity(), &Vec3::default())
+    }
+
+    pub fn translation(offset: &Vec3<Real>) -> Self {
+        Self::affine(&Mat3::identity(), offset)
+    }
+
+    // `linear` followed by a translation by `offset`.
+    pub fn affine(linear: &Mat3<Real>, offset: &Vec3<Real>) -> Self {
+        let l = &linear.0;
+        Mat4([
+            [l[0][0], l[0][1], l[0][2], offset[0]],
+            [l[1][0], l[1][1], l[1][2], offset[1]],
+            [l[2][0], l[2][1], l[2][2], offset[2]],
+            [0.0, 0.0, 0.0, 1.0],
+        ])
+    }
+
+    pub fn linear(&self) -> Mat3<Real> {
+        let m = &self.0;
+        Mat3([0, 1, 2].map(|row| [m[row][0], m[row][1], m[row][2]]))
+    }
+
+    pub fn offset(&self) -> Vec3<Real> {
+        Vec3([self.0[0][3], self.0[1][3], self.0[2][3]].into())
+    }
+
+    pub fn transpose(&self) -> Self {
+        let m = &self.0;
+        Mat4([0, 1, 2, 3].map(|row| [m[0][row], m[1][row], m[2][row], m[3][row]]))
+    }
+
+    // Gauss-Jordan elimination with partial pivoting; None for singular
+    // matrices.
+    pub fn inverse(&self) -> Option<Self> {
+        let mut m = self.0;
+        let mut inverse = Mat4::identity().0;
+        for column in 0..4 {
+            let pivot = (column..4)
+                .max_by(|&a, &b| m[a][column].abs().partial_cmp(&m[b][column].abs()).unwrap())
+                .unwrap();
+            if m[pivot][column] == 0.0 {
+                return None;
+            }
+            m.swap(column, pivot);
+            inverse.swap(column, pivot);
+            let scale = 1.0 / m[column][column];
+            for k in 0..4 {
+                m[column][k] *= scale;
+                inverse[column][k] *= scale;
+            }
+            for row in (0..4).filter(|&row| row != column) {
+                let factor = m[row][column];
+                for k in 0..4 {
+                    m[row][k] -= factor * m[column][k];
+                    inverse[row][k] -= factor * inverse[column][k];
+                }
+            }
+        }
+        Some(Mat4(inverse))
+    }
+
+    pub fn point(&self, point: &Point3<Real>) -> Point3<Real> {
+        let m = &self.0;
+        let row = |row: usize| {
+            m[row][0] * point[0] + m[row][1] * point[1] + m[row][2] * point[2] + m[row][3]
+        };
+        let w = row(3);
+        let point = Point3([row(0), row(1), row(2)].into());
+        if w == 1.0 {
+            point
+        } else {
+            point / w
+        }
+    }
+
+    pub fn vector(&self, vector: &Vec3<Real>) -> Vec3<Real> {
+        self.linear().vector(vector)
+    }
+
+    // Multiplies by the transpose, so `self` should be the inverse of the
+    // transform the surface goes through.
+    pub fn normal(&self, normal: &Normal3<Real>) -> Normal3<Real> {
+        let m = &self.0;
+        Normal3(
+            [0, 1, 2]
+                .map(|column| {
+                    m[0][column] * normal[0] + m[1][column] * normal[1] + m[2][column] * normal[2]
+                })
+                .into(),
+        )
+    }
+}
+
+impl ops::Mul for &Mat4<Real> {
+    type Output = Mat4<Real>;
+
+    fn mul(self, other: &Mat4<Real>) -> Self::Output {
+        let (a, b) = (&self.0, &other.0);
+        Mat4(
+            [0, 1, 2, 3].map(|row| {
+                [0, 1, 2, 3].map(|column| (0..4).map(|k| a[row][k] * b[k][column]).sum())
+            }),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_identity<const N: usize>(m: &[[Real; N]; N]) {
+        for (row, values) in m.iter().enumerate() {
+            for (column, value) in values.iter().enumerate() {
+                let expected = if row == column { 1.0 } else { 0.0 };
+                assert!((value - expected).abs() < 1e-4, "{:?}", m);
+            }
+        }
+    }
+
+    #[test]
+    fn mat3_inverse_round_trips() {
+        let m = &Mat3::rotation(&Vec3([30.0, -45.0, 110.0].into()))
+            * &Mat3::scale(&Vec3([2.0, 0.5, 3.0].into()));
+        let inverse = m.inverse().unwrap();
+        assert_identity(&(&m * &inverse).0);
+        assert_identity(&(&inverse * &m).0);
+        assert!(Mat3::scale(&Vec3([1.0, 0.0, 1.0].into()))
+            .inverse()
+            .is_none());
+    }
+
+    #[test]
+    fn mat4_inverse_round_trips() {
+        let linear = Mat3::rotation(&Vec3([10.0, 200.0, -60.0].into()));
+        let mut m = Mat4::affine(&linear, &Vec3([4.0, -2.0, 7.0].into()));
+        // A projective bottom row, so the elimination has to pivot
+        m.0[3] = [0.5, 0.0, 0.25, 0.0];
+        let inverse = m.inverse().unwrap();
+        assert_identity(&(&m * &inverse).0);
+        assert_identity(&(&inverse * &m).0);
+    }
+}
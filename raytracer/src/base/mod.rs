@@ -3,10 +3,35 @@ use rand::prelude::*;
 use std::ops;
 use std::slice::{Iter, IterMut};
 
+pub mod aabb;
 pub mod color;
+pub mod frame;
+pub mod matrix;
+pub mod normal3;
 pub mod point3;
+pub mod quaternion;
+pub mod simd;
 pub mod vec3;
 
+// Precision the renderer works in. Building with the `f32` feature halves
+// the size of meshes, photon maps and everything else that stores geometry;
+// films keep accumulating in `f64` either way.
+#[cfg(not(feature = "f32"))]
+pub type Real = f64;
+#[cfg(feature = "f32")]
+pub type Real = f32;
+
+#[cfg(feature = "f32")]
+pub use std::f32::consts;
+#[cfg(not(feature = "f32"))]
+pub use std::f64::consts;
+
+// Arithmetic stays elementwise on purpose. Three values fill neither a
+// register of four f32s nor whole pairs of f64s without padding and
+// shuffles, and rustc already packs these loops into `addpd`/`mulpd` where
+// that pays; hand-written SSE measures no faster, see `benches/base3.rs`.
+// SIMD is used where four independent values line up instead, in
+// `simd::Vec3x4` and the ray packets built on it.
 #[derive(Debug, Default)]
 pub struct Base3<T>(pub [T; 3]);
 
@@ -138,7 +163,7 @@ impl<T: ops::MulAssign<T> + Copy> ops::MulAssign<T> for Base3<T> {
     }
 }
 
-impl<'a, T: ops::Neg<Output = T> + Copy> ops::Neg for &'a Base3<T> {
+impl<T: ops::Neg<Output = T> + Copy> ops::Neg for &Base3<T> {
     type Output = Base3<T>;
 
     fn neg(self) -> Self::Output {
@@ -217,8 +242,7 @@ impl<'a, T> Base3<T> {
 }
 
 impl<T: SampleUniform + Copy> Base3<T> {
-    pub fn random(min: T, max: T) -> Self {
-        let mut rng = thread_rng();
+    pub fn random<R: Rng>(min: T, max: T, rng: &mut R) -> Self {
         Base3([
             rng.gen_range(min, max),
             rng.gen_range(min, max),
@@ -227,6 +251,7 @@ impl<T: SampleUniform + Copy> Base3<T> {
     }
 }
 
+#[allow(clippy::upper_case_acronyms)]
 pub trait XYZ {
     type Item;
 
@@ -0,0 +1,67 @@
+use num_traits::Float;
+use rand::distributions::uniform::SampleUniform;
+use std::ops;
+use std::slice::{Iter, IterMut};
+
+use crate::base::vec3::{Length, Vec3, Vec3Operations};
+use crate::base::Base3;
+use crate::base::XYZ;
+use raytracer_derive::Base3Ops;
+
+// Surface normal. Kept apart from `Vec3` because transforms carry it by the
+// inverse transpose of their matrix rather than by the matrix itself.
+#[derive(Debug, Default, Base3Ops, Clone)]
+pub struct Normal3<T>(pub Base3<T>);
+
+impl<T: Float> Vec3Operations<T> for Normal3<T> {}
+
+impl<T: std::iter::Sum + Float> Length<T> for Normal3<T> {
+    fn length_squared(&self) -> T {
+        self.iter().map(|x| x.powi(2)).sum()
+    }
+}
+
+impl<'a, T> XYZ for &'a Normal3<T> {
+    type Item = &'a T;
+
+    fn x(self) -> Self::Item {
+        &self[0]
+    }
+
+    fn y(self) -> Self::Item {
+        &self[1]
+    }
+
+    fn z(self) -> Self::Item {
+        &self[2]
+    }
+}
+
+impl<T> From<Vec3<T>> for Normal3<T> {
+    fn from(vector: Vec3<T>) -> Self {
+        Normal3(vector.0)
+    }
+}
+
+impl<T> From<Normal3<T>> for Vec3<T> {
+    fn from(normal: Normal3<T>) -> Self {
+        Vec3(normal.0)
+    }
+}
+
+impl<T: Float> Normal3<T> {
+    // `dot` only pairs a normal with another normal; this pairs it with a
+    // vector.
+    pub fn dot_vector(&self, vector: &Vec3<T>) -> T {
+        self[0] * vector[0] + self[1] * vector[1] + self[2] * vector[2]
+    }
+
+    // The normal flipped, if needed, to lie on the same side as `vector`.
+    pub fn face_forward(self, vector: &Vec3<T>) -> Normal3<T> {
+        if self.dot_vector(vector) < T::zero() {
+            -self
+        } else {
+            self
+        }
+    }
+}
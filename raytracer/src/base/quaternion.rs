@@ -0,0 +1,209 @@
+use std::ops;
+
+use crate::base::matrix::Mat3;
+use crate::base::vec3::{Vec3, Vec3Operations};
+use crate::base::Real;
+
+// Rotations as unit quaternions: `v` holds the vector part, `w` the scalar.
+#[derive(Debug, Clone)]
+pub struct Quaternion<T> {
+    pub v: Vec3<T>,
+    pub w: T,
+}
+
+impl Quaternion<Real> {
+    pub fn identity() -> Self {
+        Self {
+            v: Vec3::default(),
+            w: 1.0,
+        }
+    }
+
+    // Rotation by `angle` degrees about `axis`, counterclockwise looking
+    // down the axis.
+    pub fn from_axis_angle(axis: &Vec3<Real>, angle: Real) -> Self {
+        let (sin, cos) = (angle.to_radians() / 2.0).sin_cos();
+        Self {
+            v: axis.unit() * sin,
+            w: cos,
+        }
+    }
+
+    // Same order and units as `Mat3::rotation`.
+    pub fn from_euler(angles: &Vec3<Real>) -> Self {
+        let about = |axis: usize| {
+            let mut unit = Vec3::default();
+            unit[axis] = 1.0;
+            Quaternion::from_axis_angle(&unit, angles[axis])
+        };
+        &(&about(2) * &about(1)) * &about(0)
+    }
+
+    // Expects a rotation matrix; picks the largest of the four components to
+    // divide by so the result stays accurate near half turns.
+    pub fn from_mat3(matrix: &Mat3<Real>) -> Self {
+        let m = &matrix.0;
+        let trace = m[0][0] + m[1][1] + m[2][2];
+        if trace > 0.0 {
+            let s = (trace + 1.0).sqrt() * 2.0;
+            return Self {
+                v: Vec3(
+                    [
+                        (m[2][1] - m[1][2]) / s,
+                        (m[0][2] - m[2][0]) / s,
+                        (m[1][0] - m[0][1]) / s,
+                    ]
+                    .into(),
+                ),
+                w: s / 4.0,
+            };
+        }
+        let i = if m[1][1] > m[0][0] { 1 } else { 0 };
+        let i = if m[2][2] > m[i][i] { 2 } else { i };
+        let (j, k) = ((i + 1) % 3, (i + 2) % 3);
+        let s = (m[i][i] - m[j][j] - m[k][k] + 1.0).sqrt() * 2.0;
+        let mut v = Vec3::default();
+        v[i] = s / 4.0;
+        v[j] = (m[j][i] + m[i][j]) / s;
+        v[k] = (m[k][i] + m[i][k]) / s;
+        Self {
+            v,
+            w: (m[k][j] - m[j][k]) / s,
+        }
+    }
+
+    pub fn to_mat3(&self) -> Mat3<Real> {
+        let (x, y, z, w) = (self.v[0], self.v[1], self.v[2], self.w);
+        Mat3([
+            [
+                1.0 - 2.0 * (y * y + z * z),
+                2.0 * (x * y - z * w),
+                2.0 * (x * z + y * w),
+            ],
+            [
+                2.0 * (x * y + z * w),
+                1.0 - 2.0 * (x * x + z * z),
+                2.0 * (y * z - x * w),
+            ],
+            [
+                2.0 * (x * z - y * w),
+                2.0 * (y * z + x * w),
+                1.0 - 2.0 * (x * x + y * y),
+            ],
+        ])
+    }
+
+    pub fn dot(&self, other: &Quaternion<Real>) -> Real {
+        self.v.dot(&other.v) + self.w * other.w
+    }
+
+    pub fn normalized(&self) -> Self {
+        let length = self.dot(self).sqrt();
+        Self {
+            v: &self.v / length,
+            w: self.w / length,
+        }
+    }
+
+    pub fn conjugate(&self) -> Self {
+        Self {
+            v: -&self.v,
+            w: self.w,
+        }
+    }
+
+    pub fn rotate(&self, vector: &Vec3<Real>) -> Vec3<Real> {
+        // v + 2w(q × v) + 2q × (q × v)
+        let t = self.v.cross(vector) * 2.0;
+        vector + &(&t * self.w) + self.v.cross(&t)
+    }
+
+    // Constant speed interpolation along the shorter arc from `self` at
+    // `t` = 0 to `other` at `t` = 1.
+    pub fn slerp(&self, other: &Quaternion<Real>, t: Real) -> Self {
+        let mut cos = self.dot(other);
+        let mut other = other.clone();
+        if cos < 0.0 {
+            other = Self {
+                v: -other.v,
+                w: -other.w,
+            };
+            cos = -cos;
+        }
+        // Nearly parallel; the arc is a straight line to within precision
+        if cos > 0.9995 {
+            return Self {
+                v: &self.v * (1.0 - t) + &other.v * t,
+                w: self.w * (1.0 - t) + other.w * t,
+            }
+            .normalized();
+        }
+        let theta = cos.acos() * t;
+        let perpendicular = Self {
+            v: &other.v - &(&self.v * cos),
+            w: other.w - self.w * cos,
+        }
+        .normalized();
+        let (sin, cos) = theta.sin_cos();
+        Self {
+            v: &self.v * cos + &perpendicular.v * sin,
+            w: self.w * cos + perpendicular.w * sin,
+        }
+    }
+}
+
+impl ops::Mul for &Quaternion<Real> {
+    type Output = Quaternion<Real>;
+
+    // Applies `other` first, then `self`.
+    fn mul(self, other: &Quaternion<Real>) -> Self::Output {
+        Quaternion {
+            v: self.v.cross(&other.v) + &other.v * self.w + &self.v * other.w,
+            w: self.w * other.w - self.v.dot(&other.v),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(a: &Mat3<Real>, b: &Mat3<Real>) {
+        for row in 0..3 {
+            for column in 0..3 {
+                assert!(
+                    (a.0[row][column] - b.0[row][column]).abs() < 1e-4,
+                    "{:?} {:?}",
+                    a,
+                    b
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn mat3_round_trips() {
+        // The last two are half turns, where the trace is negative
+        for angles in [
+            [20.0, 30.0, 40.0],
+            [-120.0, 75.0, 10.0],
+            [180.0, 0.0, 0.0],
+            [0.0, 180.0, 90.0],
+        ] {
+            let m = Mat3::rotation(&Vec3(angles.into()));
+            assert_close(&Quaternion::from_mat3(&m).to_mat3(), &m);
+            assert_close(&Quaternion::from_euler(&Vec3(angles.into())).to_mat3(), &m);
+        }
+    }
+
+    #[test]
+    fn slerp_hits_its_endpoints() {
+        let a = Quaternion::from_axis_angle(&Vec3([0.0, 1.0, 0.0].into()), 10.0);
+        // More than a half turn away, so slerp takes the other arc
+        let b = Quaternion::from_axis_angle(&Vec3([1.0, 1.0, 0.0].into()), 250.0);
+        assert_close(&a.slerp(&b, 0.0).to_mat3(), &a.to_mat3());
+        assert_close(&a.slerp(&b, 1.0).to_mat3(), &b.to_mat3());
+        let halfway = a.slerp(&b, 0.5);
+        assert!((halfway.dot(&halfway) - 1.0).abs() < 1e-4);
+    }
+}
@@ -0,0 +1,570 @@
+use std::ops;
+
+use crate::base::Base3;
+use crate::base::Real;
+
+// Four lanes of `Real` worked on at once. On x86_64 they live in SSE
+// registers, which every x86_64 cpu has, so no runtime detection is needed;
+// elsewhere they fall back to arrays that the compiler vectorises where it
+// can.
+#[derive(Debug, Clone, Copy)]
+pub struct F4(imp::Lanes);
+
+// Result of comparing two `F4`s, one flag per lane.
+#[derive(Debug, Clone, Copy)]
+pub struct Mask4(imp::Lanes);
+
+// Four vectors, points or colors with each coordinate in its own `F4`, the
+// layout packets of rays are tested in.
+#[derive(Debug, Clone, Copy)]
+pub struct Vec3x4 {
+    pub x: F4,
+    pub y: F4,
+    pub z: F4,
+}
+
+impl F4 {
+    pub fn new(values: [Real; 4]) -> Self {
+        F4(imp::new(values))
+    }
+
+    pub fn splat(value: Real) -> Self {
+        F4(imp::splat(value))
+    }
+
+    pub fn to_array(self) -> [Real; 4] {
+        imp::to_array(self.0)
+    }
+
+    pub fn min(self, other: F4) -> F4 {
+        F4(imp::min(self.0, other.0))
+    }
+
+    pub fn max(self, other: F4) -> F4 {
+        F4(imp::max(self.0, other.0))
+    }
+
+    pub fn sqrt(self) -> F4 {
+        F4(imp::sqrt(self.0))
+    }
+
+    pub fn abs(self) -> F4 {
+        self.max(-self)
+    }
+
+    pub fn lt(self, other: F4) -> Mask4 {
+        Mask4(imp::lt(self.0, other.0))
+    }
+
+    pub fn le(self, other: F4) -> Mask4 {
+        Mask4(imp::le(self.0, other.0))
+    }
+
+    pub fn gt(self, other: F4) -> Mask4 {
+        other.lt(self)
+    }
+
+    pub fn ge(self, other: F4) -> Mask4 {
+        other.le(self)
+    }
+
+    // Lanes of `self` where `mask` is set and of `other` elsewhere.
+    pub fn select(self, mask: Mask4, other: F4) -> F4 {
+        F4(imp::select(mask.0, self.0, other.0))
+    }
+}
+
+impl Mask4 {
+    pub fn none() -> Self {
+        Mask4(imp::mask(0))
+    }
+
+    pub fn all_set() -> Self {
+        Mask4(imp::mask(0b1111))
+    }
+
+    // Lane `i` is bit `i`.
+    pub fn bits(self) -> u32 {
+        imp::bits(self.0)
+    }
+
+    pub fn any(self) -> bool {
+        self.bits() != 0
+    }
+
+    pub fn all(self) -> bool {
+        self.bits() == 0b1111
+    }
+
+    pub fn lane(self, lane: usize) -> bool {
+        self.bits() & (1 << lane) != 0
+    }
+}
+
+impl ops::BitAnd for Mask4 {
+    type Output = Mask4;
+
+    fn bitand(self, other: Mask4) -> Mask4 {
+        Mask4(imp::and(self.0, other.0))
+    }
+}
+
+impl ops::BitOr for Mask4 {
+    type Output = Mask4;
+
+    fn bitor(self, other: Mask4) -> Mask4 {
+        Mask4(imp::or(self.0, other.0))
+    }
+}
+
+impl ops::Not for Mask4 {
+    type Output = Mask4;
+
+    fn not(self) -> Mask4 {
+        Mask4(imp::and_not(self.0, imp::mask(0b1111)))
+    }
+}
+
+macro_rules! f4_op {
+    ($trait:ident, $method:ident) => {
+        impl ops::$trait for F4 {
+            type Output = F4;
+
+            fn $method(self, other: F4) -> F4 {
+                F4(imp::$method(self.0, other.0))
+            }
+        }
+    };
+}
+
+f4_op!(Add, add);
+f4_op!(Sub, sub);
+f4_op!(Mul, mul);
+f4_op!(Div, div);
+
+impl ops::Neg for F4 {
+    type Output = F4;
+
+    fn neg(self) -> F4 {
+        F4::splat(0.0) - self
+    }
+}
+
+impl Vec3x4 {
+    pub fn new(values: [&Base3<Real>; 4]) -> Self {
+        let axis = |axis: usize| {
+            F4::new([
+                values[0][axis],
+                values[1][axis],
+                values[2][axis],
+                values[3][axis],
+            ])
+        };
+        Self {
+            x: axis(0),
+            y: axis(1),
+            z: axis(2),
+        }
+    }
+
+    pub fn splat(value: &Base3<Real>) -> Self {
+        Self {
+            x: F4::splat(value[0]),
+            y: F4::splat(value[1]),
+            z: F4::splat(value[2]),
+        }
+    }
+
+    pub fn lane(&self, lane: usize) -> Base3<Real> {
+        [
+            self.x.to_array()[lane],
+            self.y.to_array()[lane],
+            self.z.to_array()[lane],
+        ]
+        .into()
+    }
+
+    pub fn axis(&self, axis: usize) -> F4 {
+        match axis {
+            0 => self.x,
+            1 => self.y,
+            _ => self.z,
+        }
+    }
+
+    pub fn dot(&self, other: &Vec3x4) -> F4 {
+        self.x * other.x + self.y * other.y + self.z * other.z
+    }
+
+    pub fn cross(&self, other: &Vec3x4) -> Vec3x4 {
+        Vec3x4 {
+            x: self.y * other.z - self.z * other.y,
+            y: self.z * other.x - self.x * other.z,
+            z: self.x * other.y - self.y * other.x,
+        }
+    }
+
+    // Per lane, the sum of the absolute terms of `self · (b × c)`.
+    pub fn triple_magnitude(&self, b: &Vec3x4, c: &Vec3x4) -> F4 {
+        let (a, b, c) = (self.abs(), b.abs(), c.abs());
+        a.x * (b.y * c.z + b.z * c.y)
+            + a.y * (b.z * c.x + b.x * c.z)
+            + a.z * (b.x * c.y + b.y * c.x)
+    }
+
+    pub fn abs(&self) -> Vec3x4 {
+        Vec3x4 {
+            x: self.x.abs(),
+            y: self.y.abs(),
+            z: self.z.abs(),
+        }
+    }
+
+    pub fn scale(&self, factor: F4) -> Vec3x4 {
+        Vec3x4 {
+            x: self.x * factor,
+            y: self.y * factor,
+            z: self.z * factor,
+        }
+    }
+
+    pub fn recip(&self) -> Vec3x4 {
+        let one = F4::splat(1.0);
+        Vec3x4 {
+            x: one / self.x,
+            y: one / self.y,
+            z: one / self.z,
+        }
+    }
+}
+
+impl ops::Add for &Vec3x4 {
+    type Output = Vec3x4;
+
+    fn add(self, other: &Vec3x4) -> Vec3x4 {
+        Vec3x4 {
+            x: self.x + other.x,
+            y: self.y + other.y,
+            z: self.z + other.z,
+        }
+    }
+}
+
+impl ops::Sub for &Vec3x4 {
+    type Output = Vec3x4;
+
+    fn sub(self, other: &Vec3x4) -> Vec3x4 {
+        Vec3x4 {
+            x: self.x - other.x,
+            y: self.y - other.y,
+            z: self.z - other.z,
+        }
+    }
+}
+
+// SAFETY for the x86_64 backends: they only use SSE and SSE2 intrinsics,
+// which the x86_64 target enables unconditionally.
+#[cfg(all(target_arch = "x86_64", not(feature = "f32")))]
+mod imp {
+    use std::arch::x86_64::*;
+
+    use crate::base::Real;
+
+    // Lanes 0 and 1 in `lo`, 2 and 3 in `hi`
+    #[derive(Debug, Clone, Copy)]
+    pub struct Lanes {
+        lo: __m128d,
+        hi: __m128d,
+    }
+
+    macro_rules! lanes {
+        ($intrinsic:ident, $a:expr, $b:expr) => {
+            unsafe {
+                Lanes {
+                    lo: $intrinsic($a.lo, $b.lo),
+                    hi: $intrinsic($a.hi, $b.hi),
+                }
+            }
+        };
+    }
+
+    pub fn new(values: [Real; 4]) -> Lanes {
+        unsafe {
+            Lanes {
+                lo: _mm_set_pd(values[1], values[0]),
+                hi: _mm_set_pd(values[3], values[2]),
+            }
+        }
+    }
+
+    pub fn splat(value: Real) -> Lanes {
+        unsafe {
+            Lanes {
+                lo: _mm_set1_pd(value),
+                hi: _mm_set1_pd(value),
+            }
+        }
+    }
+
+    pub fn to_array(lanes: Lanes) -> [Real; 4] {
+        let mut values = [0.0; 4];
+        unsafe {
+            _mm_storeu_pd(values.as_mut_ptr(), lanes.lo);
+            _mm_storeu_pd(values.as_mut_ptr().add(2), lanes.hi);
+        }
+        values
+    }
+
+    pub fn mask(bits: u32) -> Lanes {
+        let lane = |lane: u32| {
+            if bits & (1 << lane) != 0 {
+                f64::from_bits(u64::MAX)
+            } else {
+                0.0
+            }
+        };
+        new([lane(0), lane(1), lane(2), lane(3)])
+    }
+
+    pub fn bits(lanes: Lanes) -> u32 {
+        unsafe { (_mm_movemask_pd(lanes.lo) | _mm_movemask_pd(lanes.hi) << 2) as u32 }
+    }
+
+    pub fn add(a: Lanes, b: Lanes) -> Lanes {
+        lanes!(_mm_add_pd, a, b)
+    }
+
+    pub fn sub(a: Lanes, b: Lanes) -> Lanes {
+        lanes!(_mm_sub_pd, a, b)
+    }
+
+    pub fn mul(a: Lanes, b: Lanes) -> Lanes {
+        lanes!(_mm_mul_pd, a, b)
+    }
+
+    pub fn div(a: Lanes, b: Lanes) -> Lanes {
+        lanes!(_mm_div_pd, a, b)
+    }
+
+    pub fn min(a: Lanes, b: Lanes) -> Lanes {
+        lanes!(_mm_min_pd, a, b)
+    }
+
+    pub fn max(a: Lanes, b: Lanes) -> Lanes {
+        lanes!(_mm_max_pd, a, b)
+    }
+
+    pub fn sqrt(a: Lanes) -> Lanes {
+        unsafe {
+            Lanes {
+                lo: _mm_sqrt_pd(a.lo),
+                hi: _mm_sqrt_pd(a.hi),
+            }
+        }
+    }
+
+    pub fn lt(a: Lanes, b: Lanes) -> Lanes {
+        lanes!(_mm_cmplt_pd, a, b)
+    }
+
+    pub fn le(a: Lanes, b: Lanes) -> Lanes {
+        lanes!(_mm_cmple_pd, a, b)
+    }
+
+    pub fn and(a: Lanes, b: Lanes) -> Lanes {
+        lanes!(_mm_and_pd, a, b)
+    }
+
+    pub fn or(a: Lanes, b: Lanes) -> Lanes {
+        lanes!(_mm_or_pd, a, b)
+    }
+
+    // `!a & b`
+    pub fn and_not(a: Lanes, b: Lanes) -> Lanes {
+        lanes!(_mm_andnot_pd, a, b)
+    }
+
+    pub fn select(mask: Lanes, a: Lanes, b: Lanes) -> Lanes {
+        or(and(mask, a), and_not(mask, b))
+    }
+}
+
+#[cfg(all(target_arch = "x86_64", feature = "f32"))]
+mod imp {
+    use std::arch::x86_64::*;
+
+    use crate::base::Real;
+
+    #[derive(Debug, Clone, Copy)]
+    pub struct Lanes(__m128);
+
+    pub fn new(values: [Real; 4]) -> Lanes {
+        unsafe { Lanes(_mm_set_ps(values[3], values[2], values[1], values[0])) }
+    }
+
+    pub fn splat(value: Real) -> Lanes {
+        unsafe { Lanes(_mm_set1_ps(value)) }
+    }
+
+    pub fn to_array(lanes: Lanes) -> [Real; 4] {
+        let mut values = [0.0; 4];
+        unsafe { _mm_storeu_ps(values.as_mut_ptr(), lanes.0) };
+        values
+    }
+
+    pub fn mask(bits: u32) -> Lanes {
+        let lane = |lane: u32| {
+            if bits & (1 << lane) != 0 {
+                f32::from_bits(u32::MAX)
+            } else {
+                0.0
+            }
+        };
+        new([lane(0), lane(1), lane(2), lane(3)])
+    }
+
+    pub fn bits(lanes: Lanes) -> u32 {
+        unsafe { _mm_movemask_ps(lanes.0) as u32 }
+    }
+
+    macro_rules! binary {
+        ($($name:ident => $intrinsic:ident),*) => {
+            $(
+                pub fn $name(a: Lanes, b: Lanes) -> Lanes {
+                    unsafe { Lanes($intrinsic(a.0, b.0)) }
+                }
+            )*
+        };
+    }
+
+    binary!(
+        add => _mm_add_ps,
+        sub => _mm_sub_ps,
+        mul => _mm_mul_ps,
+        div => _mm_div_ps,
+        min => _mm_min_ps,
+        max => _mm_max_ps,
+        lt => _mm_cmplt_ps,
+        le => _mm_cmple_ps,
+        and => _mm_and_ps,
+        or => _mm_or_ps,
+        and_not => _mm_andnot_ps
+    );
+
+    pub fn sqrt(a: Lanes) -> Lanes {
+        unsafe { Lanes(_mm_sqrt_ps(a.0)) }
+    }
+
+    pub fn select(mask: Lanes, a: Lanes, b: Lanes) -> Lanes {
+        or(and(mask, a), and_not(mask, b))
+    }
+}
+
+#[cfg(not(target_arch = "x86_64"))]
+mod imp {
+    use crate::base::Real;
+
+    // Masks keep all bits set in a selected lane, as SSE does
+    #[derive(Debug, Clone, Copy)]
+    pub struct Lanes([Real; 4]);
+
+    fn map(a: Lanes, f: impl Fn(Real) -> Real) -> Lanes {
+        Lanes([f(a.0[0]), f(a.0[1]), f(a.0[2]), f(a.0[3])])
+    }
+
+    fn zip(a: Lanes, b: Lanes, f: impl Fn(Real, Real) -> Real) -> Lanes {
+        Lanes([
+            f(a.0[0], b.0[0]),
+            f(a.0[1], b.0[1]),
+            f(a.0[2], b.0[2]),
+            f(a.0[3], b.0[3]),
+        ])
+    }
+
+    fn flag(set: bool) -> Real {
+        if set {
+            Real::from_bits(!0)
+        } else {
+            0.0
+        }
+    }
+
+    fn is_set(value: Real) -> bool {
+        value.to_bits() != 0
+    }
+
+    pub fn new(values: [Real; 4]) -> Lanes {
+        Lanes(values)
+    }
+
+    pub fn splat(value: Real) -> Lanes {
+        Lanes([value; 4])
+    }
+
+    pub fn to_array(lanes: Lanes) -> [Real; 4] {
+        lanes.0
+    }
+
+    pub fn mask(bits: u32) -> Lanes {
+        Lanes([0, 1, 2, 3].map(|lane| flag(bits & (1 << lane) != 0)))
+    }
+
+    pub fn bits(lanes: Lanes) -> u32 {
+        (0..4).fold(0, |bits, lane| {
+            bits | (is_set(lanes.0[lane]) as u32) << lane
+        })
+    }
+
+    pub fn add(a: Lanes, b: Lanes) -> Lanes {
+        zip(a, b, |a, b| a + b)
+    }
+
+    pub fn sub(a: Lanes, b: Lanes) -> Lanes {
+        zip(a, b, |a, b| a - b)
+    }
+
+    pub fn mul(a: Lanes, b: Lanes) -> Lanes {
+        zip(a, b, |a, b| a * b)
+    }
+
+    pub fn div(a: Lanes, b: Lanes) -> Lanes {
+        zip(a, b, |a, b| a / b)
+    }
+
+    // Second operand on NaN, like `minpd`
+    pub fn min(a: Lanes, b: Lanes) -> Lanes {
+        zip(a, b, |a, b| if a < b { a } else { b })
+    }
+
+    pub fn max(a: Lanes, b: Lanes) -> Lanes {
+        zip(a, b, |a, b| if a > b { a } else { b })
+    }
+
+    pub fn sqrt(a: Lanes) -> Lanes {
+        map(a, Real::sqrt)
+    }
+
+    pub fn lt(a: Lanes, b: Lanes) -> Lanes {
+        zip(a, b, |a, b| flag(a < b))
+    }
+
+    pub fn le(a: Lanes, b: Lanes) -> Lanes {
+        zip(a, b, |a, b| flag(a <= b))
+    }
+
+    pub fn and(a: Lanes, b: Lanes) -> Lanes {
+        zip(a, b, |a, b| Real::from_bits(a.to_bits() & b.to_bits()))
+    }
+
+    pub fn or(a: Lanes, b: Lanes) -> Lanes {
+        zip(a, b, |a, b| Real::from_bits(a.to_bits() | b.to_bits()))
+    }
+
+    pub fn and_not(a: Lanes, b: Lanes) -> Lanes {
+        zip(a, b, |a, b| Real::from_bits(!a.to_bits() & b.to_bits()))
+    }
+
+    pub fn select(mask: Lanes, a: Lanes, b: Lanes) -> Lanes {
+        or(and(mask, a), and_not(mask, b))
+    }
+}
@@ -1,25 +1,28 @@
 use num_traits::Float;
 use rand::distributions::uniform::SampleUniform;
-use rand::prelude::*;
-use std::f64::consts::PI;
 use std::ops;
 use std::ops::{Add, Div, Mul, Sub};
 use std::slice::{Iter, IterMut};
 
 use crate::base::color::{AsColor, Color};
+use crate::base::consts::PI;
+use crate::base::normal3::Normal3;
 use crate::base::Base3;
+use crate::base::Real;
 use crate::base::XYZ;
+use crate::sampler::Sampler;
 use raytracer_derive::Base3Ops;
 
 #[derive(Debug, Default, Base3Ops, Clone)]
 pub struct Vec3<T>(pub Base3<T>);
 
 pub trait Vec3Operations<T> {
+    #[allow(clippy::type_complexity)]
     fn dot<'a>(
         &'a self,
         other: &'a Self,
     ) -> <<<<&'a Self as XYZ>::Item as Mul<<&'a Self as XYZ>::Item>>::Output as Add<
-        <<&Self as XYZ>::Item as Mul<<&'a Self as XYZ>::Item>>::Output,
+        <<&'a Self as XYZ>::Item as Mul<<&'a Self as XYZ>::Item>>::Output,
     >>::Output as Add<<<&'a Self as XYZ>::Item as Mul>::Output>>::Output
     where
         &'a Self: XYZ,
@@ -65,22 +68,33 @@ pub trait Vec3Operations<T> {
 
 impl<T: Float> Vec3Operations<T> for Vec3<T> {}
 
-impl Vec3<f64> {
-    pub fn random_unit() -> Vec3<f64> {
-        let mut rng = thread_rng();
-        let a = rng.gen_range(0.0, 2.0 * PI);
-        let z = rng.gen_range(-1.0, 1.0);
+impl Vec3<Real> {
+    pub fn random_unit<S: Sampler>(sampler: &mut S) -> Vec3<Real> {
+        let (u1, u2) = sampler.next_2d();
+        let a = 2.0 * PI * u1;
+        let z = 2.0 * u2 - 1.0;
         let r = (1.0 - z.powi(2)).sqrt();
         Vec3([r * a.cos(), r * a.sin(), z].into())
     }
 
-    pub fn reflect(&self, normal: &Vec3<f64>) -> Vec3<f64> {
-        self - &(normal * self.dot(normal) * 2.0)
+    // Cosine-weighted direction about local z: a uniform point on the unit
+    // disk projected up onto the hemisphere.
+    pub fn random_cosine<S: Sampler>(sampler: &mut S) -> Vec3<Real> {
+        let (u1, u2) = sampler.next_2d();
+        let r = u1.sqrt();
+        let phi = 2.0 * PI * u2;
+        Vec3([r * phi.cos(), r * phi.sin(), (1.0 - u1).max(0.0).sqrt()].into())
     }
 
-    pub fn refract(&self, normal: &Vec3<f64>, etai_over_etat: f64) -> Vec3<f64> {
-        let cos_theta = (-self).dot(normal);
-        let r_out_parallel = (self + &(normal * cos_theta)) * etai_over_etat;
+    pub fn reflect(&self, normal: &Normal3<Real>) -> Vec3<Real> {
+        let normal = Vec3::from(normal.clone());
+        self - &(&normal * self.dot(&normal) * 2.0)
+    }
+
+    pub fn refract(&self, normal: &Normal3<Real>, etai_over_etat: Real) -> Vec3<Real> {
+        let normal = Vec3::from(normal.clone());
+        let cos_theta = (-self).dot(&normal);
+        let r_out_parallel = (self + &(&normal * cos_theta)) * etai_over_etat;
         let r_out_perp = -normal * (1.0 - r_out_parallel.length_squared()).sqrt();
         r_out_parallel + r_out_perp
     }
@@ -0,0 +1,549 @@
+use crate::base::color::Color;
+use crate::base::consts::PI;
+use crate::base::frame::Frame;
+use crate::base::normal3::Normal3;
+use crate::base::point3::Point3;
+use crate::base::vec3::{Length, Vec3, Vec3Operations};
+use crate::base::Real;
+use crate::camera::Camera;
+use crate::film::{Film, SplatBuffer};
+use crate::hittable::{Hit, HittableVec};
+use crate::integrator::Integrator;
+use crate::lights::Lights;
+use crate::materials::{Materials, Scatter};
+use crate::ray::{offset_origin, sky, Ray};
+use crate::sampler::Sampler;
+use crate::stats::{self, Counter};
+
+// Traces a camera subpath and a light subpath per sample and connects every
+// prefix of one to every prefix of the other, weighting each strategy with
+// the balance heuristic. Strategies that end on the camera lens are splatted
+// to the film instead of returned.
+//
+// Light subpaths start on `Lights`; other emissive surfaces and the sky are
+// only found by camera subpaths. Metal and glass are treated as specular and
+// never connected.
+#[derive(Debug, Clone)]
+pub struct BdptIntegrator {
+    pub max_depth: u16,
+    camera: Camera<Real>,
+    lights: Lights,
+    splats: SplatBuffer,
+    width: u32,
+    height: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum VertexKind {
+    Camera,
+    Light,
+    Surface,
+}
+
+// `pdf_fwd` is the area density of sampling the vertex from its predecessor
+// on its own subpath, `pdf_rev` the density of sampling it from the other
+// direction; MIS weights are built from their ratios.
+#[derive(Debug, Clone)]
+struct Vertex {
+    kind: VertexKind,
+    point: Point3<Real>,
+    error: Vec3<Real>,
+    normal: Normal3<Real>,
+    geometric_normal: Normal3<Real>,
+    material: Option<Materials<Real>>,
+    // Index of the emitter in `Lights` the vertex lies on, if any
+    light: Option<usize>,
+    emitted: Color<Real>,
+    beta: Color<Real>,
+    pdf_fwd: Real,
+    pdf_rev: Real,
+    delta: bool,
+}
+
+impl Vertex {
+    fn new(
+        kind: VertexKind,
+        point: Point3<Real>,
+        normal: Normal3<Real>,
+        beta: Color<Real>,
+    ) -> Self {
+        Self {
+            kind,
+            point,
+            error: Vec3::default(),
+            normal,
+            geometric_normal: Normal3::default(),
+            material: None,
+            light: None,
+            emitted: Color::default(),
+            beta,
+            pdf_fwd: 0.0,
+            pdf_rev: 0.0,
+            delta: false,
+        }
+    }
+
+    // Origin for a ray leaving the vertex along `direction`; camera vertices
+    // have no surface to leave and no error, so they stay where they are.
+    fn spawn_origin(&self, direction: &Vec3<Real>) -> Point3<Real> {
+        offset_origin(&self.point, &self.error, &self.geometric_normal, direction)
+    }
+}
+
+impl BdptIntegrator {
+    pub fn new(
+        max_depth: u16,
+        camera: &Camera<Real>,
+        world: &HittableVec<Real>,
+        film: &Film,
+    ) -> Self {
+        Self {
+            max_depth,
+            camera: camera.clone(),
+            lights: Lights::new(world),
+            splats: film.splats(),
+            width: film.width,
+            height: film.height,
+        }
+    }
+
+    fn is_connectable(&self, vertex: &Vertex) -> bool {
+        match vertex.kind {
+            VertexKind::Camera => self.camera.is_connectable(),
+            VertexKind::Light => true,
+            VertexKind::Surface => matches!(vertex.material, Some(Materials::Lambertian(_))),
+        }
+    }
+
+    // BSDF at `vertex` for light leaving towards `next`.
+    fn f(&self, vertex: &Vertex, next: &Vertex) -> Color<Real> {
+        match &vertex.material {
+            Some(Materials::Lambertian(lambertian))
+                if vertex
+                    .normal
+                    .dot_vector(&next.point.vec_from(&vertex.point))
+                    > 0.0 =>
+            {
+                &lambertian.albedo / PI
+            }
+            _ => Color::default(),
+        }
+    }
+
+    fn pdf(&self, vertex: &Vertex, next: &Vertex) -> Real {
+        let direction = next.point.vec_from(&vertex.point);
+        let pdf = match vertex.kind {
+            VertexKind::Camera => self.camera.pdf_direction(&Ray {
+                origin: vertex.point.clone(),
+                direction,
+            }),
+            VertexKind::Light => return self.pdf_light(vertex, next),
+            VertexKind::Surface => match vertex.material {
+                Some(Materials::Lambertian(_)) => {
+                    vertex.normal.dot_vector(&direction.unit()).max(0.0) / PI
+                }
+                _ => 0.0,
+            },
+        };
+        convert_density(pdf, vertex, next)
+    }
+
+    // Density of an emitter at `vertex` sending light towards `next`.
+    fn pdf_light(&self, vertex: &Vertex, next: &Vertex) -> Real {
+        let direction = next.point.vec_from(&vertex.point).unit();
+        convert_density(
+            vertex.normal.dot_vector(&direction).max(0.0) / PI,
+            vertex,
+            next,
+        )
+    }
+
+    fn sample_light<S: Sampler>(&self, sampler: &mut S) -> (Vertex, Real) {
+        let sample = self.lights.sample(sampler);
+        let mut vertex = Vertex::new(
+            VertexKind::Light,
+            sample.point,
+            sample.normal,
+            Color::default(),
+        );
+        vertex.error = sample.error;
+        vertex.geometric_normal = vertex.normal.clone();
+        vertex.light = Some(sample.light);
+        vertex.emitted = sample.emit;
+        vertex.pdf_fwd = sample.pdf;
+        (vertex, sample.pdf)
+    }
+
+    fn light_subpath<'a, T, S: Sampler>(
+        &self,
+        world: &'a T,
+        max_vertices: usize,
+        sampler: &mut S,
+        path: &mut Vec<Vertex>,
+    ) where
+        &'a T: Hit<Real>,
+    {
+        if self.lights.is_empty() || max_vertices == 0 {
+            return;
+        }
+        let (mut vertex, pdf) = self.sample_light(sampler);
+        let frame = Frame::from_normal(&vertex.normal);
+        let direction = frame.to_world(&Vec3::random_cosine(sampler));
+        let pdf_direction = vertex.normal.dot_vector(&direction).max(0.0) / PI;
+        if pdf_direction == 0.0 {
+            return;
+        }
+        vertex.beta = &vertex.emitted / pdf;
+        // emitted * cos / (pdf * pdf_direction) with a cosine-distributed
+        // direction
+        let beta = &vertex.emitted * (PI / pdf);
+        let ray = Ray {
+            origin: vertex.spawn_origin(&direction),
+            direction,
+        };
+        path.push(vertex);
+        self.random_walk(
+            world,
+            ray,
+            beta,
+            pdf_direction,
+            max_vertices,
+            false,
+            sampler,
+            path,
+        );
+    }
+
+    // Extends `path` along `ray` until it holds `max_vertices`, returning
+    // the sky seen by a camera subpath that escapes.
+    #[allow(clippy::too_many_arguments)]
+    fn random_walk<'a, T, S: Sampler>(
+        &self,
+        world: &'a T,
+        mut ray: Ray<Real>,
+        mut beta: Color<Real>,
+        mut pdf_fwd: Real,
+        max_vertices: usize,
+        from_camera: bool,
+        sampler: &mut S,
+        path: &mut Vec<Vertex>,
+    ) -> Color<Real>
+    where
+        &'a T: Hit<Real>,
+    {
+        while path.len() < max_vertices {
+            let rec = match world.hit(&ray, 0.0, Real::INFINITY) {
+                Some(rec) => rec,
+                None => {
+                    if from_camera {
+                        return &beta * &sky(&ray);
+                    }
+                    break;
+                }
+            };
+            let previous = path.len() - 1;
+            let mut vertex = Vertex::new(
+                VertexKind::Surface,
+                rec.point.clone(),
+                rec.normal.clone(),
+                beta.clone(),
+            );
+            vertex.error = rec.error.clone();
+            vertex.geometric_normal = rec.geometric_normal.clone();
+            vertex.material = Some(rec.material.clone());
+            vertex.light = self.lights.find(&rec);
+            vertex.emitted = rec.material.emitted(&rec);
+            vertex.pdf_fwd = convert_density(pdf_fwd, &path[previous], &vertex);
+            path.push(vertex);
+            if path.len() >= max_vertices {
+                break;
+            }
+
+            let (scattered, attenuation) = match rec.material.scatter(&ray, &rec, sampler) {
+                Some(scatter) => scatter,
+                None => break,
+            };
+            let current = path.len() - 1;
+            let pdf_rev = match rec.material {
+                Materials::Lambertian(_) => {
+                    pdf_fwd = rec.normal.dot_vector(&scattered.direction.unit()).max(0.0) / PI;
+                    rec.normal.dot_vector(&-ray.direction.unit()).max(0.0) / PI
+                }
+                _ => {
+                    path[current].delta = true;
+                    pdf_fwd = 0.0;
+                    0.0
+                }
+            };
+            path[previous].pdf_rev = convert_density(pdf_rev, &path[current], &path[previous]);
+            beta = &beta * &attenuation;
+            stats::count(Counter::BounceRays);
+            ray = scattered;
+        }
+        Color::default()
+    }
+
+    // Both ends are offset off their surfaces past their rounding error, so
+    // the surface the far end lies on is beyond `t` = 1. The intersection
+    // tests drop hits whose error bounds reach past `t_max`, so rounding
+    // cannot bring that surface back into range either.
+    fn visible<'a, T>(&self, world: &'a T, from: &Vertex, to: &Vertex) -> bool
+    where
+        &'a T: Hit<Real>,
+    {
+        let origin = from.spawn_origin(&to.point.vec_from(&from.point));
+        let target = to.spawn_origin(&from.point.vec_from(&to.point));
+        stats::count(Counter::ShadowRays);
+        let ray = Ray {
+            direction: target.vec_from(&origin),
+            origin,
+        };
+        world.hit(&ray, 0.0, 1.0).is_none()
+    }
+
+    fn connect<'a, T, S: Sampler>(
+        &self,
+        world: &'a T,
+        light_path: &[Vertex],
+        camera_path: &[Vertex],
+        s: usize,
+        t: usize,
+        sampler: &mut S,
+    ) -> Color<Real>
+    where
+        &'a T: Hit<Real>,
+    {
+        let mut sampled = None;
+        let mut raster = (0.0, 0.0);
+        let contribution = if s == 0 {
+            let pt = &camera_path[t - 1];
+            &pt.beta * &pt.emitted
+        } else if t == 1 {
+            let qs = &light_path[s - 1];
+            if !self.is_connectable(qs) {
+                return Color::default();
+            }
+            let sample = match self.camera.sample_importance(&qs.point, sampler) {
+                Some(sample) => sample,
+                None => return Color::default(),
+            };
+            let importance = sample.importance / sample.pdf;
+            let vertex = Vertex::new(
+                VertexKind::Camera,
+                sample.lens_point,
+                Normal3::default(),
+                Color([importance, importance, importance].into()),
+            );
+            let direction = vertex.point.vec_from(&qs.point).unit();
+            let contribution = &(&qs.beta * &self.f(qs, &vertex)) * &vertex.beta;
+            let contribution = contribution * qs.normal.dot_vector(&direction).abs();
+            if is_black(&contribution) || !self.visible(world, qs, &vertex) {
+                return Color::default();
+            }
+            raster = (
+                sample.u * self.width as Real,
+                sample.v * self.height as Real,
+            );
+            sampled = Some(vertex);
+            contribution
+        } else if s == 1 {
+            let pt = &camera_path[t - 1];
+            if !self.is_connectable(pt) || self.lights.is_empty() {
+                return Color::default();
+            }
+            let (mut vertex, pdf) = self.sample_light(sampler);
+            let direction = vertex.point.vec_from(&pt.point);
+            let distance_squared = direction.length_squared();
+            let direction = direction.unit();
+            let cos_light = vertex.normal.dot_vector(&-&direction);
+            if cos_light <= 0.0 {
+                return Color::default();
+            }
+            vertex.beta = &vertex.emitted * (cos_light / (pdf * distance_squared));
+            let contribution = &(&pt.beta * &self.f(pt, &vertex)) * &vertex.beta;
+            let contribution = contribution * pt.normal.dot_vector(&direction).abs();
+            if is_black(&contribution) || !self.visible(world, pt, &vertex) {
+                return Color::default();
+            }
+            sampled = Some(vertex);
+            contribution
+        } else {
+            let qs = &light_path[s - 1];
+            let pt = &camera_path[t - 1];
+            if !self.is_connectable(qs) || !self.is_connectable(pt) {
+                return Color::default();
+            }
+            let contribution = &(&(&qs.beta * &self.f(qs, pt)) * &self.f(pt, qs)) * &pt.beta;
+            if is_black(&contribution) {
+                return Color::default();
+            }
+            let direction = pt.point.vec_from(&qs.point);
+            let distance_squared = direction.length_squared();
+            let direction = direction.unit();
+            let geometry = qs.normal.dot_vector(&direction).abs()
+                * pt.normal.dot_vector(&direction).abs()
+                / distance_squared;
+            if !self.visible(world, qs, pt) {
+                return Color::default();
+            }
+            contribution * geometry
+        };
+        if is_black(&contribution) {
+            return contribution;
+        }
+
+        let weighted =
+            contribution * self.mis_weight(light_path, camera_path, sampled.as_ref(), s, t);
+        if t == 1 {
+            self.splats.add(raster.0, raster.1, &weighted);
+            return Color::default();
+        }
+        weighted
+    }
+
+    // Balance heuristic over every strategy that could have produced the
+    // same path, from the pdf ratios of moving the connection along it.
+    fn mis_weight(
+        &self,
+        light_path: &[Vertex],
+        camera_path: &[Vertex],
+        sampled: Option<&Vertex>,
+        s: usize,
+        t: usize,
+    ) -> Real {
+        if s + t == 2 {
+            return 1.0;
+        }
+        let pdfs = |vertex: &Vertex| (vertex.pdf_fwd, vertex.pdf_rev, vertex.delta);
+        let mut camera: Vec<_> = camera_path[..t].iter().map(pdfs).collect();
+        let mut light: Vec<_> = light_path[..s].iter().map(pdfs).collect();
+
+        let pt = match (t, sampled) {
+            (1, Some(sampled)) => {
+                camera[0] = pdfs(sampled);
+                sampled
+            }
+            _ => &camera_path[t - 1],
+        };
+        let qs = match (s, sampled) {
+            (0, _) => None,
+            (1, Some(sampled)) => {
+                light[0] = pdfs(sampled);
+                Some(sampled)
+            }
+            _ => Some(&light_path[s - 1]),
+        };
+        let pt_minus = if t > 1 {
+            Some(&camera_path[t - 2])
+        } else {
+            None
+        };
+        let qs_minus = if s > 1 {
+            Some(&light_path[s - 2])
+        } else {
+            None
+        };
+
+        camera[t - 1].2 = false;
+        camera[t - 1].1 = match qs {
+            Some(qs) => self.pdf(qs, pt),
+            None => match pt.light {
+                Some(light) => self.lights.pdf(light),
+                // Nothing but a camera subpath can find this emitter
+                None => return 1.0,
+            },
+        };
+        if let Some(pt_minus) = pt_minus {
+            camera[t - 2].1 = match qs {
+                Some(_) => self.pdf(pt, pt_minus),
+                None => self.pdf_light(pt, pt_minus),
+            };
+        }
+        if let Some(qs) = qs {
+            light[s - 1].2 = false;
+            light[s - 1].1 = self.pdf(pt, qs);
+            if let Some(qs_minus) = qs_minus {
+                light[s - 2].1 = self.pdf(qs, qs_minus);
+            }
+        }
+
+        let remap = |pdf: Real| if pdf != 0.0 { pdf } else { 1.0 };
+        let mut sum = 0.0;
+        let mut ratio = 1.0;
+        for i in (1..t).rev() {
+            ratio *= remap(camera[i].1) / remap(camera[i].0);
+            if !camera[i].2 && !camera[i - 1].2 && (i > 1 || self.camera.is_connectable()) {
+                sum += ratio;
+            }
+        }
+        ratio = 1.0;
+        for i in (0..s).rev() {
+            ratio *= remap(light[i].1) / remap(light[i].0);
+            if !light[i].2 && (i == 0 || !light[i - 1].2) {
+                sum += ratio;
+            }
+        }
+        1.0 / (1.0 + sum)
+    }
+}
+
+impl Integrator for BdptIntegrator {
+    fn radiance<'a, T, S: Sampler>(
+        &self,
+        ray: &Ray<Real>,
+        world: &'a T,
+        sampler: &mut S,
+    ) -> Color<Real>
+    where
+        &'a T: Hit<Real>,
+    {
+        let max_depth = self.max_depth as usize;
+        let mut camera_path = vec![Vertex::new(
+            VertexKind::Camera,
+            ray.origin.clone(),
+            Normal3::default(),
+            Color([1.0, 1.0, 1.0].into()),
+        )];
+        let mut radiance = self.random_walk(
+            world,
+            ray.clone(),
+            Color([1.0, 1.0, 1.0].into()),
+            self.camera.pdf_direction(ray),
+            max_depth + 2,
+            true,
+            sampler,
+            &mut camera_path,
+        );
+        let mut light_path = Vec::new();
+        self.light_subpath(world, max_depth + 1, sampler, &mut light_path);
+
+        for t in 1..=camera_path.len() {
+            for s in 0..=light_path.len() {
+                let depth = s + t;
+                if (s == 1 && t == 1) || depth < 2 || depth - 2 > max_depth {
+                    continue;
+                }
+                radiance += &self.connect(world, &light_path, &camera_path, s, t, sampler);
+            }
+        }
+        radiance
+    }
+}
+
+// Converts a solid angle density at `from` to an area density at `to`.
+fn convert_density(pdf: Real, from: &Vertex, to: &Vertex) -> Real {
+    let direction = to.point.vec_from(&from.point);
+    let distance_squared = direction.length_squared();
+    if distance_squared == 0.0 {
+        return 0.0;
+    }
+    let mut pdf = pdf / distance_squared;
+    if to.kind != VertexKind::Camera {
+        pdf *= to.normal.dot_vector(&direction.unit()).abs();
+    }
+    pdf
+}
+
+fn is_black(color: &Color<Real>) -> bool {
+    color.iter().all(|&value| value == 0.0)
+}
@@ -0,0 +1,561 @@
+use crate::base::aabb::Aabb;
+use crate::base::simd::{Mask4, F4};
+use crate::base::Real;
+use crate::hittable::{Hit, HitRecord, Hittable, HittableVec};
+use crate::ray::{Ray, RayPacket};
+use crate::stats::{self, Counter};
+
+const BINS: usize = 12;
+const MAX_LEAF_SIZE: usize = 8;
+// Leaves keep their size in a `u16`
+const _: () = assert!(MAX_LEAF_SIZE <= u16::MAX as usize);
+// Deeper than this the build stops looking for good splits and halves the
+// primitives, so traversal stacks stay bounded.
+const MAX_SAH_DEPTH: usize = 64;
+const STACK_SIZE: usize = 128;
+
+// Bounding volume hierarchy over a list of boxes, built with the surface
+// area heuristic. Nodes are stored depth first: an interior node is followed
+// by its first child and points at its second.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct BvhTree {
+    nodes: Vec<BvhNode>,
+    indices: Vec<u32>,
+}
+
+#[derive(Debug, Clone)]
+struct BvhNode {
+    bounds: Aabb<Real>,
+    // Second child of interior nodes, first index of leaves
+    offset: u32,
+    // Zero for interior nodes
+    count: u16,
+    axis: u8,
+}
+
+// Objects of a `HittableVec` in a `BvhTree`, so a ray only tests the objects
+// whose boxes it passes through. Nested vectors are flattened into the tree.
+#[derive(Debug, Clone)]
+pub struct Bvh<T> {
+    objects: Vec<Hittable<T>>,
+    tree: BvhTree,
+    // Objects without a bounding box, tested by every ray
+    unbounded: Vec<usize>,
+}
+
+impl BvhTree {
+    pub(crate) fn new(boxes: &[Aabb<Real>]) -> Self {
+        let centers: Vec<[Real; 3]> = boxes
+            .iter()
+            .map(|bounds| {
+                let center = bounds.center();
+                [center[0], center[1], center[2]]
+            })
+            .collect();
+        let mut tree = Self {
+            nodes: Vec::with_capacity(boxes.len() * 2),
+            indices: (0..boxes.len() as u32).collect(),
+        };
+        if !boxes.is_empty() {
+            let mut indices = std::mem::take(&mut tree.indices);
+            tree.build(boxes, &centers, &mut indices, 0, 0);
+            tree.indices = indices;
+        }
+        tree
+    }
+
+    pub(crate) fn bounds(&self) -> Option<&Aabb<Real>> {
+        self.nodes.first().map(|node| &node.bounds)
+    }
+
+    fn build(
+        &mut self,
+        boxes: &[Aabb<Real>],
+        centers: &[[Real; 3]],
+        indices: &mut [u32],
+        offset: usize,
+        depth: usize,
+    ) {
+        let node = self.nodes.len();
+        let bounds = indices
+            .iter()
+            .map(|&index| boxes[index as usize].clone())
+            .reduce(|a, b| a.union(&b))
+            .unwrap();
+        self.nodes.push(BvhNode {
+            bounds,
+            offset: offset as u32,
+            count: 0,
+            axis: 0,
+        });
+        if indices.len() <= 2 {
+            return self.leaf(node, indices.len());
+        }
+
+        let mut min = [Real::INFINITY; 3];
+        let mut max = [Real::NEG_INFINITY; 3];
+        for &index in indices.iter() {
+            for axis in 0..3 {
+                min[axis] = min[axis].min(centers[index as usize][axis]);
+                max[axis] = max[axis].max(centers[index as usize][axis]);
+            }
+        }
+        let axis = (0..3)
+            .max_by(|&a, &b| (max[a] - min[a]).partial_cmp(&(max[b] - min[b])).unwrap())
+            .unwrap();
+        let extent = max[axis] - min[axis];
+
+        let split = if extent <= 0.0 {
+            // Every center in the same place: no split separates them
+            if indices.len() <= MAX_LEAF_SIZE {
+                return self.leaf(node, indices.len());
+            }
+            None
+        } else if depth >= MAX_SAH_DEPTH {
+            None
+        } else {
+            let bin = |index: u32| {
+                let position = (centers[index as usize][axis] - min[axis]) / extent;
+                ((position * BINS as Real) as usize).min(BINS - 1)
+            };
+            let mut bins: [(Option<Aabb<Real>>, usize); BINS] = Default::default();
+            for &index in indices.iter() {
+                let (bounds, count) = &mut bins[bin(index)];
+                let object = &boxes[index as usize];
+                *bounds = Some(match bounds {
+                    Some(bounds) => bounds.union(object),
+                    None => object.clone(),
+                });
+                *count += 1;
+            }
+
+            // Cost of splitting after each bin, relative to intersecting
+            // every primitive in the node
+            let sweep = |bins: &mut dyn Iterator<Item = &(Option<Aabb<Real>>, usize)>| {
+                let mut bounds: Option<Aabb<Real>> = None;
+                let mut count = 0;
+                bins.map(|(bin_bounds, bin_count)| {
+                    if let Some(bin_bounds) = bin_bounds {
+                        bounds = Some(match &bounds {
+                            Some(bounds) => bounds.union(bin_bounds),
+                            None => bin_bounds.clone(),
+                        });
+                    }
+                    count += bin_count;
+                    count as Real * bounds.as_ref().map_or(0.0, Aabb::surface_area)
+                })
+                .collect::<Vec<_>>()
+            };
+            let below = sweep(&mut bins.iter());
+            let mut above = sweep(&mut bins.iter().rev());
+            above.reverse();
+            let area = self.nodes[node].bounds.surface_area();
+            let (best, cost) = (0..BINS - 1)
+                .map(|split| (split, below[split] + above[split + 1]))
+                .min_by(|a, b| a.1.partial_cmp(&b.1).unwrap())
+                .unwrap();
+            let cost = 0.125 + cost / area.max(Real::MIN_POSITIVE);
+            if cost >= indices.len() as Real && indices.len() <= MAX_LEAF_SIZE {
+                return self.leaf(node, indices.len());
+            }
+            Some(partition(indices, |index| bin(index) <= best))
+        };
+
+        let middle = match split {
+            Some(middle) if middle > 0 && middle < indices.len() => middle,
+            _ => {
+                let middle = indices.len() / 2;
+                indices.select_nth_unstable_by(middle, |&a, &b| {
+                    centers[a as usize][axis]
+                        .partial_cmp(&centers[b as usize][axis])
+                        .unwrap()
+                });
+                middle
+            }
+        };
+        self.nodes[node].axis = axis as u8;
+        let (below, above) = indices.split_at_mut(middle);
+        self.build(boxes, centers, below, offset, depth + 1);
+        self.nodes[node].offset = self.nodes.len() as u32;
+        self.build(boxes, centers, above, offset + middle, depth + 1);
+    }
+
+    fn leaf(&mut self, node: usize, count: usize) {
+        debug_assert!(count <= MAX_LEAF_SIZE);
+        self.nodes[node].count = count as u16;
+    }
+
+    // Calls `hit` with every primitive in the leaves the ray reaches, nearer
+    // children first. `hit` gets the distance of the closest hit so far and
+    // returns the distance of a closer one, if it found one.
+    pub(crate) fn traverse<F>(&self, ray: &Ray<Real>, t_min: Real, mut t_max: Real, mut hit: F)
+    where
+        F: FnMut(usize, Real) -> Option<Real>,
+    {
+        if self.nodes.is_empty() {
+            return;
+        }
+        let origin = [ray.origin[0], ray.origin[1], ray.origin[2]];
+        let inverse = [
+            1.0 / ray.direction[0],
+            1.0 / ray.direction[1],
+            1.0 / ray.direction[2],
+        ];
+        let mut stack = [0u32; STACK_SIZE];
+        let mut top = 1;
+        let mut visits = 0;
+        while top > 0 {
+            top -= 1;
+            let node = &self.nodes[stack[top] as usize];
+            visits += 1;
+            if !slab(&node.bounds, &origin, &inverse, t_min, t_max) {
+                continue;
+            }
+            if node.count > 0 {
+                let start = node.offset as usize;
+                for &index in &self.indices[start..start + node.count as usize] {
+                    if let Some(t) = hit(index as usize, t_max) {
+                        t_max = t;
+                    }
+                }
+            } else {
+                let first = stack[top] + 1;
+                let (near, far) = if inverse[node.axis as usize] < 0.0 {
+                    (node.offset, first)
+                } else {
+                    (first, node.offset)
+                };
+                stack[top] = far;
+                stack[top + 1] = near;
+                top += 2;
+            }
+        }
+        stats::add(Counter::NodeVisits, visits);
+    }
+
+    // Packet version of `traverse`: a node is entered when any lane of
+    // `active` passes through its box, and `hit` gets the lanes that did. The
+    // order children are visited in follows the first of those lanes.
+    pub(crate) fn traverse_packet<F>(
+        &self,
+        packet: &RayPacket,
+        t_min: F4,
+        t_max: &mut F4,
+        active: Mask4,
+        mut hit: F,
+    ) where
+        F: FnMut(usize, Mask4, &mut F4),
+    {
+        if self.nodes.is_empty() || !active.any() {
+            return;
+        }
+        let mut stack = [0u32; STACK_SIZE];
+        let mut top = 1;
+        let mut visits = 0;
+        while top > 0 {
+            top -= 1;
+            let node = &self.nodes[stack[top] as usize];
+            visits += 1;
+            let lanes = slab_packet(&node.bounds, packet, t_min, *t_max) & active;
+            if !lanes.any() {
+                continue;
+            }
+            if node.count > 0 {
+                let start = node.offset as usize;
+                for &index in &self.indices[start..start + node.count as usize] {
+                    hit(index as usize, lanes, t_max);
+                }
+            } else {
+                let lane = lanes.bits().trailing_zeros() as usize;
+                let direction = packet.inverse.axis(node.axis as usize).to_array()[lane];
+                let first = stack[top] + 1;
+                let (near, far) = if direction < 0.0 {
+                    (node.offset, first)
+                } else {
+                    (first, node.offset)
+                };
+                stack[top] = far;
+                stack[top + 1] = near;
+                top += 2;
+            }
+        }
+        stats::add(Counter::NodeVisits, visits);
+    }
+}
+
+impl Bvh<Real> {
+    pub fn new(world: &HittableVec<Real>) -> Self {
+        let mut objects = Vec::new();
+        flatten(world, &mut objects);
+        let mut boxes = Vec::new();
+        let mut bounded = Vec::new();
+        let mut unbounded = Vec::new();
+        for (index, object) in objects.iter().enumerate() {
+            match object.bounding_box() {
+                Some(bounds) => {
+                    boxes.push(bounds);
+                    bounded.push(index);
+                }
+                None => unbounded.push(index),
+            }
+        }
+        let mut tree = BvhTree::new(&boxes);
+        // Leaves index `objects` rather than `boxes`
+        for index in tree.indices.iter_mut() {
+            *index = bounded[*index as usize] as u32;
+        }
+        Self {
+            objects,
+            tree,
+            unbounded,
+        }
+    }
+
+    // Closest hits of four rays at once. Spheres and meshes are tested
+    // against the whole packet; other objects one ray at a time.
+    pub fn hit_packet(
+        &self,
+        rays: &[Ray<Real>; 4],
+        t_min: Real,
+        t_max: Real,
+    ) -> [Option<HitRecord<Real>>; 4] {
+        let packet = RayPacket::new(rays);
+        let mut records: [Option<HitRecord<Real>>; 4] = Default::default();
+        let mut closest = F4::splat(t_max);
+        let mut tests = 0;
+        let mut test = |index: usize, lanes: Mask4, closest: &mut F4| {
+            tests += 1;
+            match &self.objects[index] {
+                Hittable::Sphere(sphere) => {
+                    let t = sphere.hit_packet(&packet, F4::splat(t_min), *closest);
+                    let hit = t.lt(*closest) & lanes;
+                    for (lane, t) in t.to_array().iter().enumerate() {
+                        if hit.lane(lane) {
+                            records[lane] = Some(sphere.record(&rays[lane], *t));
+                        }
+                    }
+                    *closest = t.select(hit, *closest);
+                }
+                Hittable::Mesh(mesh) => {
+                    mesh.hit_packet(&packet, rays, t_min, lanes, closest, &mut records);
+                }
+                object => {
+                    let mut distances = closest.to_array();
+                    for (lane, ray) in rays.iter().enumerate() {
+                        if !lanes.lane(lane) {
+                            continue;
+                        }
+                        if let Some(rec) = object.hit(ray, t_min, distances[lane]) {
+                            distances[lane] = rec.t;
+                            records[lane] = Some(rec);
+                        }
+                    }
+                    *closest = F4::new(distances);
+                }
+            }
+        };
+        self.tree.traverse_packet(
+            &packet,
+            F4::splat(t_min),
+            &mut closest,
+            Mask4::all_set(),
+            &mut test,
+        );
+        for &index in &self.unbounded {
+            test(index, Mask4::all_set(), &mut closest);
+        }
+        stats::add(Counter::IntersectionTests, tests);
+        records
+    }
+}
+
+impl Hit<Real> for &Bvh<Real> {
+    fn hit(self, ray: &Ray<Real>, t_min: Real, t_max: Real) -> Option<HitRecord<Real>> {
+        let mut closest = None;
+        let mut tests = 0;
+        let mut test = |index: usize, t_max: Real| {
+            tests += 1;
+            let rec = self.objects[index].hit(ray, t_min, t_max)?;
+            let t = rec.t;
+            closest = Some(rec);
+            Some(t)
+        };
+        let mut t_max = t_max;
+        for &index in &self.unbounded {
+            if let Some(t) = test(index, t_max) {
+                t_max = t;
+            }
+        }
+        self.tree.traverse(ray, t_min, t_max, &mut test);
+        stats::add(Counter::IntersectionTests, tests);
+        closest
+    }
+
+    fn bounding_box(self) -> Option<Aabb<Real>> {
+        if self.unbounded.is_empty() {
+            self.tree.bounds().cloned()
+        } else {
+            None
+        }
+    }
+}
+
+fn flatten(world: &HittableVec<Real>, objects: &mut Vec<Hittable<Real>>) {
+    for object in &world.objects {
+        match object {
+            Hittable::HittableVec(vec) => flatten(vec, objects),
+            object => objects.push(object.clone()),
+        }
+    }
+}
+
+// Moves the indices `below` returns true for to the front and returns how
+// many there are.
+fn partition<F: Fn(u32) -> bool>(indices: &mut [u32], below: F) -> usize {
+    let mut middle = 0;
+    for i in 0..indices.len() {
+        if below(indices[i]) {
+            indices.swap(i, middle);
+            middle += 1;
+        }
+    }
+    middle
+}
+
+// Slab test against precomputed reciprocal directions. A zero direction
+// gives NaN on a slab boundary, which `max` and `min` skip.
+fn slab(
+    bounds: &Aabb<Real>,
+    origin: &[Real; 3],
+    inverse: &[Real; 3],
+    t_min: Real,
+    t_max: Real,
+) -> bool {
+    let (mut near, mut far) = (t_min, t_max);
+    for axis in 0..3 {
+        let t0 = (bounds.min[axis] - origin[axis]) * inverse[axis];
+        let t1 = (bounds.max[axis] - origin[axis]) * inverse[axis];
+        near = t0.min(t1).max(near);
+        far = t0.max(t1).min(far);
+        if far < near {
+            return false;
+        }
+    }
+    true
+}
+
+// `min` and `max` on `F4` return their second operand for NaN lanes, so the
+// running bounds go second.
+fn slab_packet(bounds: &Aabb<Real>, packet: &RayPacket, t_min: F4, t_max: F4) -> Mask4 {
+    let (mut near, mut far) = (t_min, t_max);
+    for axis in 0..3 {
+        let origin = packet.origin.axis(axis);
+        let inverse = packet.inverse.axis(axis);
+        let t0 = (F4::splat(bounds.min[axis]) - origin) * inverse;
+        let t1 = (F4::splat(bounds.max[axis]) - origin) * inverse;
+        near = t0.min(t1).max(near);
+        far = t0.max(t1).min(far);
+    }
+    near.le(far)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::base::color::Color;
+    use crate::base::point3::Point3;
+    use crate::base::vec3::Vec3;
+    use crate::hittable::Sphere;
+    use crate::materials::{Lambertian, Materials};
+    use crate::mesh::{Mesh, MeshTriangle};
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    fn grey() -> Materials<Real> {
+        Materials::Lambertian(Lambertian {
+            albedo: Color([0.5, 0.5, 0.5].into()),
+        })
+    }
+
+    // A ground sphere, a field of small spheres and a bumpy mesh through
+    // them.
+    fn world(rng: &mut StdRng) -> HittableVec<Real> {
+        let mut objects = vec![Hittable::Sphere(Sphere::new(
+            Point3([0.0, -1000.0, 0.0].into()),
+            1000.0,
+            grey(),
+        ))];
+        for _ in 0..200 {
+            objects.push(Hittable::Sphere(Sphere::new(
+                Point3([0, 1, 2].map(|_| rng.gen_range(-5.0, 5.0)).into()),
+                rng.gen_range(0.05, 0.6),
+                grey(),
+            )));
+        }
+        let size = 8;
+        let positions = (0..=size)
+            .flat_map(|z| (0..=size).map(move |x| (x, z)))
+            .map(|(x, z)| {
+                let height = rng.gen_range(0.5, 1.5);
+                Point3([x as Real - 4.0, height, z as Real - 4.0].into())
+            })
+            .collect();
+        let corner = |x: usize, z: usize| z * (size + 1) + x;
+        let triangles = (0..size)
+            .flat_map(|z| (0..size).map(move |x| (x, z)))
+            .flat_map(|(x, z)| {
+                [
+                    [corner(x, z), corner(x + 1, z), corner(x + 1, z + 1)],
+                    [corner(x, z), corner(x + 1, z + 1), corner(x, z + 1)],
+                ]
+            })
+            .map(|positions| MeshTriangle {
+                positions,
+                ..MeshTriangle::default()
+            })
+            .collect();
+        objects.push(Hittable::Mesh(Mesh::new(
+            positions,
+            Vec::new(),
+            Vec::new(),
+            Vec::new(),
+            triangles,
+            grey(),
+        )));
+        HittableVec { objects }
+    }
+
+    #[test]
+    fn bvh_and_packets_hit_what_brute_force_hits() {
+        let mut rng = StdRng::seed_from_u64(9);
+        let world = world(&mut rng);
+        let bvh = Bvh::new(&world);
+        let mut hits = 0;
+        for _ in 0..500 {
+            // Four rays fanning out from one origin, as camera rays do
+            let origin = Point3([0, 1, 2].map(|_| rng.gen_range(-6.0, 6.0)).into());
+            let direction = Vec3([0, 1, 2].map(|_| rng.gen_range(-1.0, 1.0)).into());
+            let rays = [(); 4].map(|_| Ray {
+                origin: origin.clone(),
+                direction: &direction + &Vec3([0, 1, 2].map(|_| rng.gen_range(-0.1, 0.1)).into()),
+            });
+            let packet = bvh.hit_packet(&rays, 0.0, Real::INFINITY);
+            for (ray, from_packet) in rays.iter().zip(&packet) {
+                let expected = (&world).hit(ray, 0.0, Real::INFINITY);
+                let from_tree = (&bvh).hit(ray, 0.0, Real::INFINITY);
+                for found in [&from_tree, from_packet] {
+                    match (found, &expected) {
+                        (Some(a), Some(b)) => {
+                            assert!((a.t - b.t).abs() <= 1e-4 * b.t.max(1.0), "{:?}", ray)
+                        }
+                        (None, None) => {}
+                        _ => panic!("{:?}", ray),
+                    }
+                }
+                hits += expected.is_some() as u32;
+            }
+        }
+        // Enough of the rays hit something for the comparison to mean much
+        assert!(hits > 1000, "{}", hits);
+    }
+}
@@ -1,9 +1,70 @@
-use rand::prelude::*;
+use image::{GrayImage, ImageResult};
+use std::path::Path;
 
+use crate::base::aabb::Aabb;
+use crate::base::consts::{FRAC_PI_2, FRAC_PI_4, PI};
+use crate::base::frame::Frame;
 use crate::base::point3::Point3;
-use crate::base::vec3::{Vec3, Vec3Operations};
-use crate::base::XYZ;
+use crate::base::vec3::{Length, Vec3, Vec3Operations};
+use crate::base::Real;
+use crate::hittable::Hit;
 use crate::ray::Ray;
+use crate::sampler::Sampler;
+use crate::stats::{self, Counter};
+
+#[derive(Debug, Clone, Default)]
+pub enum Aperture<T> {
+    #[default]
+    Circle,
+    Polygon {
+        blades: u32,
+        rotation: T,
+    },
+    Mask(ApertureMask<T>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FisheyeMapping {
+    Equidistant,
+    Equisolid,
+}
+
+#[derive(Debug, Clone, Default)]
+pub enum Projection<T> {
+    #[default]
+    Perspective,
+    Orthographic,
+    Fisheye {
+        fov: T,
+        mapping: FisheyeMapping,
+    },
+    Equirectangular,
+    Cylindrical,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Convergence {
+    Parallel,
+    ToeIn,
+    OffAxis,
+}
+
+#[derive(Debug, Clone)]
+pub struct PhysicalCamera<T> {
+    pub focal_length: T,
+    pub sensor_width: T,
+    pub sensor_height: T,
+    pub f_number: T,
+    pub shutter_time: T,
+    pub iso: T,
+}
+
+#[derive(Debug, Clone)]
+pub struct ApertureMask<T> {
+    width: u32,
+    height: u32,
+    cdf: Vec<T>,
+}
 
 #[derive(Debug, Clone, Default)]
 pub struct Camera<T> {
@@ -11,59 +72,631 @@ pub struct Camera<T> {
     horizontal: Vec3<T>,
     vertical: Vec3<T>,
     pub origin: Point3<T>,
-    u: Vec3<T>,
-    v: Vec3<T>,
-    w: Vec3<T>,
+    // Right, up and forward
+    frame: Frame<T>,
+    half_height: T,
+    aspect_ratio: T,
+    focus_dist: T,
+    shift: T,
+    eye_offset: T,
     lens_radius: T,
+    exposure: T,
+    aperture: Aperture<T>,
+    projection: Projection<T>,
+}
+
+// A lens position that sees a given scene point, with the importance the
+// camera assigns to the ray through it and the solid angle density of
+// having picked it, measured at the scene point.
+#[derive(Debug, Clone)]
+pub(crate) struct LensSample {
+    pub(crate) u: Real,
+    pub(crate) v: Real,
+    pub(crate) lens_point: Point3<Real>,
+    pub(crate) importance: Real,
+    pub(crate) pdf: Real,
+}
+
+impl Aperture<Real> {
+    // Area of the shape `sample` covers inside the unit disk.
+    fn area(&self) -> Real {
+        match self {
+            Aperture::Polygon { blades, .. } if *blades >= 3 => {
+                let blades = *blades as Real;
+                blades / 2.0 * (2.0 * PI / blades).sin()
+            }
+            _ => PI,
+        }
+    }
+
+    // Maps a uniform sample on the unit square to a uniformly distributed
+    // point on the aperture, scaled to fit the unit disk.
+    fn sample(&self, u: Real, v: Real) -> (Real, Real) {
+        match self {
+            Aperture::Polygon { blades, rotation } if *blades >= 3 => {
+                let blades = *blades as Real;
+                let scaled = u * blades;
+                let blade = scaled.floor().min(blades - 1.0);
+                let u = scaled - blade;
+                let start = rotation.to_radians() + 2.0 * PI * blade / blades;
+                let end = start + 2.0 * PI / blades;
+                let su = u.sqrt();
+                (
+                    su * ((1.0 - v) * start.cos() + v * end.cos()),
+                    su * ((1.0 - v) * start.sin() + v * end.sin()),
+                )
+            }
+            Aperture::Mask(mask) => mask.sample(u, v),
+            _ => concentric_disk(u, v),
+        }
+    }
+}
+
+// Lengths are in millimetres, the shutter time in seconds, and one scene unit
+// is taken to be one metre.
+impl PhysicalCamera<Real> {
+    pub fn full_frame(focal_length: Real, f_number: Real, shutter_time: Real, iso: Real) -> Self {
+        Self {
+            focal_length,
+            sensor_width: 36.0,
+            sensor_height: 24.0,
+            f_number,
+            shutter_time,
+            iso,
+        }
+    }
+
+    pub fn vfov(&self) -> Real {
+        2.0 * (self.sensor_height / (2.0 * self.focal_length))
+            .atan()
+            .to_degrees()
+    }
+
+    pub fn aspect_ratio(&self) -> Real {
+        self.sensor_width / self.sensor_height
+    }
+
+    pub fn aperture(&self) -> Real {
+        self.focal_length / self.f_number / 1000.0
+    }
+
+    // Relative to the "sunny 16" exposure (f/16, 1/100 s at ISO 100), which
+    // maps a radiance of 1.0 to 1.0, so a sky of 1.0 reads as daylight.
+    pub fn exposure(&self) -> Real {
+        256.0 * self.shutter_time * self.iso / self.f_number.powi(2)
+    }
 }
 
-impl Camera<f64> {
-    pub fn get_ray(&self, u: f64, v: f64) -> Ray<f64> {
-        let mut rng = thread_rng();
-        let rd = Vec3([rng.gen_range(-1.0, 1.0), rng.gen_range(-1.0, 1.0), 0.0].into()).unit()
-            * self.lens_radius;
-        let offset = &self.u * *rd.x() + &self.v * *rd.y();
+impl ApertureMask<Real> {
+    pub fn open<P: AsRef<Path>>(path: P) -> ImageResult<Self> {
+        Ok(Self::from_image(&image::open(path)?.to_luma8()))
+    }
+
+    pub fn from_image(image: &GrayImage) -> Self {
+        let mut total = 0.0;
+        let mut cdf: Vec<Real> = image
+            .pixels()
+            .map(|pixel| {
+                total += pixel[0] as Real;
+                total
+            })
+            .collect();
+        if total > 0.0 {
+            cdf.iter_mut().for_each(|value| *value /= total);
+        }
+        Self {
+            width: image.width(),
+            height: image.height(),
+            cdf,
+        }
+    }
+
+    fn sample(&self, u: Real, v: Real) -> (Real, Real) {
+        if self.cdf.last().is_none_or(|&total| total <= 0.0) {
+            return concentric_disk(u, v);
+        }
+        let index = self
+            .cdf
+            .partition_point(|&value| value <= u)
+            .min(self.cdf.len() - 1);
+        let start = if index > 0 { self.cdf[index - 1] } else { 0.0 };
+        let jitter = (u - start) / (self.cdf[index] - start);
+
+        let size = self.width.max(self.height) as Real;
+        let x = (index as u32 % self.width) as Real + jitter;
+        let y = (index as u32 / self.width) as Real + v;
+        (
+            (2.0 * x - self.width as Real) / size,
+            (self.height as Real - 2.0 * y) / size,
+        )
+    }
+}
+
+impl Camera<Real> {
+    // Returns `None` for image points the projection does not cover, such as
+    // the corners outside a circular fisheye.
+    pub fn get_ray<S: Sampler>(&self, u: Real, v: Real, sampler: &mut S) -> Option<Ray<Real>> {
+        let ray = self.project(u, v, sampler);
+        if ray.is_some() {
+            stats::count(Counter::CameraRays);
+        }
+        ray
+    }
+
+    fn project<S: Sampler>(&self, u: Real, v: Real, sampler: &mut S) -> Option<Ray<Real>> {
+        match &self.projection {
+            Projection::Perspective => {
+                let target = &self.upper_left_corner + &(&self.horizontal * u + &self.vertical * v);
+                Some(self.thin_lens_ray(&self.origin, &target, sampler))
+            }
+            Projection::Orthographic => {
+                let target = &self.upper_left_corner + &(&self.horizontal * u + &self.vertical * v);
+                let origin = &target - &(&self.frame.n * self.focus_dist);
+                Some(self.thin_lens_ray(&origin, &target, sampler))
+            }
+            Projection::Fisheye { fov, mapping } => {
+                let x = (2.0 * u - 1.0) * self.aspect_ratio;
+                let y = 1.0 - 2.0 * v;
+                let radius = (x * x + y * y).sqrt();
+                if radius > 1.0 {
+                    return None;
+                }
+                let half_fov = fov.to_radians() / 2.0;
+                let theta = match mapping {
+                    FisheyeMapping::Equidistant => radius * half_fov,
+                    FisheyeMapping::Equisolid => 2.0 * (radius * (half_fov / 2.0).sin()).asin(),
+                };
+                let phi = y.atan2(x);
+                Some(self.direction_ray(
+                    theta.cos(),
+                    theta.sin() * phi.cos(),
+                    theta.sin() * phi.sin(),
+                ))
+            }
+            Projection::Equirectangular => {
+                let longitude = (u - 0.5) * 2.0 * PI;
+                let latitude = (0.5 - v) * PI;
+                let mut ray = self.direction_ray(
+                    latitude.cos() * longitude.cos(),
+                    latitude.cos() * longitude.sin(),
+                    latitude.sin(),
+                );
+                // Omni-directional stereo: each column sees the scene from a
+                // point on the viewing circle tangent to its direction.
+                if self.eye_offset != 0.0 {
+                    let tangent = &self.frame.s * longitude.cos() - &self.frame.n * longitude.sin();
+                    ray.origin = &ray.origin + &(tangent * self.eye_offset);
+                }
+                Some(ray)
+            }
+            Projection::Cylindrical => {
+                let longitude = (u - 0.5) * 2.0 * PI;
+                let height = (1.0 - 2.0 * v) * self.half_height;
+                Some(self.direction_ray(longitude.cos(), longitude.sin(), height))
+            }
+        }
+    }
+
+    fn thin_lens_ray<S: Sampler>(
+        &self,
+        origin: &Point3<Real>,
+        target: &Point3<Real>,
+        sampler: &mut S,
+    ) -> Ray<Real> {
+        let (lens_u, lens_v) = sampler.next_2d();
+        let (lens_x, lens_y) = self.aperture.sample(lens_u, lens_v);
+        let offset = &self.frame.s * (lens_x * self.lens_radius)
+            + &self.frame.t * (lens_y * self.lens_radius);
+        let origin = origin + &offset;
         Ray {
-            origin: &self.origin + &offset,
-            direction: (&self.upper_left_corner + &(&self.horizontal * u + &self.vertical * v))
-                .vec_from(&self.origin)
-                - offset,
+            direction: target.vec_from(&origin),
+            origin,
+        }
+    }
+
+    // Light paths can only be connected to perspective cameras whose
+    // aperture is sampled uniformly.
+    pub(crate) fn is_connectable(&self) -> bool {
+        matches!(self.projection, Projection::Perspective)
+            && !matches!(self.aperture, Aperture::Mask(_))
+    }
+
+    fn lens_area(&self) -> Real {
+        if self.lens_radius == 0.0 {
+            1.0
+        } else {
+            self.aperture.area() * self.lens_radius.powi(2)
+        }
+    }
+
+    // Image plane area at unit distance from the lens.
+    fn image_area(&self) -> Real {
+        4.0 * self.half_height.powi(2) * self.aspect_ratio
+    }
+
+    // Image coordinates where `ray`, leaving the lens, crosses the plane in
+    // focus.
+    pub(crate) fn raster(&self, ray: &Ray<Real>) -> Option<(Real, Real)> {
+        let cos_theta = ray.direction.dot(&self.frame.n);
+        if cos_theta <= 0.0 {
+            return None;
+        }
+        let focus = ray.at(self.focus_dist / cos_theta);
+        let local = focus.vec_from(&self.upper_left_corner);
+        let u = local.dot(&self.horizontal) / self.horizontal.length_squared();
+        let v = local.dot(&self.vertical) / self.vertical.length_squared();
+        if (0.0..1.0).contains(&u) && (0.0..1.0).contains(&v) {
+            Some((u, v))
+        } else {
+            None
+        }
+    }
+
+    // Solid angle density of `get_ray` producing the direction of `ray`.
+    pub(crate) fn pdf_direction(&self, ray: &Ray<Real>) -> Real {
+        if !self.is_connectable() || self.raster(ray).is_none() {
+            return 0.0;
+        }
+        let cos_theta = ray.direction.unit().dot(&self.frame.n);
+        1.0 / (self.image_area() * cos_theta.powi(3))
+    }
+
+    pub(crate) fn sample_importance<S: Sampler>(
+        &self,
+        point: &Point3<Real>,
+        sampler: &mut S,
+    ) -> Option<LensSample> {
+        let (lens_u, lens_v) = sampler.next_2d();
+        if !self.is_connectable() {
+            return None;
+        }
+        let (lens_x, lens_y) = self.aperture.sample(lens_u, lens_v);
+        let offset = &self.frame.s * (lens_x * self.lens_radius)
+            + &self.frame.t * (lens_y * self.lens_radius);
+        let lens_point = &self.origin + &offset;
+        let direction = point.vec_from(&lens_point);
+        let (u, v) = self.raster(&Ray {
+            origin: lens_point.clone(),
+            direction: direction.clone(),
+        })?;
+
+        let lens_area = self.lens_area();
+        let cos_theta = direction.unit().dot(&self.frame.n);
+        Some(LensSample {
+            u,
+            v,
+            lens_point,
+            importance: 1.0 / (self.image_area() * lens_area * cos_theta.powi(4)),
+            pdf: direction.length_squared() / (cos_theta * lens_area),
+        })
+    }
+
+    fn direction_ray(&self, forward: Real, right: Real, up: Real) -> Ray<Real> {
+        Ray {
+            origin: self.origin.clone(),
+            direction: &self.frame.n * forward + &self.frame.s * right + &self.frame.t * up,
         }
     }
 
     pub fn new(
-        look_from: Point3<f64>,
-        look_at: Point3<f64>,
-        up: Vec3<f64>,
-        vfov: f64,
-        aspect_ratio: f64,
-        aperture: f64,
-        focus_dist: f64,
+        look_from: Point3<Real>,
+        look_at: Point3<Real>,
+        up: Vec3<Real>,
+        vfov: Real,
+        aspect_ratio: Real,
+        aperture: Real,
+        focus_dist: Real,
     ) -> Self {
         let lens_radius = aperture / 2.0;
 
         let theta = vfov.to_radians();
         let half_height = (theta / 2.0).tan();
-        let half_width = half_height * aspect_ratio;
 
-        let w = look_at.vec_from(&look_from).unit();
-        let u = up.cross(&w).unit();
-        let v = w.cross(&u);
+        let frame = Frame::looking(&look_at.vec_from(&look_from), &up);
 
-        let upper_left_corner = &look_from
-            - &(&u * focus_dist * half_width - &v * focus_dist * half_height - &w * focus_dist);
-        let horizontal = &u * half_width * focus_dist * 2.0;
-        let vertical = &v * half_height * focus_dist * -2.0;
-
-        Camera {
-            upper_left_corner,
-            horizontal,
-            vertical,
+        let mut camera = Camera {
             origin: look_from,
+            frame,
+            half_height,
+            aspect_ratio,
+            focus_dist,
+            lens_radius,
+            exposure: 1.0,
+            ..Default::default()
+        };
+        camera.update_viewport();
+        camera
+    }
+
+    pub fn physical(
+        look_from: Point3<Real>,
+        look_at: Point3<Real>,
+        up: Vec3<Real>,
+        physical: &PhysicalCamera<Real>,
+        focus_dist: Real,
+    ) -> Self {
+        let mut camera = Camera::new(
+            look_from,
+            look_at,
+            up,
+            physical.vfov(),
+            physical.aspect_ratio(),
+            physical.aperture(),
+            focus_dist,
+        );
+        camera.exposure = physical.exposure();
+        camera
+    }
+
+    // Places the camera along `view_direction` so the bounding sphere of
+    // `bounds` just fits the narrower of the two fields of view, and focuses
+    // on its center.
+    pub fn framing(
+        bounds: &Aabb<Real>,
+        view_direction: &Vec3<Real>,
+        up: Vec3<Real>,
+        vfov: Real,
+        aspect_ratio: Real,
+        aperture: Real,
+    ) -> Self {
+        let half_vfov = vfov.to_radians() / 2.0;
+        let half_hfov = (half_vfov.tan() * aspect_ratio).atan();
+        let radius = bounds.diagonal().length() / 2.0;
+        let distance = radius / half_vfov.min(half_hfov).sin();
+
+        let look_at = bounds.center();
+        let look_from = &look_at - &(view_direction.unit() * distance);
+        Camera::new(
+            look_from,
+            look_at,
+            up,
+            vfov,
+            aspect_ratio,
+            aperture,
+            distance,
+        )
+    }
+
+    // Casts a pinhole ray through image point (`u`, `v`) and moves the focus
+    // plane onto whatever it hits. Returns the new focus distance.
+    pub fn autofocus<'a, T>(&mut self, world: &'a T, u: Real, v: Real) -> Option<Real>
+    where
+        &'a T: Hit<Real>,
+    {
+        let ray = Ray {
+            origin: self.origin.clone(),
+            direction: (&self.upper_left_corner + &(&self.horizontal * u + &self.vertical * v))
+                .vec_from(&self.origin),
+        };
+        let rec = world.hit(&ray, 0.0, Real::INFINITY)?;
+        self.focus_dist = rec.point.vec_from(&self.origin).dot(&self.frame.n);
+        self.update_viewport();
+        Some(self.focus_dist)
+    }
+
+    pub fn exposure(&self) -> Real {
+        self.exposure
+    }
+
+    fn update_viewport(&mut self) {
+        let half_height = self.half_height * self.focus_dist;
+        let half_width = half_height * self.aspect_ratio;
+        self.upper_left_corner = &self.origin
+            - &(&self.frame.s * (half_width - self.shift)
+                - &self.frame.t * half_height
+                - &self.frame.n * self.focus_dist);
+        self.horizontal = &self.frame.s * half_width * 2.0;
+        self.vertical = &self.frame.t * half_height * -2.0;
+    }
+
+    // Camera for one eye `eye_offset` along the camera's right axis,
+    // converging at the focus distance. Panoramic projections ignore
+    // `convergence` and use omni-directional stereo instead.
+    pub fn stereo_eye(&self, eye_offset: Real, convergence: Convergence) -> Self {
+        let mut eye = self.clone();
+        if let Projection::Equirectangular = self.projection {
+            eye.eye_offset = eye_offset;
+            return eye;
+        }
+
+        eye.origin = &self.origin + &(&self.frame.s * eye_offset);
+        match convergence {
+            Convergence::Parallel => {}
+            Convergence::ToeIn => {
+                let target = &self.origin + &(&self.frame.n * self.focus_dist);
+                eye.frame = Frame::looking(&target.vec_from(&eye.origin), &self.frame.t);
+            }
+            Convergence::OffAxis => eye.shift = self.shift - eye_offset,
+        }
+        eye.update_viewport();
+        eye
+    }
+
+    pub fn with_projection(mut self, projection: Projection<Real>) -> Self {
+        self.projection = projection;
+        self
+    }
+
+    pub fn with_aperture(mut self, aperture: Aperture<Real>) -> Self {
+        self.aperture = aperture;
+        self
+    }
+}
+
+fn concentric_disk(u: Real, v: Real) -> (Real, Real) {
+    let x = 2.0 * u - 1.0;
+    let y = 2.0 * v - 1.0;
+    if x == 0.0 && y == 0.0 {
+        return (0.0, 0.0);
+    }
+    let (radius, theta) = if x.abs() > y.abs() {
+        (x, FRAC_PI_4 * (y / x))
+    } else {
+        (y, FRAC_PI_2 - FRAC_PI_4 * (x / y))
+    };
+    (radius * theta.cos(), radius * theta.sin())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::base::color::Color;
+    use crate::hittable::Sphere;
+    use crate::materials::{Lambertian, Materials};
+    use crate::sampler::IndependentSampler;
+    use image::Luma;
+
+    #[test]
+    fn polygon_samples_stay_inside_the_blades() {
+        let blades = 5;
+        let rotation: Real = 18.0;
+        let aperture = Aperture::Polygon { blades, rotation };
+        let corner = |k: u32| {
+            let angle = rotation.to_radians() + 2.0 * PI * k as Real / blades as Real;
+            (angle.cos(), angle.sin())
+        };
+        for i in 0..32 {
+            for j in 0..32 {
+                let (x, y) = aperture.sample(i as Real / 32.0, j as Real / 32.0);
+                // Counter-clockwise corners, so inside is left of every edge
+                for k in 0..blades {
+                    let (ax, ay) = corner(k);
+                    let (bx, by) = corner(k + 1);
+                    let side = (bx - ax) * (y - ay) - (by - ay) * (x - ax);
+                    assert!(side > -1e-6, "({}, {}) outside edge {}", x, y, k);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn mask_samples_land_on_open_pixels() {
+        // Two open pixels in a wide mask, one brighter than the other
+        let mut image = GrayImage::new(6, 4);
+        image.put_pixel(4, 1, Luma([255]));
+        image.put_pixel(1, 2, Luma([64]));
+        let aperture = Aperture::Mask(ApertureMask::from_image(&image));
+        for i in 0..32 {
+            for j in 0..32 {
+                let (x, y) = aperture.sample(i as Real / 32.0, j as Real / 32.0);
+                // Back to pixel coordinates
+                let column = ((x * 6.0 + 6.0) / 2.0).floor() as u32;
+                let row = ((4.0 - y * 6.0) / 2.0).floor() as u32;
+                assert_ne!(image.get_pixel(column, row)[0], 0, "({}, {})", x, y);
+            }
+        }
+    }
+
+    fn panoramic(projection: Projection<Real>) -> Camera<Real> {
+        Camera::new(
+            Point3([0.0, 0.0, 0.0].into()),
+            Point3([0.0, 0.0, -1.0].into()),
+            Vec3([0.0, 1.0, 0.0].into()),
+            90.0,
+            1.0,
+            0.0,
+            1.0,
+        )
+        .with_projection(projection)
+    }
+
+    fn assert_direction(camera: &Camera<Real>, u: Real, v: Real, expected: &Vec3<Real>) {
+        let mut sampler = IndependentSampler::new(5);
+        sampler.start_pixel_sample(0, 0, 0);
+        let ray = camera.get_ray(u, v, &mut sampler).unwrap();
+        let cosine = ray.direction.unit().dot(&expected.unit());
+        assert!(
+            cosine > 1.0 - 1e-6,
+            "({}, {}) looks along {:?}",
             u,
             v,
-            w,
-            lens_radius,
+            ray.direction
+        );
+    }
+
+    #[test]
+    fn fisheye_maps_radius_to_angle() {
+        for mapping in [FisheyeMapping::Equidistant, FisheyeMapping::Equisolid] {
+            let camera = panoramic(Projection::Fisheye {
+                fov: 180.0,
+                mapping,
+            });
+            let Frame { s, t, n } = camera.frame.clone();
+            assert_direction(&camera, 0.5, 0.5, &n);
+            // The rim of the circle is a quarter turn off the axis
+            assert_direction(&camera, 1.0, 0.5, &s);
+            assert_direction(&camera, 0.0, 0.5, &(&s * -1.0));
+            assert_direction(&camera, 0.5, 0.0, &t);
+
+            // Halfway out
+            let theta = match mapping {
+                FisheyeMapping::Equidistant => FRAC_PI_4,
+                FisheyeMapping::Equisolid => 2.0 * (0.5 * FRAC_PI_4.sin()).asin(),
+            };
+            assert_direction(&camera, 0.75, 0.5, &(&n * theta.cos() + &s * theta.sin()));
+
+            let mut sampler = IndependentSampler::new(5);
+            sampler.start_pixel_sample(0, 0, 0);
+            assert!(camera.get_ray(0.0, 0.0, &mut sampler).is_none());
         }
     }
+
+    #[test]
+    fn equirectangular_maps_pixels_to_longitude_and_latitude() {
+        let camera = panoramic(Projection::Equirectangular);
+        let Frame { s, t, n } = camera.frame.clone();
+        assert_direction(&camera, 0.5, 0.5, &n);
+        assert_direction(&camera, 0.75, 0.5, &s);
+        assert_direction(&camera, 0.25, 0.5, &(&s * -1.0));
+        assert_direction(&camera, 0.0, 0.5, &(&n * -1.0));
+        assert_direction(&camera, 0.5, 0.0, &t);
+        assert_direction(&camera, 0.5, 0.75, &(&n - &t));
+    }
+
+    #[test]
+    fn autofocus_measures_depth_along_the_axis() {
+        let material = Materials::Lambertian(Lambertian {
+            albedo: Color([0.5, 0.5, 0.5].into()),
+        });
+        let mut camera = panoramic(Projection::Perspective);
+        let ball = Sphere::new(Point3([0.0, 0.0, -5.0].into()), 1.0, material.clone());
+        assert!((camera.autofocus(&ball, 0.5, 0.5).unwrap() - 4.0).abs() < 1e-4);
+        assert!(camera.autofocus(&ball, 0.0, 0.0).is_none());
+        assert!((camera.focus_dist - 4.0).abs() < 1e-4);
+
+        // Off to the side of a near-flat wall five units away, which curves
+        // back by about a hundredth there
+        let wall = Sphere::new(Point3([0.0, 0.0, -1005.0].into()), 1000.0, material);
+        let depth = camera.autofocus(&wall, 0.9, 0.3).unwrap();
+        assert!((depth - 5.0).abs() < 0.02, "{}", depth);
+    }
+
+    #[test]
+    fn framing_fits_the_bounding_sphere() {
+        let bounds = Aabb::new(
+            Point3([-1.0, 0.0, -1.0].into()),
+            Point3([1.0, 2.0, 1.0].into()),
+        );
+        // Wider than tall, so the 30 degree half height is the tight one
+        let camera = Camera::framing(
+            &bounds,
+            &Vec3([0.0, 0.0, -1.0].into()),
+            Vec3([0.0, 1.0, 0.0].into()),
+            60.0,
+            2.0,
+            0.0,
+        );
+        let distance = (3.0 as Real).sqrt() / 0.5;
+        let expected = Point3([0.0, 1.0, distance].into());
+        assert!(camera.origin.vec_from(&expected).length() < 1e-4);
+        assert!((camera.focus_dist - distance).abs() < 1e-4);
+    }
+
+    #[test]
+    fn sunny_16_is_unit_exposure() {
+        let camera = PhysicalCamera::full_frame(50.0, 16.0, 0.01, 100.0);
+        assert!((camera.exposure() - 1.0).abs() < 1e-6);
+        let faster = PhysicalCamera::full_frame(50.0, 8.0, 0.0025, 100.0);
+        assert!((faster.exposure() - 1.0).abs() < 1e-6);
+    }
 }
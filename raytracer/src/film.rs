@@ -0,0 +1,448 @@
+use image::RgbImage;
+use num_traits::AsPrimitive;
+use std::cell::RefCell;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+use crate::base::color::Color;
+use crate::base::Real;
+use crate::filter::Filter;
+use crate::renderer::Tile;
+
+const CHECKPOINT_MAGIC: &[u8; 8] = b"RTCKPT04";
+
+// What decides the samples a render draws and how they are weighted.
+// Checkpoints record it so that a resumed render cannot mix in samples from a
+// different stream or reconstruct them with a different filter.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SampleSettings {
+    pub seed: u64,
+    pub sampler: String,
+    pub samples_per_pixel: u32,
+    pub pass_samples: u32,
+    pub filter: Filter<f64>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Film {
+    pub width: u32,
+    pub height: u32,
+    pub samples: u32,
+    pub exposure: f64,
+    pub filter: Filter<f64>,
+    // Size of the separate images the film is tiled with, such as the two
+    // eyes of a stereo pair; the filter never spreads a sample past the edge
+    // of its own. The whole film by default.
+    pub viewport: (u32, u32),
+    pixels: Vec<Color<f64>>,
+    weights: Vec<f64>,
+    splats: Vec<Color<f64>>,
+}
+
+// Light tracing strategies land on arbitrary pixels from any tile. They are
+// recorded on the calling thread, like the render statistics, and whoever
+// drains them with `take` adds them to the film in a fixed order, so the
+// sums do not depend on which thread got which work. The film divides them
+// by the sample count when it is read.
+#[derive(Debug, Clone, Copy)]
+pub struct SplatBuffer {
+    width: u32,
+    height: u32,
+}
+
+thread_local! {
+    static SPLATS: RefCell<Vec<(usize, Color<f64>)>> = const { RefCell::new(Vec::new()) };
+}
+
+// Samples near a tile edge spread into the neighbouring tiles, so a film tile
+// covers its tile plus the filter radius and is merged into the film as a sum.
+#[derive(Debug, Clone)]
+pub struct FilmTile {
+    pub x0: u32,
+    pub y0: u32,
+    pub x1: u32,
+    pub y1: u32,
+    filter: Filter<f64>,
+    viewport: (u32, u32),
+    pixels: Vec<Color<f64>>,
+    weights: Vec<f64>,
+    splats: Vec<(usize, Color<f64>)>,
+}
+
+impl Film {
+    pub fn new(width: u32, height: u32) -> Self {
+        let size = (width * height) as usize;
+        Self {
+            width,
+            height,
+            samples: 0,
+            exposure: 1.0,
+            filter: Filter::default(),
+            viewport: (width, height),
+            pixels: vec![Color::default(); size],
+            weights: vec![0.0; size],
+            splats: vec![Color::default(); size],
+        }
+    }
+
+    pub fn splats(&self) -> SplatBuffer {
+        SplatBuffer::new(self.width, self.height)
+    }
+
+    // Adds splats drained with `SplatBuffer::take` in the order given.
+    pub fn add_splats(&mut self, splats: &[(usize, Color<f64>)]) {
+        for (index, color) in splats {
+            self.splats[*index] += color;
+        }
+    }
+
+    pub fn film_tile(&self, tile: &Tile) -> FilmTile {
+        let padding = (self.filter.radius() - 0.5).ceil().max(0.0) as u32;
+        let x0 = tile.x0.saturating_sub(padding);
+        let y0 = tile.y0.saturating_sub(padding);
+        let x1 = (tile.x1 + padding).min(self.width);
+        let y1 = (tile.y1 + padding).min(self.height);
+        let size = ((x1 - x0) * (y1 - y0)) as usize;
+        FilmTile {
+            x0,
+            y0,
+            x1,
+            y1,
+            filter: self.filter,
+            viewport: self.viewport,
+            pixels: vec![Color::default(); size],
+            weights: vec![0.0; size],
+            splats: Vec::new(),
+        }
+    }
+
+    pub fn merge_tile(&mut self, tile: &FilmTile) {
+        let tile_width = tile.x1 - tile.x0;
+        for y in tile.y0..tile.y1 {
+            for x in tile.x0..tile.x1 {
+                let index = (y * self.width + x) as usize;
+                let tile_index = ((y - tile.y0) * tile_width + x - tile.x0) as usize;
+                self.pixels[index] += &tile.pixels[tile_index];
+                self.weights[index] += tile.weights[tile_index];
+            }
+        }
+        self.add_splats(&tile.splats);
+    }
+
+    pub fn pixel(&self, x: u32, y: u32) -> Color<f64> {
+        let index = (y * self.width + x) as usize;
+        let mut pixel = if self.weights[index] != 0.0 {
+            &self.pixels[index] / self.weights[index]
+        } else {
+            Color::default()
+        };
+        if self.samples > 0 {
+            pixel += &(&self.splats[index] / self.samples as f64);
+        }
+        pixel
+    }
+
+    pub fn to_image(&self) -> RgbImage {
+        RgbImage::from_fn(self.width, self.height, |x, y| {
+            (self.pixel(x, y) * self.exposure).as_rgb(1)
+        })
+    }
+
+    // The checkpoint is written next to `path` first and renamed over it, so
+    // a render killed mid-write still leaves the previous checkpoint intact.
+    pub fn save_checkpoint<P: AsRef<Path>>(
+        &self,
+        path: P,
+        settings: &SampleSettings,
+    ) -> io::Result<()> {
+        let path = path.as_ref();
+        let temp_path = path.with_extension("tmp");
+        {
+            let mut writer = BufWriter::new(File::create(&temp_path)?);
+            writer.write_all(CHECKPOINT_MAGIC)?;
+            for value in &[self.width, self.height, self.samples] {
+                writer.write_all(&value.to_le_bytes())?;
+            }
+            writer.write_all(&settings.seed.to_le_bytes())?;
+            writer.write_all(&settings.samples_per_pixel.to_le_bytes())?;
+            writer.write_all(&settings.pass_samples.to_le_bytes())?;
+            write_filter(&mut writer, &settings.filter)?;
+            writer.write_all(&(settings.sampler.len() as u32).to_le_bytes())?;
+            writer.write_all(settings.sampler.as_bytes())?;
+            for ((pixel, weight), splat) in self.pixels.iter().zip(&self.weights).zip(&self.splats)
+            {
+                for value in pixel.iter().chain(Some(weight)).chain(splat.iter()) {
+                    writer.write_all(&value.to_le_bytes())?;
+                }
+            }
+            writer.flush()?;
+        }
+        fs::rename(temp_path, path)
+    }
+
+    // Also returns the settings the samples were drawn with; the film takes
+    // its filter from them.
+    pub fn load_checkpoint<P: AsRef<Path>>(path: P) -> io::Result<(Self, SampleSettings)> {
+        let mut reader = BufReader::new(File::open(path)?);
+        let mut magic = [0; 8];
+        reader.read_exact(&mut magic)?;
+        if &magic != CHECKPOINT_MAGIC {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "not a raytracer checkpoint",
+            ));
+        }
+
+        let width = read_u32(&mut reader)?;
+        let height = read_u32(&mut reader)?;
+        let mut film = Film::new(width, height);
+        film.samples = read_u32(&mut reader)?;
+        let seed = read_u64(&mut reader)?;
+        let samples_per_pixel = read_u32(&mut reader)?;
+        let pass_samples = read_u32(&mut reader)?;
+        let filter = read_filter(&mut reader)?;
+        let mut sampler = vec![0; read_u32(&mut reader)? as usize];
+        reader.read_exact(&mut sampler)?;
+        let sampler = String::from_utf8(sampler)
+            .map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))?;
+        film.filter = filter;
+        for ((pixel, weight), splat) in film
+            .pixels
+            .iter_mut()
+            .zip(film.weights.iter_mut())
+            .zip(film.splats.iter_mut())
+        {
+            for value in pixel.iter_mut() {
+                *value = read_f64(&mut reader)?;
+            }
+            *weight = read_f64(&mut reader)?;
+            for value in splat.iter_mut() {
+                *value = read_f64(&mut reader)?;
+            }
+        }
+        let settings = SampleSettings {
+            seed,
+            sampler,
+            samples_per_pixel,
+            pass_samples,
+            filter,
+        };
+        Ok((film, settings))
+    }
+}
+
+impl fmt::Display for SampleSettings {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "seed {}, {} sampler, {} spp in passes of {}, {:?} filter",
+            self.seed, self.sampler, self.samples_per_pixel, self.pass_samples, self.filter
+        )
+    }
+}
+
+impl FilmTile {
+    // `x` and `y` are continuous raster coordinates; pixel centers sit at
+    // half-integer positions.
+    pub fn add_sample(&mut self, x: Real, y: Real, color: &Color<Real>) {
+        let (x, y, color): (f64, f64, _) = (x.as_(), y.as_(), widen(color));
+        let radius = self.filter.radius();
+        // Pixels of the tile within the sample's viewport
+        let (width, height) = self.viewport;
+        let (vx, vy) = (x as u32 / width * width, y as u32 / height * height);
+        let (x0, y0) = (self.x0.max(vx), self.y0.max(vy));
+        let (x1, y1) = (self.x1.min(vx + width), self.y1.min(vy + height));
+        let x_start = ((x - 0.5 - radius).ceil().max(x0 as f64)) as u32;
+        let y_start = ((y - 0.5 - radius).ceil().max(y0 as f64)) as u32;
+        let x_end = ((x - 0.5 + radius).floor() + 1.0).min(x1 as f64).max(0.0) as u32;
+        let y_end = ((y - 0.5 + radius).floor() + 1.0).min(y1 as f64).max(0.0) as u32;
+
+        let tile_width = self.x1 - self.x0;
+        for py in y_start..y_end {
+            for px in x_start..x_end {
+                let weight = self
+                    .filter
+                    .evaluate(px as f64 + 0.5 - x, py as f64 + 0.5 - y);
+                if weight != 0.0 {
+                    let index = ((py - self.y0) * tile_width + px - self.x0) as usize;
+                    self.pixels[index] += &(&color * weight);
+                    self.weights[index] += weight;
+                }
+            }
+        }
+    }
+
+    // Moves the splats this thread recorded while rendering the tile into
+    // it, to be added to the film when the tile is merged.
+    pub fn collect_splats(&mut self) {
+        self.splats = SplatBuffer::take();
+    }
+}
+
+impl SplatBuffer {
+    pub fn new(width: u32, height: u32) -> Self {
+        Self { width, height }
+    }
+
+    // `x` and `y` are continuous raster coordinates, as for `add_sample`;
+    // splats outside the film are dropped.
+    pub fn add(&self, x: Real, y: Real, color: &Color<Real>) {
+        let (x, y): (f64, f64) = (x.as_(), y.as_());
+        if x < 0.0 || y < 0.0 || x >= self.width as f64 || y >= self.height as f64 {
+            return;
+        }
+        let index = (y as u32 * self.width + x as u32) as usize;
+        SPLATS.with(|splats| splats.borrow_mut().push((index, widen(color))));
+    }
+
+    // Drains the splats recorded on the calling thread, by pixel index.
+    pub fn take() -> Vec<(usize, Color<f64>)> {
+        SPLATS.with(|splats| std::mem::take(&mut *splats.borrow_mut()))
+    }
+}
+
+fn read_u32<R: Read>(reader: &mut R) -> io::Result<u32> {
+    let mut bytes = [0; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+fn read_u64<R: Read>(reader: &mut R) -> io::Result<u64> {
+    let mut bytes = [0; 8];
+    reader.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+// A tag for the kind of filter followed by its parameters, unused ones zero.
+fn write_filter<W: Write>(writer: &mut W, filter: &Filter<f64>) -> io::Result<()> {
+    let (tag, parameters) = match *filter {
+        Filter::Box { radius } => (0u32, [radius, 0.0, 0.0]),
+        Filter::Tent { radius } => (1, [radius, 0.0, 0.0]),
+        Filter::Gaussian { radius, sigma } => (2, [radius, sigma, 0.0]),
+        Filter::Mitchell { radius, b, c } => (3, [radius, b, c]),
+        Filter::Lanczos { radius } => (4, [radius, 0.0, 0.0]),
+    };
+    writer.write_all(&tag.to_le_bytes())?;
+    for value in &parameters {
+        writer.write_all(&value.to_le_bytes())?;
+    }
+    Ok(())
+}
+
+fn read_filter<R: Read>(reader: &mut R) -> io::Result<Filter<f64>> {
+    let tag = read_u32(reader)?;
+    let radius = read_f64(reader)?;
+    let (a, b) = (read_f64(reader)?, read_f64(reader)?);
+    Ok(match tag {
+        0 => Filter::Box { radius },
+        1 => Filter::Tent { radius },
+        2 => Filter::Gaussian { radius, sigma: a },
+        3 => Filter::Mitchell { radius, b: a, c: b },
+        4 => Filter::Lanczos { radius },
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "unknown filter in checkpoint",
+            ))
+        }
+    })
+}
+
+fn read_f64<R: Read>(reader: &mut R) -> io::Result<f64> {
+    let mut bytes = [0; 8];
+    reader.read_exact(&mut bytes)?;
+    Ok(f64::from_le_bytes(bytes))
+}
+
+// Samples come in at the precision of the renderer, but sums over many of
+// them stay in `f64`.
+fn widen(color: &Color<Real>) -> Color<f64> {
+    Color([color[0].as_(), color[1].as_(), color[2].as_()].into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checkpoints_round_trip_their_settings() {
+        let mut film = Film::new(3, 2);
+        film.samples = 8;
+        film.pixels[4] = Color([0.25, 0.5, 2.0].into());
+        film.weights[4] = 1.5;
+        film.splats().add(1.5, 1.5, &Color([1.0, 0.0, 3.0].into()));
+        film.add_splats(&SplatBuffer::take());
+        film.filter = Filter::Mitchell {
+            radius: 2.0,
+            b: 1.0 / 3.0,
+            c: 1.0 / 3.0,
+        };
+        let settings = SampleSettings {
+            seed: 42,
+            sampler: String::from("sobol"),
+            samples_per_pixel: 64,
+            pass_samples: 16,
+            filter: film.filter,
+        };
+        let path = std::env::temp_dir().join(format!("film-{}.checkpoint", std::process::id()));
+        film.save_checkpoint(&path, &settings).unwrap();
+        let (loaded, recorded) = Film::load_checkpoint(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(recorded, settings);
+        assert_eq!((loaded.width, loaded.height, loaded.samples), (3, 2, 8));
+        assert_eq!(loaded.filter, film.filter);
+        for (x, y) in [(0, 0), (1, 1)] {
+            let channels = |film: &Film| film.pixel(x, y).iter().cloned().collect::<Vec<_>>();
+            assert_eq!(channels(&loaded), channels(&film));
+        }
+    }
+
+    #[test]
+    fn tiles_carry_their_splats_into_the_film() {
+        let mut film = Film::new(4, 4);
+        film.samples = 2;
+        let tile = Tile {
+            index: 0,
+            x0: 0,
+            y0: 0,
+            x1: 2,
+            y1: 2,
+        };
+        let mut film_tile = film.film_tile(&tile);
+        // Splats may land outside the tile that traced them
+        film.splats().add(3.5, 0.5, &Color([2.0, 4.0, 6.0].into()));
+        film.splats().add(4.5, 0.5, &Color([1.0, 1.0, 1.0].into()));
+        film_tile.collect_splats();
+        assert!(SplatBuffer::take().is_empty());
+        assert_eq!(film.pixel(3, 0).iter().sum::<f64>(), 0.0);
+
+        film.merge_tile(&film_tile);
+        let channels: Vec<f64> = film.pixel(3, 0).iter().cloned().collect();
+        assert_eq!(channels, [1.0, 2.0, 3.0]);
+    }
+
+    #[test]
+    fn samples_stay_in_their_viewport() {
+        // Two 2x2 eyes side by side
+        let mut film = Film::new(4, 2);
+        film.filter = Filter::Box { radius: 1.5 };
+        film.viewport = (2, 2);
+        let tile = Tile {
+            index: 0,
+            x0: 0,
+            y0: 0,
+            x1: 4,
+            y1: 2,
+        };
+        let mut film_tile = film.film_tile(&tile);
+        film_tile.add_sample(1.9, 1.0, &Color([1.0, 1.0, 1.0].into()));
+        film.merge_tile(&film_tile);
+        for y in 0..2 {
+            assert!(film.pixel(1, y)[0] > 0.0);
+            assert_eq!(film.pixel(2, y)[0], 0.0);
+        }
+    }
+}
@@ -0,0 +1,155 @@
+use std::f64::consts::PI;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Filter<T> {
+    Box { radius: T },
+    Tent { radius: T },
+    Gaussian { radius: T, sigma: T },
+    Mitchell { radius: T, b: T, c: T },
+    Lanczos { radius: T },
+}
+
+impl Default for Filter<f64> {
+    fn default() -> Self {
+        Filter::Box { radius: 0.5 }
+    }
+}
+
+impl Filter<f64> {
+    pub fn radius(&self) -> f64 {
+        match *self {
+            Filter::Box { radius }
+            | Filter::Tent { radius }
+            | Filter::Gaussian { radius, .. }
+            | Filter::Mitchell { radius, .. }
+            | Filter::Lanczos { radius } => radius,
+        }
+    }
+
+    pub fn with_radius(self, radius: f64) -> Self {
+        match self {
+            Filter::Box { .. } => Filter::Box { radius },
+            Filter::Tent { .. } => Filter::Tent { radius },
+            Filter::Gaussian { sigma, .. } => Filter::Gaussian { radius, sigma },
+            Filter::Mitchell { b, c, .. } => Filter::Mitchell { radius, b, c },
+            Filter::Lanczos { .. } => Filter::Lanczos { radius },
+        }
+    }
+
+    // `x` and `y` are offsets from the pixel center in pixels. All filters
+    // are separable; Mitchell and Lanczos have negative lobes.
+    pub fn evaluate(&self, x: f64, y: f64) -> f64 {
+        self.evaluate_1d(x) * self.evaluate_1d(y)
+    }
+
+    fn evaluate_1d(&self, x: f64) -> f64 {
+        let x = x.abs();
+        if x > self.radius() {
+            return 0.0;
+        }
+        match *self {
+            Filter::Box { .. } => 1.0,
+            Filter::Tent { radius } => radius - x,
+            Filter::Gaussian { radius, sigma } => {
+                let gaussian = |x: f64| (-x * x / (2.0 * sigma * sigma)).exp();
+                (gaussian(x) - gaussian(radius)).max(0.0)
+            }
+            Filter::Mitchell { radius, b, c } => {
+                let x = 2.0 * x / radius;
+                if x > 1.0 {
+                    ((-b - 6.0 * c) * x.powi(3)
+                        + (6.0 * b + 30.0 * c) * x.powi(2)
+                        + (-12.0 * b - 48.0 * c) * x
+                        + (8.0 * b + 24.0 * c))
+                        / 6.0
+                } else {
+                    ((12.0 - 9.0 * b - 6.0 * c) * x.powi(3)
+                        + (-18.0 + 12.0 * b + 6.0 * c) * x.powi(2)
+                        + (6.0 - 2.0 * b))
+                        / 6.0
+                }
+            }
+            Filter::Lanczos { radius } => sinc(x) * sinc(x / radius),
+        }
+    }
+}
+
+fn sinc(x: f64) -> f64 {
+    if x < 1e-5 {
+        1.0
+    } else {
+        (PI * x).sin() / (PI * x)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(a: f64, b: f64) {
+        assert!((a - b).abs() < 1e-9, "{} != {}", a, b);
+    }
+
+    #[test]
+    fn weights_vanish_past_the_radius() {
+        for filter in [
+            Filter::Box { radius: 0.5 },
+            Filter::Tent { radius: 1.5 },
+            Filter::Gaussian {
+                radius: 1.5,
+                sigma: 0.5,
+            },
+            Filter::Mitchell {
+                radius: 2.0,
+                b: 1.0 / 3.0,
+                c: 1.0 / 3.0,
+            },
+            Filter::Lanczos { radius: 3.0 },
+        ] {
+            let radius = filter.radius();
+            assert!(filter.evaluate(0.0, 0.0) > 0.0, "{:?}", filter);
+            assert_eq!(filter.evaluate(radius * 1.01, 0.0), 0.0);
+            assert_eq!(filter.evaluate(0.0, -radius * 1.01), 0.0);
+            // Separable and symmetric
+            assert_close(
+                filter.evaluate(0.3, -0.2),
+                filter.evaluate_1d(0.3) * filter.evaluate_1d(0.2),
+            );
+            assert_close(filter.evaluate(-0.4, 0.1), filter.evaluate(0.1, 0.4));
+        }
+    }
+
+    #[test]
+    fn weights_match_their_kernels() {
+        assert_close(Filter::Box { radius: 0.5 }.evaluate(0.49, -0.3), 1.0);
+        let tent = Filter::Tent { radius: 2.0 };
+        assert_close(tent.evaluate(0.5, 0.0), 1.5 * 2.0);
+        assert_close(tent.evaluate(2.0, 0.0), 0.0);
+        // Shifted down so the Gaussian meets zero at its radius
+        let gaussian = Filter::Gaussian {
+            radius: 1.0,
+            sigma: 0.5,
+        };
+        assert_close(gaussian.evaluate_1d(0.0), 1.0 - (-2.0f64).exp());
+        assert_close(gaussian.evaluate_1d(1.0), 0.0);
+        // B = C = 1/3 is Mitchell and Netravali's recommended kernel,
+        // continuous across its two pieces at half the radius
+        let mitchell = Filter::Mitchell {
+            radius: 2.0,
+            b: 1.0 / 3.0,
+            c: 1.0 / 3.0,
+        };
+        assert_close(mitchell.evaluate_1d(0.0), 16.0 / 18.0);
+        assert_close(mitchell.evaluate_1d(1.0), 1.0 / 18.0);
+        assert_close(
+            mitchell.evaluate_1d(1.0 - 1e-12),
+            mitchell.evaluate_1d(1.0 + 1e-12),
+        );
+        assert!(mitchell.evaluate_1d(1.5) < 0.0);
+        // Lanczos crosses zero at every whole pixel
+        let lanczos = Filter::Lanczos { radius: 3.0 };
+        assert_close(lanczos.evaluate_1d(0.0), 1.0);
+        assert_close(lanczos.evaluate_1d(1.0), 0.0);
+        assert_close(lanczos.evaluate_1d(2.0), 0.0);
+    }
+}
@@ -1,15 +1,38 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::base::aabb::Aabb;
+use crate::base::matrix::{Mat3, Mat4};
+use crate::base::normal3::Normal3;
 use crate::base::point3::Point3;
+use crate::base::quaternion::Quaternion;
+use crate::base::simd::{Vec3x4, F4};
 use crate::base::vec3::{Length, Vec3, Vec3Operations};
+use crate::base::Real;
 use crate::materials::Materials;
-use crate::ray::Ray;
+use crate::mesh::Mesh;
+use crate::ray::{gamma, offset_origin, Ray, RayPacket};
+use crate::stats::{self, Counter};
 
 #[derive(Debug, Clone)]
 pub struct HitRecord<T> {
     pub point: Point3<T>,
-    pub normal: Vec3<T>,
+    // Bound on how far rounding may have moved `point` along each axis
+    pub error: Vec3<T>,
+    pub normal: Normal3<T>,
+    // Normal of the surface itself, on the same side as `normal`
+    pub geometric_normal: Normal3<T>,
     pub material: Materials<T>,
     pub t: T,
     pub front_face: bool,
+    pub primitive: Primitive,
+}
+
+// What a ray hit: the shape, whose id is copied along with it when the world
+// is cloned into a BVH, and the triangle for meshes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Primitive {
+    pub shape: u64,
+    pub triangle: u32,
 }
 
 #[derive(Debug, Clone)]
@@ -17,12 +40,15 @@ pub struct Sphere<T> {
     pub center: Point3<T>,
     pub radius: T,
     pub material: Materials<T>,
+    pub(crate) id: u64,
 }
 
 #[derive(Debug, Clone)]
 pub enum Hittable<T> {
     Sphere(Sphere<T>),
     HittableVec(HittableVec<T>),
+    Transformed(Transformed<T>),
+    Mesh(Mesh<T>),
 }
 
 #[derive(Debug, Default, Clone)]
@@ -30,19 +56,37 @@ pub struct HittableVec<T> {
     pub objects: Vec<Hittable<T>>,
 }
 
+// An affine map and its inverse. Rays reach object space with their
+// directions transformed but not renormalised, so distances along them stay
+// the same in both spaces; normals come back through the inverse transpose.
+#[derive(Debug, Clone)]
+pub struct Transform<T> {
+    matrix: Mat4<T>,
+    inverse: Mat4<T>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Transformed<T> {
+    pub object: Box<Hittable<T>>,
+    pub transform: Transform<T>,
+}
+
 pub trait Hit<T> {
     fn hit(self, ray: &Ray<T>, t_min: T, t_max: T) -> Option<HitRecord<T>>;
+    fn bounding_box(self) -> Option<Aabb<T>>;
 }
 
-impl HitRecord<f64> {
-    fn new(
-        point: Point3<f64>,
-        t: f64,
-        ray: &Ray<f64>,
-        outward_normal: Vec3<f64>,
-        material: &Materials<f64>,
+impl HitRecord<Real> {
+    pub(crate) fn new(
+        point: Point3<Real>,
+        error: Vec3<Real>,
+        t: Real,
+        ray: &Ray<Real>,
+        outward_normal: Normal3<Real>,
+        material: &Materials<Real>,
+        primitive: Primitive,
     ) -> Self {
-        let front_face = ray.direction.dot(&outward_normal) < 0.0;
+        let front_face = outward_normal.dot_vector(&ray.direction) < 0.0;
         let normal = if front_face {
             outward_normal
         } else {
@@ -51,69 +95,288 @@ impl HitRecord<f64> {
 
         Self {
             point,
+            error,
+            geometric_normal: normal.clone(),
             normal,
             material: material.clone(),
             t,
             front_face,
+            primitive,
+        }
+    }
+
+    pub fn spawn_ray(&self, direction: Vec3<Real>) -> Ray<Real> {
+        Ray {
+            origin: offset_origin(&self.point, &self.error, &self.geometric_normal, &direction),
+            direction,
+        }
+    }
+}
+
+impl Sphere<Real> {
+    pub fn new(center: Point3<Real>, radius: Real, material: Materials<Real>) -> Self {
+        Self {
+            center,
+            radius,
+            material,
+            id: shape_id(),
         }
     }
+
+    pub(crate) fn record(&self, ray: &Ray<Real>, t: Real) -> HitRecord<Real> {
+        let outward_normal = ray.at(t).vec_from(&self.center) / self.radius;
+        let (point, error) = self.surface_point(&outward_normal);
+        let primitive = Primitive {
+            shape: self.id,
+            triangle: 0,
+        };
+        HitRecord::new(
+            point,
+            error,
+            t,
+            ray,
+            outward_normal.into(),
+            &self.material,
+            primitive,
+        )
+    }
+
+    // Point of the sphere in `direction` from its center, reprojected onto
+    // the surface, with a bound on its rounding error.
+    pub(crate) fn surface_point(&self, direction: &Vec3<Real>) -> (Point3<Real>, Vec3<Real>) {
+        let offset = direction * (self.radius / direction.length());
+        let point = &self.center + &offset;
+        let error =
+            [0, 1, 2].map(|axis| gamma(5) * offset[axis].abs() + gamma(1) * point[axis].abs());
+        (point, Vec3(error.into()))
+    }
+
+    // Distance to the sphere along each ray of `packet`, or infinity for the
+    // rays that miss it within their range.
+    pub(crate) fn hit_packet(&self, packet: &RayPacket, t_min: F4, t_max: F4) -> F4 {
+        let oc = &packet.origin - &Vec3x4::splat(&self.center.0);
+        let a = packet.direction.dot(&packet.direction);
+        let half_b = oc.dot(&packet.direction);
+        let distance = oc.dot(&oc).sqrt();
+        let radius = F4::splat(self.radius);
+        let c = (distance - radius) * (distance + radius);
+        let discriminant = half_b * half_b - a * c;
+        let zero = F4::splat(0.0);
+        let root = discriminant.max(zero).sqrt();
+        let q = -(half_b + root.select(half_b.ge(zero), -root));
+        let near = (q / a).min(c / q);
+        let far = (q / a).max(c / q);
+        // Same bound on the error of the roots as `hit`
+        let a_error = F4::splat(gamma(3)) * a;
+        let half_b_error = F4::splat(gamma(5)) * oc.abs().dot(&packet.direction.abs());
+        let c_error = (distance + radius)
+            * (F4::splat(2.0 * gamma(4)) * distance
+                + F4::splat(gamma(3)) * (distance - radius).abs());
+        let t_error = |t: F4| {
+            (a_error * t * t + F4::splat(2.0) * half_b_error * t.abs() + c_error)
+                / (F4::splat(2.0) * root)
+                + F4::splat(gamma(7)) * t.abs()
+        };
+        let within = |t: F4| (t - t_error(t)).gt(t_min) & (t + t_error(t)).le(t_max);
+        let miss = F4::splat(Real::INFINITY);
+        let t = near.select(within(near), far.select(within(far), miss));
+        t.select(discriminant.gt(F4::splat(0.0)), miss)
+    }
 }
 
-impl Hit<f64> for &Sphere<f64> {
-    fn hit(self, ray: &Ray<f64>, t_min: f64, t_max: f64) -> Option<HitRecord<f64>> {
+impl Hit<Real> for &Sphere<Real> {
+    fn hit(self, ray: &Ray<Real>, t_min: Real, t_max: Real) -> Option<HitRecord<Real>> {
         let oc = ray.origin.vec_from(&self.center);
         let a = ray.direction.length_squared();
         let half_b = oc.dot(&ray.direction);
-        let c = oc.length_squared() - self.radius.powi(2);
+        // As a product, c keeps its sign for origins just off the surface
+        let distance = oc.length();
+        let c = (distance - self.radius) * (distance + self.radius);
         // let discriminant = half_b.powi(2) - a * c;
         let discriminant = a.mul_add(-c, half_b.powi(2));
         if discriminant > 0.0 {
             let root = discriminant.sqrt();
-            let mut temp = (-half_b - root) / a;
-            if temp < t_min || temp > t_max {
-                temp = (-half_b + root) / a;
+            // The root nearest zero comes from c / q rather than a
+            // difference of nearly equal terms, so a ray leaving the surface
+            // does not find it again
+            let q = -(half_b + root.copysign(half_b));
+            let (near, far) = if q / a < c / q {
+                (q / a, c / q)
+            } else {
+                (c / q, q / a)
+            };
+            // Roots that rounding alone could have moved across either end
+            // of the range are dropped. To first order, a root moves by the
+            // error of the quadratic there over its slope, 2 `root`, so near
+            // tangent rays get wide bounds.
+            let a_error = gamma(3) * a;
+            let half_b_error = gamma(5)
+                * (0..3)
+                    .map(|axis| (oc[axis] * ray.direction[axis]).abs())
+                    .sum::<Real>();
+            let c_error = (distance + self.radius)
+                * (2.0 * gamma(4) * distance + gamma(3) * (distance - self.radius).abs());
+            let outside = |t: Real| {
+                let error = (a_error * t * t + 2.0 * half_b_error * t.abs() + c_error)
+                    / (2.0 * root)
+                    + gamma(7) * t.abs();
+                t - error <= t_min || t + error > t_max
+            };
+            let mut temp = near;
+            if outside(temp) {
+                temp = far;
 
-                if temp < t_min || temp > t_max {
+                if outside(temp) {
                     return None;
                 }
             };
 
-            let hit_point = ray.at(temp);
-            let outward_normal = hit_point.vec_from(&self.center) / self.radius;
-            return Some(HitRecord::new(
-                hit_point,
-                temp,
-                ray,
-                outward_normal,
-                &self.material,
-            ));
+            return Some(self.record(ray, temp));
         }
         None
     }
+
+    fn bounding_box(self) -> Option<Aabb<Real>> {
+        let radius = Vec3([self.radius, self.radius, self.radius].into());
+        Some(Aabb::new(&self.center - &radius, &self.center + &radius))
+    }
 }
 
-impl Hit<f64> for &Hittable<f64> {
-    fn hit(self, ray: &Ray<f64>, t_min: f64, t_max: f64) -> Option<HitRecord<f64>> {
+impl Hit<Real> for &Hittable<Real> {
+    fn hit(self, ray: &Ray<Real>, t_min: Real, t_max: Real) -> Option<HitRecord<Real>> {
         match self {
             Hittable::Sphere(sphere) => sphere.hit(ray, t_min, t_max),
             Hittable::HittableVec(vec) => vec.hit(ray, t_min, t_max),
+            Hittable::Transformed(transformed) => transformed.hit(ray, t_min, t_max),
+            Hittable::Mesh(mesh) => mesh.hit(ray, t_min, t_max),
+        }
+    }
+
+    fn bounding_box(self) -> Option<Aabb<Real>> {
+        match self {
+            Hittable::Sphere(sphere) => sphere.bounding_box(),
+            Hittable::HittableVec(vec) => vec.bounding_box(),
+            Hittable::Transformed(transformed) => transformed.bounding_box(),
+            Hittable::Mesh(mesh) => mesh.bounding_box(),
         }
     }
 }
 
-impl Hit<f64> for &HittableVec<f64> {
-    fn hit(self, ray: &Ray<f64>, t_min: f64, t_max: f64) -> Option<HitRecord<f64>> {
+impl Hit<Real> for &HittableVec<Real> {
+    fn hit(self, ray: &Ray<Real>, t_min: Real, t_max: Real) -> Option<HitRecord<Real>> {
         let mut temp_rec = None;
         let mut closest_so_far = t_max;
 
+        // Primitive tests are tallied per node: a thread-local update per
+        // sphere costs about as much as the test itself.
+        let mut tests = 0;
         for object in self.objects.iter() {
+            if !matches!(object, Hittable::HittableVec(_)) {
+                tests += 1;
+            }
             if let Some(rec) = object.hit(ray, t_min, closest_so_far) {
                 closest_so_far = rec.t;
                 temp_rec = Some(rec);
             }
         }
+        stats::count(Counter::NodeVisits);
+        stats::add(Counter::IntersectionTests, tests);
         temp_rec
     }
+
+    fn bounding_box(self) -> Option<Aabb<Real>> {
+        self.objects
+            .iter()
+            .filter_map(|object| object.bounding_box())
+            .reduce(|a, b| a.union(&b))
+    }
+}
+
+impl Hit<Real> for &Transformed<Real> {
+    fn hit(self, ray: &Ray<Real>, t_min: Real, t_max: Real) -> Option<HitRecord<Real>> {
+        let local_ray = Ray {
+            origin: self.transform.inverse_point(&ray.origin),
+            direction: self.transform.inverse_vector(&ray.direction),
+        };
+        let mut rec = self.object.hit(&local_ray, t_min, t_max)?;
+        rec.error = self.transform.point_error(&rec.point, &rec.error);
+        rec.point = self.transform.point(&rec.point);
+        rec.normal = self.transform.normal(&rec.normal);
+        rec.geometric_normal = self.transform.normal(&rec.geometric_normal);
+        Some(rec)
+    }
+
+    fn bounding_box(self) -> Option<Aabb<Real>> {
+        let bounds = self.object.bounding_box()?;
+        Some(bounds.transformed(&self.transform.matrix))
+    }
+}
+
+impl Transform<Real> {
+    // `rotation` is a unit quaternion, applied after a uniform `scale` and
+    // before the translation; `Quaternion::from_euler` builds one from
+    // angles.
+    pub fn new(translation: Vec3<Real>, rotation: &Quaternion<Real>, scale: Real) -> Self {
+        let rotation = rotation.to_mat3();
+        let linear = &rotation * &Mat3::scale(&Vec3([scale, scale, scale].into()));
+        // The rotation inverts by transposing, so only the scale divides
+        let unscale = 1.0 / scale;
+        let inverse =
+            &Mat3::scale(&Vec3([unscale, unscale, unscale].into())) * &rotation.transpose();
+        let offset = -inverse.vector(&translation);
+        Self {
+            matrix: Mat4::affine(&linear, &translation),
+            inverse: Mat4::affine(&inverse, &offset),
+        }
+    }
+
+    // None when `matrix` has no inverse.
+    pub fn from_matrix(matrix: Mat4<Real>) -> Option<Self> {
+        Some(Self {
+            inverse: matrix.inverse()?,
+            matrix,
+        })
+    }
+
+    pub fn matrix(&self) -> &Mat4<Real> {
+        &self.matrix
+    }
+
+    pub fn point(&self, point: &Point3<Real>) -> Point3<Real> {
+        self.matrix.point(point)
+    }
+
+    pub fn vector(&self, vector: &Vec3<Real>) -> Vec3<Real> {
+        self.matrix.vector(vector)
+    }
+
+    pub fn normal(&self, normal: &Normal3<Real>) -> Normal3<Real> {
+        self.inverse.normal(normal).unit()
+    }
+
+    // Bound on the error of `point(point)` for a `point` that is already
+    // off by `error`.
+    fn point_error(&self, point: &Point3<Real>, error: &Vec3<Real>) -> Vec3<Real> {
+        let m = &self.matrix.0;
+        let bound = [0, 1, 2].map(|row| {
+            let mut spread = 0.0;
+            let mut magnitude = m[row][3].abs();
+            for column in 0..3 {
+                spread += m[row][column].abs() * error[column];
+                magnitude += m[row][column].abs() * point[column].abs();
+            }
+            (gamma(3) + 1.0) * spread + gamma(3) * magnitude
+        });
+        Vec3(bound.into())
+    }
+
+    pub fn inverse_point(&self, point: &Point3<Real>) -> Point3<Real> {
+        self.inverse.point(point)
+    }
+
+    pub fn inverse_vector(&self, vector: &Vec3<Real>) -> Vec3<Real> {
+        self.inverse.vector(vector)
+    }
 }
 
 impl<T> HittableVec<T> {
@@ -121,3 +384,10 @@ impl<T> HittableVec<T> {
         self.objects.push(value)
     }
 }
+
+// A fresh id for a sphere or mesh; `Lights` looks up the emitters a ray hits
+// by it.
+pub(crate) fn shape_id() -> u64 {
+    static NEXT: AtomicU64 = AtomicU64::new(0);
+    NEXT.fetch_add(1, Ordering::Relaxed)
+}
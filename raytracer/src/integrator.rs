@@ -0,0 +1,503 @@
+use crate::base::color::Color;
+use crate::base::consts::PI;
+use crate::base::frame::Frame;
+use crate::base::vec3::{Length, Vec3, Vec3Operations};
+use crate::base::Real;
+use crate::bdpt::BdptIntegrator;
+use crate::hittable::{Hit, HitRecord, HittableVec};
+use crate::lights::Lights;
+use crate::materials::{Materials, Scatter};
+use crate::photon::PhotonMapIntegrator;
+use crate::ray::{
+    offset_origin, path_trace, path_trace_from, ray_color, sky, Ray, RussianRoulette,
+};
+use crate::sampler::Sampler;
+use crate::stats::{self, Counter};
+
+pub trait Integrator {
+    fn radiance<'a, T, S: Sampler>(
+        &self,
+        ray: &Ray<Real>,
+        world: &'a T,
+        sampler: &mut S,
+    ) -> Color<Real>
+    where
+        &'a T: Hit<Real>;
+
+    // Radiance along `ray` when its closest hit is already known, as for
+    // camera rays traced together in a packet. Integrators that don't start
+    // from the closest hit trace the ray again.
+    fn radiance_from<'a, T, S: Sampler>(
+        &self,
+        ray: &Ray<Real>,
+        _hit: Option<HitRecord<Real>>,
+        world: &'a T,
+        sampler: &mut S,
+    ) -> Color<Real>
+    where
+        &'a T: Hit<Real>,
+    {
+        self.radiance(ray, world, sampler)
+    }
+
+    // Called before every pass of samples, with passes counted from the
+    // start of the frame, for integrators that precompute from the scene.
+    fn start_pass<'a, T, S>(&self, _pass: u32, _world: &'a T, _sampler: &S)
+    where
+        T: Sync,
+        &'a T: Hit<Real>,
+        S: Sampler + Clone + Send + Sync,
+    {
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct PathIntegrator {
+    pub max_depth: u16,
+    pub roulette: Option<RussianRoulette>,
+}
+
+// Fraction of the cosine-weighted hemisphere above the first hit that is
+// open for `distance`, from `samples` rays per hit.
+#[derive(Debug, Clone)]
+pub struct AmbientOcclusionIntegrator {
+    pub distance: Real,
+    pub samples: u32,
+}
+
+// Light arriving at the first diffuse surface straight from the sky or an
+// emitter, seen through any chain of mirrors and glass in front of it. The
+// diffuse surface samples `Lights`, the sky and its BSDF once each and
+// combines them with the power heuristic.
+#[derive(Debug, Clone)]
+pub struct DirectLightingIntegrator {
+    pub max_depth: u16,
+    lights: Lights,
+}
+
+// Shading normals mapped from [-1, 1] to [0, 1].
+#[derive(Debug, Clone)]
+pub struct NormalsIntegrator;
+
+#[derive(Debug, Clone)]
+pub enum Integrators {
+    Path(PathIntegrator),
+    // The recursive `ray_color`, kept to compare against `Path`
+    Recursive(PathIntegrator),
+    AmbientOcclusion(AmbientOcclusionIntegrator),
+    DirectLighting(DirectLightingIntegrator),
+    Normals(NormalsIntegrator),
+    Bidirectional(Box<BdptIntegrator>),
+    PhotonMap(PhotonMapIntegrator),
+}
+
+impl Integrator for PathIntegrator {
+    fn radiance<'a, T, S: Sampler>(
+        &self,
+        ray: &Ray<Real>,
+        world: &'a T,
+        sampler: &mut S,
+    ) -> Color<Real>
+    where
+        &'a T: Hit<Real>,
+    {
+        path_trace(ray, world, self.max_depth, self.roulette, sampler)
+    }
+
+    fn radiance_from<'a, T, S: Sampler>(
+        &self,
+        ray: &Ray<Real>,
+        hit: Option<HitRecord<Real>>,
+        world: &'a T,
+        sampler: &mut S,
+    ) -> Color<Real>
+    where
+        &'a T: Hit<Real>,
+    {
+        path_trace_from(ray, hit, world, self.max_depth, self.roulette, sampler)
+    }
+}
+
+impl Integrator for AmbientOcclusionIntegrator {
+    fn radiance<'a, T, S: Sampler>(
+        &self,
+        ray: &Ray<Real>,
+        world: &'a T,
+        sampler: &mut S,
+    ) -> Color<Real>
+    where
+        &'a T: Hit<Real>,
+    {
+        self.radiance_from(ray, world.hit(ray, 0.0, Real::INFINITY), world, sampler)
+    }
+
+    fn radiance_from<'a, T, S: Sampler>(
+        &self,
+        _ray: &Ray<Real>,
+        hit: Option<HitRecord<Real>>,
+        world: &'a T,
+        sampler: &mut S,
+    ) -> Color<Real>
+    where
+        &'a T: Hit<Real>,
+    {
+        let rec = match hit {
+            Some(rec) => rec,
+            None => return Color([1.0, 1.0, 1.0].into()),
+        };
+        let frame = Frame::from_normal(&rec.normal);
+        let samples = self.samples.max(1);
+        let open = (0..samples)
+            .filter(|_| {
+                let shadow_ray = rec.spawn_ray(frame.to_world(&Vec3::random_cosine(sampler)));
+                stats::count(Counter::ShadowRays);
+                world.hit(&shadow_ray, 0.0, self.distance).is_none()
+            })
+            .count();
+        let open = open as Real / samples as Real;
+        Color([open, open, open].into())
+    }
+}
+
+impl Integrator for DirectLightingIntegrator {
+    fn radiance<'a, T, S: Sampler>(
+        &self,
+        ray: &Ray<Real>,
+        world: &'a T,
+        sampler: &mut S,
+    ) -> Color<Real>
+    where
+        &'a T: Hit<Real>,
+    {
+        self.radiance_from(ray, world.hit(ray, 0.0, Real::INFINITY), world, sampler)
+    }
+
+    fn radiance_from<'a, T, S: Sampler>(
+        &self,
+        ray: &Ray<Real>,
+        hit: Option<HitRecord<Real>>,
+        world: &'a T,
+        sampler: &mut S,
+    ) -> Color<Real>
+    where
+        &'a T: Hit<Real>,
+    {
+        let mut throughput = Color([1.0, 1.0, 1.0].into());
+        let mut ray = ray.clone();
+        let mut hit = hit;
+
+        for bounce in 0..self.max_depth {
+            if bounce > 0 {
+                hit = world.hit(&ray, 0.0, Real::INFINITY);
+            }
+            let rec = match hit.take() {
+                Some(rec) => rec,
+                None => return &throughput * &sky(&ray),
+            };
+            if let Materials::Lambertian(lambertian) = &rec.material {
+                let irradiance = self.irradiance(&rec, world, sampler);
+                return &(&throughput * &(&lambertian.albedo / PI)) * &irradiance;
+            }
+            let emitted = &throughput * &rec.material.emitted(&rec);
+            let (scattered, attenuation) = match rec.material.scatter(&ray, &rec, sampler) {
+                Some(scatter) => scatter,
+                None => return emitted,
+            };
+            throughput = &throughput * &attenuation;
+            stats::count(Counter::BounceRays);
+            ray = scattered;
+        }
+        Color::default()
+    }
+}
+
+impl DirectLightingIntegrator {
+    pub fn new(max_depth: u16, world: &HittableVec<Real>) -> Self {
+        Self {
+            max_depth,
+            lights: Lights::new(world),
+        }
+    }
+
+    // Irradiance at `rec` from the emitters and the sky, estimated from one
+    // sample of each and one cosine-weighted direction.
+    fn irradiance<'a, T, S: Sampler>(
+        &self,
+        rec: &HitRecord<Real>,
+        world: &'a T,
+        sampler: &mut S,
+    ) -> Color<Real>
+    where
+        &'a T: Hit<Real>,
+    {
+        let cosine = |direction: &Vec3<Real>| rec.normal.dot_vector(&direction.unit());
+        let mut irradiance = Color::default();
+
+        if !self.lights.is_empty() {
+            let light = self.lights.sample(sampler);
+            let direction = light.point.vec_from(&rec.point);
+            let cos_light = light.normal.dot_vector(&-&direction.unit());
+            let cos_surface = cosine(&direction);
+            if cos_light > 0.0 && cos_surface > 0.0 {
+                let light_pdf = light.pdf * direction.length_squared() / cos_light;
+                // Both ends are offset off their surfaces, as in `BdptIntegrator`
+                let origin = rec.spawn_ray(direction.clone()).origin;
+                let target = offset_origin(&light.point, &light.error, &light.normal, &-&direction);
+                let shadow_ray = Ray {
+                    direction: target.vec_from(&origin),
+                    origin,
+                };
+                stats::count(Counter::ShadowRays);
+                if world.hit(&shadow_ray, 0.0, 1.0).is_none() {
+                    let weight = power_heuristic(light_pdf, cos_surface / PI);
+                    irradiance += &(light.emit * (cos_surface * weight / light_pdf));
+                }
+            }
+        }
+
+        let shadow_ray = rec.spawn_ray(Vec3::random_unit(sampler));
+        let cos_surface = cosine(&shadow_ray.direction);
+        if cos_surface > 0.0 {
+            stats::count(Counter::ShadowRays);
+            if world.hit(&shadow_ray, 0.0, Real::INFINITY).is_none() {
+                let weight = power_heuristic(SKY_PDF, cos_surface / PI);
+                irradiance += &(sky(&shadow_ray) * (cos_surface * weight / SKY_PDF));
+            }
+        }
+
+        let frame = Frame::from_normal(&rec.normal);
+        let ray = rec.spawn_ray(frame.to_world(&Vec3::random_cosine(sampler)));
+        let bsdf_pdf = cosine(&ray.direction) / PI;
+        if bsdf_pdf > 0.0 {
+            stats::count(Counter::BounceRays);
+            // The cosine over `bsdf_pdf` leaves a factor of pi
+            irradiance += &match world.hit(&ray, 0.0, Real::INFINITY) {
+                Some(light) => {
+                    let direction = light.point.vec_from(&rec.point);
+                    let weight = match self.lights.find(&light) {
+                        // Nothing else could have found this emitter
+                        None => 1.0,
+                        Some(index) => {
+                            let area_pdf = self.lights.pdf(index);
+                            let cos_light = light.geometric_normal.dot_vector(&direction.unit());
+                            let light_pdf = area_pdf * direction.length_squared() / cos_light.abs();
+                            power_heuristic(bsdf_pdf, light_pdf)
+                        }
+                    };
+                    light.material.emitted(&light) * (PI * weight)
+                }
+                None => sky(&ray) * (PI * power_heuristic(bsdf_pdf, SKY_PDF)),
+            };
+        }
+        irradiance
+    }
+}
+
+impl Integrator for NormalsIntegrator {
+    fn radiance<'a, T, S: Sampler>(
+        &self,
+        ray: &Ray<Real>,
+        world: &'a T,
+        sampler: &mut S,
+    ) -> Color<Real>
+    where
+        &'a T: Hit<Real>,
+    {
+        self.radiance_from(ray, world.hit(ray, 0.0, Real::INFINITY), world, sampler)
+    }
+
+    fn radiance_from<'a, T, S: Sampler>(
+        &self,
+        _ray: &Ray<Real>,
+        hit: Option<HitRecord<Real>>,
+        _world: &'a T,
+        _sampler: &mut S,
+    ) -> Color<Real>
+    where
+        &'a T: Hit<Real>,
+    {
+        match hit {
+            Some(rec) => {
+                let normal = rec.normal.unit();
+                Color([normal[0] + 1.0, normal[1] + 1.0, normal[2] + 1.0].into()) * 0.5
+            }
+            None => Color::default(),
+        }
+    }
+}
+
+impl Integrator for Integrators {
+    fn radiance<'a, T, S: Sampler>(
+        &self,
+        ray: &Ray<Real>,
+        world: &'a T,
+        sampler: &mut S,
+    ) -> Color<Real>
+    where
+        &'a T: Hit<Real>,
+    {
+        match self {
+            Integrators::Path(integrator) => integrator.radiance(ray, world, sampler),
+            Integrators::Recursive(integrator) => ray_color(
+                ray,
+                world,
+                integrator.max_depth,
+                integrator.roulette,
+                sampler,
+            ),
+            Integrators::AmbientOcclusion(integrator) => integrator.radiance(ray, world, sampler),
+            Integrators::DirectLighting(integrator) => integrator.radiance(ray, world, sampler),
+            Integrators::Normals(integrator) => integrator.radiance(ray, world, sampler),
+            Integrators::Bidirectional(integrator) => integrator.radiance(ray, world, sampler),
+            Integrators::PhotonMap(integrator) => integrator.radiance(ray, world, sampler),
+        }
+    }
+
+    fn radiance_from<'a, T, S: Sampler>(
+        &self,
+        ray: &Ray<Real>,
+        hit: Option<HitRecord<Real>>,
+        world: &'a T,
+        sampler: &mut S,
+    ) -> Color<Real>
+    where
+        &'a T: Hit<Real>,
+    {
+        match self {
+            Integrators::Path(integrator) => integrator.radiance_from(ray, hit, world, sampler),
+            Integrators::AmbientOcclusion(integrator) => {
+                integrator.radiance_from(ray, hit, world, sampler)
+            }
+            Integrators::DirectLighting(integrator) => {
+                integrator.radiance_from(ray, hit, world, sampler)
+            }
+            Integrators::Normals(integrator) => integrator.radiance_from(ray, hit, world, sampler),
+            _ => self.radiance(ray, world, sampler),
+        }
+    }
+
+    fn start_pass<'a, T, S>(&self, pass: u32, world: &'a T, sampler: &S)
+    where
+        T: Sync,
+        &'a T: Hit<Real>,
+        S: Sampler + Clone + Send + Sync,
+    {
+        if let Integrators::PhotonMap(integrator) = self {
+            integrator.start_pass(pass, world, sampler);
+        }
+    }
+}
+
+// Density of the uniform directions the sky is sampled with
+const SKY_PDF: Real = 1.0 / (4.0 * PI);
+
+fn power_heuristic(pdf: Real, other_pdf: Real) -> Real {
+    pdf.powi(2) / (pdf.powi(2) + other_pdf.powi(2))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::base::point3::Point3;
+    use crate::hittable::{Hittable, Sphere};
+    use crate::materials::{DiffuseLight, Lambertian};
+    use crate::mesh::{Mesh, MeshTriangle};
+    use crate::sampler::IndependentSampler;
+
+    // A square at y = 0, wide enough to fill the lower half of every view
+    // from near its center.
+    fn ground() -> Hittable<Real> {
+        let positions = [[-1e3, -1e3], [1e3, -1e3], [1e3, 1e3], [-1e3, 1e3]]
+            .iter()
+            .map(|&[x, z]| Point3([x, 0.0, z].into()))
+            .collect();
+        let triangles = [[0, 1, 2], [0, 2, 3]]
+            .iter()
+            .map(|&positions| MeshTriangle {
+                positions,
+                ..MeshTriangle::default()
+            })
+            .collect();
+        let material = Materials::Lambertian(Lambertian {
+            albedo: Color([0.5, 0.5, 0.5].into()),
+        });
+        Hittable::Mesh(Mesh::new(
+            positions,
+            Vec::new(),
+            Vec::new(),
+            Vec::new(),
+            triangles,
+            material,
+        ))
+    }
+
+    // Irradiance on an upward facing surface from the sky within `cos_max`
+    // of straight up. The sky blends from white to blue with the height of
+    // the direction, so it integrates the cosine and its square.
+    fn sky_irradiance(cos_max: Real) -> Color<Real> {
+        let cosine = PI * (1.0 - cos_max.powi(2));
+        let cosine_squared = 2.0 * PI / 3.0 * (1.0 - cos_max.powi(3));
+        let blue = 0.5 * (cosine + cosine_squared);
+        Color([1.0, 1.0, 1.0].into()) * (cosine - blue) + Color([0.5, 0.7, 1.0].into()) * blue
+    }
+
+    // Mean radiance seen straight down onto the origin.
+    fn mean_radiance(world: &HittableVec<Real>) -> Color<Real> {
+        let integrator = DirectLightingIntegrator::new(4, world);
+        let ray = Ray {
+            origin: Point3([0.0, 0.5, 0.0].into()),
+            direction: Vec3([0.0, -1.0, 0.0].into()),
+        };
+        let mut sampler = IndependentSampler::new(3);
+        let samples = 1 << 14;
+        let mut sum = Color::default();
+        for index in 0..samples {
+            sampler.start_pixel_sample(0, 0, index);
+            sum += &integrator.radiance(&ray, world, &mut sampler);
+        }
+        sum / samples as Real
+    }
+
+    fn assert_close(actual: &Color<Real>, expected: &Color<Real>) {
+        for channel in 0..3 {
+            assert!(
+                (actual[channel] - expected[channel]).abs() < 0.01 * expected[channel],
+                "channel {}: {} instead of {}",
+                channel,
+                actual[channel],
+                expected[channel]
+            );
+        }
+    }
+
+    #[test]
+    fn lights_a_plane_under_the_sky() {
+        let world = HittableVec {
+            objects: vec![ground()],
+        };
+        let expected = sky_irradiance(0.0) * (0.5 / PI);
+        assert_close(&mean_radiance(&world), &expected);
+    }
+
+    #[test]
+    fn lights_a_plane_under_a_sphere_light() {
+        let (radius, distance, emit) = (1.0, 4.0, 3.0);
+        let light = Sphere::new(
+            Point3([0.0, distance, 0.0].into()),
+            radius,
+            Materials::DiffuseLight(DiffuseLight {
+                emit: Color([emit, emit, emit].into()),
+            }),
+        );
+        let world = HittableVec {
+            objects: vec![ground(), Hittable::Sphere(light)],
+        };
+        // The sphere covers a cone of the sky, and from inside that cone it
+        // sends pi sin^2 of its half angle times its radiance
+        let sin_squared: Real = (radius / distance).powi(2);
+        let cone = sky_irradiance((1.0 - sin_squared).sqrt());
+        let sphere = emit * PI * sin_squared;
+        let irradiance = sky_irradiance(0.0) - cone + Color([sphere, sphere, sphere].into());
+        assert_close(&mean_radiance(&world), &(irradiance * (0.5 / PI)));
+    }
+}
@@ -1,14 +1,63 @@
+mod animation;
+mod bake;
 mod base;
+mod bdpt;
+mod bvh;
 mod camera;
+mod film;
+mod filter;
 mod hittable;
+mod integrator;
+mod lights;
 mod materials;
+mod mesh;
+mod mlt;
+mod photon;
+mod preview;
 mod ray;
+mod renderer;
+mod sampler;
+mod stats;
+mod stereo;
 mod utils;
 
-pub use crate::base::color::{Color, RGB};
+pub use crate::animation::{
+    CameraAnimation, Interpolate, Interpolation, ObjectAnimation, SceneAnimation, Track,
+};
+pub use crate::bake::{BakeMode, Baker};
+pub use crate::base::aabb::Aabb;
+pub use crate::base::color::{AsColor, Color, RGB};
+pub use crate::base::frame::Frame;
+pub use crate::base::matrix::{Mat3, Mat4};
+pub use crate::base::normal3::Normal3;
 pub use crate::base::point3::Point3;
+pub use crate::base::quaternion::Quaternion;
+pub use crate::base::simd::{Mask4, Vec3x4, F4};
 pub use crate::base::vec3::{Length, Vec3, Vec3Operations};
-pub use crate::camera::Camera;
-pub use crate::hittable::{Hittable, HittableVec, Sphere};
-pub use crate::materials::{Dielectric, Lambertian, Materials, Metal};
-pub use crate::ray::{ray_color, Ray};
+pub use crate::base::{consts, Real};
+pub use crate::bdpt::BdptIntegrator;
+pub use crate::bvh::Bvh;
+pub use crate::camera::{
+    Aperture, ApertureMask, Camera, Convergence, FisheyeMapping, PhysicalCamera, Projection,
+};
+pub use crate::film::{Film, FilmTile, SampleSettings, SplatBuffer};
+pub use crate::filter::Filter;
+pub use crate::hittable::{
+    Hit, HitRecord, Hittable, HittableVec, Primitive, Sphere, Transform, Transformed,
+};
+pub use crate::integrator::{
+    AmbientOcclusionIntegrator, DirectLightingIntegrator, Integrator, Integrators,
+    NormalsIntegrator, PathIntegrator,
+};
+pub use crate::materials::{Dielectric, DiffuseLight, Lambertian, Materials, Metal};
+pub use crate::mesh::{Mesh, MeshTriangle, SurfacePoint, UvSet};
+pub use crate::mlt::{Metropolis, MltSampler};
+pub use crate::photon::{Photon, PhotonMap, PhotonMapIntegrator};
+pub use crate::preview::PreviewServer;
+pub use crate::ray::{path_trace, path_trace_from, ray_color, Ray, RayPacket, RussianRoulette};
+pub use crate::renderer::{Renderer, Tile, TileOrder};
+pub use crate::sampler::{
+    HaltonSampler, IndependentSampler, Sampler, Samplers, SobolSampler, StratifiedSampler,
+};
+pub use crate::stats::RenderStats;
+pub use crate::stereo::{StereoLayout, StereoRig};
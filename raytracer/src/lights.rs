@@ -0,0 +1,250 @@
+use std::collections::HashMap;
+
+use crate::base::color::Color;
+use crate::base::consts::PI;
+use crate::base::normal3::Normal3;
+use crate::base::point3::Point3;
+use crate::base::vec3::{Length, Vec3, Vec3Operations};
+use crate::base::Real;
+use crate::hittable::{HitRecord, Hittable, HittableVec, Primitive, Sphere};
+use crate::materials::Materials;
+use crate::mesh::Mesh;
+use crate::sampler::Sampler;
+
+// The emitters integrators can sample directly: spheres and mesh triangles
+// with a `DiffuseLight` material outside any transform. Other emissive
+// surfaces are only found by rays that happen to hit them.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Lights {
+    emitters: Vec<Emitter>,
+    // Emissive meshes, which triangle emitters index
+    meshes: Vec<Mesh<Real>>,
+    // The emitter behind each primitive a ray can hit
+    index: HashMap<Primitive, usize>,
+}
+
+#[derive(Debug, Clone)]
+enum Emitter {
+    Sphere(Sphere<Real>),
+    Triangle {
+        mesh: usize,
+        triangle: usize,
+        area: Real,
+    },
+}
+
+pub(crate) struct LightSample {
+    // Index of the emitter, as `find` returns it
+    pub(crate) light: usize,
+    pub(crate) point: Point3<Real>,
+    pub(crate) error: Vec3<Real>,
+    pub(crate) normal: Normal3<Real>,
+    pub(crate) emit: Color<Real>,
+    pub(crate) pdf: Real,
+}
+
+impl Lights {
+    pub(crate) fn new(world: &HittableVec<Real>) -> Self {
+        let mut lights = Self::default();
+        lights.collect(world);
+        lights
+    }
+
+    fn collect(&mut self, world: &HittableVec<Real>) {
+        for object in &world.objects {
+            match object {
+                Hittable::Sphere(sphere) => {
+                    if let Materials::DiffuseLight(_) = sphere.material {
+                        let primitive = Primitive {
+                            shape: sphere.id,
+                            triangle: 0,
+                        };
+                        self.index.insert(primitive, self.emitters.len());
+                        self.emitters.push(Emitter::Sphere(sphere.clone()));
+                    }
+                }
+                Hittable::Mesh(mesh) => {
+                    if let Materials::DiffuseLight(_) = mesh.material {
+                        let index = self.meshes.len();
+                        for triangle in 0..mesh.triangles().len() {
+                            let area = triangle_area(mesh, triangle);
+                            // Triangles collapsed to a line cannot be sampled
+                            if area > 0.0 {
+                                let primitive = Primitive {
+                                    shape: mesh.id,
+                                    triangle: triangle as u32,
+                                };
+                                self.index.insert(primitive, self.emitters.len());
+                                self.emitters.push(Emitter::Triangle {
+                                    mesh: index,
+                                    triangle,
+                                    area,
+                                });
+                            }
+                        }
+                        self.meshes.push(mesh.clone());
+                    }
+                }
+                Hittable::HittableVec(vec) => self.collect(vec),
+                Hittable::Transformed(_) => {}
+            }
+        }
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.emitters.is_empty()
+    }
+
+    // The light `rec` lies on, if any. Hits inside a transform never are,
+    // just as the transformed shapes are never collected.
+    pub(crate) fn find(&self, rec: &HitRecord<Real>) -> Option<usize> {
+        self.index.get(&rec.primitive).copied()
+    }
+
+    // Area density of `sample` picking a point on `light`.
+    pub(crate) fn pdf(&self, light: usize) -> Real {
+        self.area_pdf(&self.emitters[light])
+    }
+
+    // Picks a light uniformly, then a point uniformly on its surface.
+    pub(crate) fn sample<S: Sampler>(&self, sampler: &mut S) -> LightSample {
+        let choice = sampler.next_1d();
+        let (u, v) = sampler.next_2d();
+        let index = ((choice * self.emitters.len() as Real) as usize).min(self.emitters.len() - 1);
+        let light = &self.emitters[index];
+
+        let (point, error, normal, material) = match light {
+            Emitter::Sphere(sphere) => {
+                let z = 1.0 - 2.0 * u;
+                let r = (1.0 - z * z).max(0.0).sqrt();
+                let phi = 2.0 * PI * v;
+                let normal = Vec3([r * phi.cos(), r * phi.sin(), z].into());
+                let (point, error) = sphere.surface_point(&normal);
+                (point, error, normal.into(), &sphere.material)
+            }
+            Emitter::Triangle { mesh, triangle, .. } => {
+                let mesh = &self.meshes[*mesh];
+                // Uniform over the triangle by folding the unit square
+                let root = u.sqrt();
+                let barycentric = [1.0 - root, v * root, (1.0 - v) * root];
+                let surface = mesh.surface_point(*triangle, barycentric);
+                (
+                    surface.point,
+                    surface.error,
+                    surface.geometric_normal,
+                    &mesh.material,
+                )
+            }
+        };
+        let emit = match material {
+            Materials::DiffuseLight(emitter) => emitter.emit.clone(),
+            _ => Color::default(),
+        };
+        LightSample {
+            light: index,
+            point,
+            error,
+            normal,
+            emit,
+            pdf: self.area_pdf(light),
+        }
+    }
+
+    fn area_pdf(&self, light: &Emitter) -> Real {
+        let area = match light {
+            Emitter::Sphere(sphere) => 4.0 * PI * sphere.radius.powi(2),
+            Emitter::Triangle { area, .. } => *area,
+        };
+        1.0 / (self.emitters.len() as Real * area)
+    }
+}
+
+fn triangle_area(mesh: &Mesh<Real>, triangle: usize) -> Real {
+    let corners = &mesh.triangles()[triangle].positions;
+    let p0 = &mesh.positions[corners[0]];
+    let e1 = mesh.positions[corners[1]].vec_from(p0);
+    let e2 = mesh.positions[corners[2]].vec_from(p0);
+    0.5 * e1.cross(&e2).length()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hittable::Hit;
+    use crate::materials::{DiffuseLight, Lambertian};
+    use crate::mesh::MeshTriangle;
+    use crate::ray::Ray;
+    use crate::sampler::IndependentSampler;
+
+    // A 2 by 1 rectangle at y = 3, emitting downwards.
+    fn panel() -> HittableVec<Real> {
+        let positions = [[0.0, 0.0], [2.0, 0.0], [2.0, 1.0], [0.0, 1.0]]
+            .iter()
+            .map(|&[x, z]| Point3([x, 3.0, z].into()))
+            .collect();
+        let triangles = [[0, 1, 2], [0, 2, 3]]
+            .iter()
+            .map(|&positions| MeshTriangle {
+                positions,
+                ..MeshTriangle::default()
+            })
+            .collect();
+        let material = Materials::DiffuseLight(DiffuseLight {
+            emit: Color([4.0, 4.0, 4.0].into()),
+        });
+        HittableVec {
+            objects: vec![Hittable::Mesh(Mesh::new(
+                positions,
+                Vec::new(),
+                Vec::new(),
+                Vec::new(),
+                triangles,
+                material,
+            ))],
+        }
+    }
+
+    #[test]
+    fn samples_emissive_meshes_by_area() {
+        let world = panel();
+        let lights = Lights::new(&world);
+        let mut sampler = IndependentSampler::new(7);
+        for index in 0..64 {
+            sampler.start_pixel_sample(0, 0, index);
+            let sample = lights.sample(&mut sampler);
+            assert!((sample.point[1] - 3.0).abs() < 1e-6);
+            assert!((0.0..=2.0).contains(&sample.point[0]));
+            assert!((0.0..=1.0).contains(&sample.point[2]));
+            assert!((sample.normal[1] + 1.0).abs() < 1e-6);
+            // Two triangles of area 1, each picked half the time
+            assert!((sample.pdf - 0.5).abs() < 1e-6);
+            assert!((lights.pdf(sample.light) - 0.5).abs() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn finds_emissive_meshes_where_rays_hit_them() {
+        let mut world = panel();
+        world.push(Hittable::Sphere(Sphere::new(
+            Point3([3.0, 1.0, 0.5].into()),
+            0.5,
+            Materials::Lambertian(Lambertian {
+                albedo: Color([0.5, 0.5, 0.5].into()),
+            }),
+        )));
+        let lights = Lights::new(&world);
+        let hit = |origin: [Real; 3], direction: [Real; 3]| {
+            let ray = Ray {
+                origin: Point3(origin.into()),
+                direction: Vec3(direction.into()),
+            };
+            (&world).hit(&ray, 0.0, Real::INFINITY).unwrap()
+        };
+        // One ray into each triangle of the panel, then one into the sphere
+        let first = lights.find(&hit([0.3, 0.0, 0.9], [0.5, 1.0, -0.1]));
+        let second = lights.find(&hit([0.2, 0.0, 0.8], [0.0, 1.0, 0.0]));
+        assert!(first.is_some() && second.is_some() && first != second);
+        assert!((lights.pdf(first.unwrap()) - 0.5).abs() < 1e-6);
+        assert_eq!(lights.find(&hit([3.0, 3.0, 0.5], [0.0, -1.0, 0.0])), None);
+    }
+}
@@ -1,28 +1,310 @@
-use image::RgbImage;
 use indicatif::{ProgressBar, ProgressStyle};
+use num_traits::AsPrimitive;
 use rand::prelude::*;
-use rayon::prelude::*;
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
 use raytracer::*;
 
+struct Options {
+    width: u32,
+    samples_per_pixel: u32,
+    pass_samples: u32,
+    output: String,
+    checkpoint: String,
+    checkpoint_interval: u64,
+    resume: bool,
+    preview_port: Option<u16>,
+    preview_refresh: u64,
+    aperture: Real,
+    blades: u32,
+    blade_rotation: Real,
+    aperture_mask: Option<String>,
+    projection: String,
+    fisheye_fov: Real,
+    vertical_fov: Real,
+    focal_length: Option<Real>,
+    sensor: (Real, Real),
+    f_number: Real,
+    shutter_time: Real,
+    iso: Real,
+    autofocus: Option<(Real, Real)>,
+    stereo: Option<StereoLayout>,
+    interaxial: Real,
+    convergence: Convergence,
+    filter: Filter<f64>,
+    filter_radius: Option<f64>,
+    sampler: String,
+    seed: u64,
+    tile_order: TileOrder,
+    tile_size: u32,
+    frames: Option<(u32, u32)>,
+    fps: Real,
+    frame_dir: String,
+    turntable: bool,
+    stats: bool,
+    stats_json: Option<String>,
+    max_depth: u16,
+    roulette: Option<RussianRoulette>,
+    lights: bool,
+    integrator: String,
+    ao_distance: Real,
+    ao_samples: u32,
+    bake: Option<BakeMode>,
+    mesh: Option<String>,
+    bake_size: u32,
+    bake_dilation: u32,
+    photons: u32,
+    photon_radius: Real,
+    progressive: Option<Real>,
+    mlt: bool,
+    mlt_bootstrap: u32,
+    mlt_chains: u32,
+    mlt_sigma: Real,
+    mlt_large_step: Real,
+}
+
+fn parse_args() -> Options {
+    let mut options = Options {
+        width: 1920,
+        samples_per_pixel: 1000,
+        pass_samples: 16,
+        output: String::from("image.png"),
+        checkpoint: String::from("image.checkpoint"),
+        checkpoint_interval: 60,
+        resume: false,
+        preview_port: None,
+        preview_refresh: 2,
+        aperture: 0.1,
+        blades: 0,
+        blade_rotation: 0.0,
+        aperture_mask: None,
+        projection: String::from("perspective"),
+        fisheye_fov: 180.0,
+        vertical_fov: 20.0,
+        focal_length: None,
+        sensor: (36.0, 24.0),
+        f_number: 8.0,
+        shutter_time: 0.0025,
+        iso: 100.0,
+        autofocus: None,
+        stereo: None,
+        interaxial: 0.065,
+        convergence: Convergence::OffAxis,
+        filter: Filter::default(),
+        filter_radius: None,
+        sampler: String::from("sobol"),
+        seed: 0,
+        tile_order: TileOrder::Spiral,
+        tile_size: 32,
+        frames: None,
+        fps: 24.0,
+        frame_dir: String::from("frames"),
+        turntable: false,
+        stats: false,
+        stats_json: None,
+        max_depth: 50,
+        roulette: Some(RussianRoulette { min_depth: 3 }),
+        lights: false,
+        integrator: String::from("path"),
+        ao_distance: 1.0,
+        ao_samples: 1,
+        bake: None,
+        mesh: None,
+        bake_size: 1024,
+        bake_dilation: 4,
+        photons: 200_000,
+        photon_radius: 0.05,
+        progressive: None,
+        mlt: false,
+        mlt_bootstrap: 100_000,
+        mlt_chains: 1000,
+        mlt_sigma: 0.01,
+        mlt_large_step: 0.3,
+    };
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--width" => options.width = next_value(&mut args, &arg),
+            "--samples" => options.samples_per_pixel = next_value(&mut args, &arg),
+            "--pass-samples" => options.pass_samples = next_value(&mut args, &arg),
+            "--output" => options.output = next_value(&mut args, &arg),
+            "--checkpoint" => options.checkpoint = next_value(&mut args, &arg),
+            "--checkpoint-interval" => options.checkpoint_interval = next_value(&mut args, &arg),
+            "--resume" => options.resume = true,
+            "--preview" => options.preview_port = Some(next_value(&mut args, &arg)),
+            "--preview-refresh" => options.preview_refresh = next_value(&mut args, &arg),
+            "--aperture" => options.aperture = next_value(&mut args, &arg),
+            "--blades" => options.blades = next_value(&mut args, &arg),
+            "--blade-rotation" => options.blade_rotation = next_value(&mut args, &arg),
+            "--aperture-mask" => options.aperture_mask = Some(next_value(&mut args, &arg)),
+            "--projection" => options.projection = next_value(&mut args, &arg),
+            "--fisheye-fov" => options.fisheye_fov = next_value(&mut args, &arg),
+            "--vfov" => options.vertical_fov = next_value(&mut args, &arg),
+            "--focal-length" => options.focal_length = Some(next_value(&mut args, &arg)),
+            "--sensor" => {
+                let size: String = next_value(&mut args, &arg);
+                options.sensor = match size.split_once('x') {
+                    Some((width, height)) => (
+                        width.parse().expect("invalid sensor width"),
+                        height.parse().expect("invalid sensor height"),
+                    ),
+                    None => panic!("--sensor expects <width>x<height> in millimetres"),
+                }
+            }
+            "--f-number" => options.f_number = next_value(&mut args, &arg),
+            "--shutter" => options.shutter_time = next_value(&mut args, &arg),
+            "--iso" => options.iso = next_value(&mut args, &arg),
+            "--autofocus" => {
+                let point: String = next_value(&mut args, &arg);
+                options.autofocus = match point.split_once(',') {
+                    Some((u, v)) => Some((
+                        u.parse().expect("invalid autofocus u"),
+                        v.parse().expect("invalid autofocus v"),
+                    )),
+                    None => panic!("--autofocus expects <u>,<v> in image coordinates"),
+                }
+            }
+            "--stereo" => {
+                options.stereo = match next_value::<String>(&mut args, &arg).as_str() {
+                    "side-by-side" => Some(StereoLayout::SideBySide),
+                    "top-bottom" => Some(StereoLayout::TopBottom),
+                    layout => panic!("unknown stereo layout: {}", layout),
+                }
+            }
+            "--interaxial" => options.interaxial = next_value(&mut args, &arg),
+            "--convergence" => {
+                options.convergence = match next_value::<String>(&mut args, &arg).as_str() {
+                    "parallel" => Convergence::Parallel,
+                    "toe-in" => Convergence::ToeIn,
+                    "off-axis" => Convergence::OffAxis,
+                    convergence => panic!("unknown convergence: {}", convergence),
+                }
+            }
+            "--filter" => {
+                options.filter = match next_value::<String>(&mut args, &arg).as_str() {
+                    "box" => Filter::Box { radius: 0.5 },
+                    "tent" => Filter::Tent { radius: 1.0 },
+                    "gaussian" => Filter::Gaussian {
+                        radius: 1.5,
+                        sigma: 0.5,
+                    },
+                    "mitchell" => Filter::Mitchell {
+                        radius: 2.0,
+                        b: 1.0 / 3.0,
+                        c: 1.0 / 3.0,
+                    },
+                    "lanczos" => Filter::Lanczos { radius: 3.0 },
+                    filter => panic!("unknown filter: {}", filter),
+                }
+            }
+            "--filter-radius" => options.filter_radius = Some(next_value(&mut args, &arg)),
+            "--sampler" => options.sampler = next_value(&mut args, &arg),
+            "--seed" => options.seed = next_value(&mut args, &arg),
+            "--tile-order" => {
+                options.tile_order = match next_value::<String>(&mut args, &arg).as_str() {
+                    "scanline" => TileOrder::Scanline,
+                    "spiral" => TileOrder::Spiral,
+                    "hilbert" => TileOrder::Hilbert,
+                    order => panic!("unknown tile order: {}", order),
+                }
+            }
+            "--tile-size" => options.tile_size = next_value(&mut args, &arg),
+            "--frames" => {
+                let range: String = next_value(&mut args, &arg);
+                options.frames = match range.split_once('-') {
+                    Some((first, last)) => Some((
+                        first.parse().expect("invalid first frame"),
+                        last.parse().expect("invalid last frame"),
+                    )),
+                    None => panic!("--frames expects <first>-<last>"),
+                }
+            }
+            "--fps" => options.fps = next_value(&mut args, &arg),
+            "--frame-dir" => options.frame_dir = next_value(&mut args, &arg),
+            "--turntable" => options.turntable = true,
+            "--stats" => options.stats = true,
+            "--stats-json" => options.stats_json = Some(next_value(&mut args, &arg)),
+            "--lights" => options.lights = true,
+            "--integrator" => options.integrator = next_value(&mut args, &arg),
+            "--ao-distance" => options.ao_distance = next_value(&mut args, &arg),
+            "--ao-samples" => options.ao_samples = next_value(&mut args, &arg),
+            "--bake" => {
+                options.bake = match next_value::<String>(&mut args, &arg).as_str() {
+                    "ao" => Some(BakeMode::AmbientOcclusion),
+                    "curvature" => Some(BakeMode::Curvature),
+                    "lightmap" => Some(BakeMode::Lightmap),
+                    mode => panic!("unknown bake mode: {}", mode),
+                }
+            }
+            "--mesh" => options.mesh = Some(next_value(&mut args, &arg)),
+            "--bake-size" => options.bake_size = next_value(&mut args, &arg),
+            "--bake-dilation" => options.bake_dilation = next_value(&mut args, &arg),
+            "--photons" => options.photons = next_value(&mut args, &arg),
+            "--photon-radius" => options.photon_radius = next_value(&mut args, &arg),
+            "--progressive" => options.progressive = Some(next_value(&mut args, &arg)),
+            "--mlt" => options.mlt = true,
+            "--mlt-bootstrap" => options.mlt_bootstrap = next_value(&mut args, &arg),
+            "--mlt-chains" => options.mlt_chains = next_value(&mut args, &arg),
+            "--mlt-sigma" => options.mlt_sigma = next_value(&mut args, &arg),
+            "--mlt-large-step" => options.mlt_large_step = next_value(&mut args, &arg),
+            "--max-depth" => options.max_depth = next_value(&mut args, &arg),
+            "--roulette" => {
+                options.roulette = match next_value::<String>(&mut args, &arg).as_str() {
+                    "off" => None,
+                    depth => Some(RussianRoulette {
+                        min_depth: depth.parse().expect("invalid roulette depth"),
+                    }),
+                }
+            }
+            _ => panic!("unknown argument: {}", arg),
+        }
+    }
+    options
+}
+
+fn next_value<T: FromStr>(args: &mut impl Iterator<Item = String>, name: &str) -> T {
+    args.next()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or_else(|| panic!("{} requires a valid value", name))
+}
+
 fn main() {
-    let aspect_ratio = 16.0 / 9.0;
-    let image_width = 1920;
-    let image_height = (image_width as f64 / aspect_ratio) as u32;
-    let samples_per_pixel = 1000;
-    let max_depth = 50;
+    let options = parse_args();
+    if let Some(mode) = options.bake {
+        bake(&options, mode);
+        return;
+    }
+
+    let physical = options.focal_length.map(|focal_length| PhysicalCamera {
+        focal_length,
+        sensor_width: options.sensor.0,
+        sensor_height: options.sensor.1,
+        f_number: options.f_number,
+        shutter_time: options.shutter_time,
+        iso: options.iso,
+    });
+
+    let aspect_ratio = match &physical {
+        Some(physical) => physical.aspect_ratio(),
+        None => 16.0 / 9.0,
+    };
+    let image_width = options.width;
+    let image_height = (image_width as Real / aspect_ratio) as u32;
+    let samples_per_pixel = options.samples_per_pixel;
 
     let look_from = Point3([13.0, 2.0, -3.0].into());
     let look_at = Point3([0.0, 0.0, 0.0].into());
     let up = Vec3([0.0, 1.0, 0.0].into());
-    let vertical_fov = 20.0;
-    let aperture = 0.1;
+    let vertical_fov = options.vertical_fov;
+    let aperture = options.aperture;
     let dist_to_focus = 10.0;
 
-    let mut img = RgbImage::new(image_width, image_height);
-    let world = random_scene();
-
-    let cam = Camera::new(
+    let seed = options.seed;
+    let mut camera = CameraAnimation::new(
         look_from,
         look_at,
         up,
@@ -31,111 +313,599 @@ fn main() {
         aperture,
         dist_to_focus,
     );
-    let progress_bar = ProgressBar::new(image_height as u64);
+    camera.physical = physical;
+    let mut scene = SceneAnimation {
+        camera,
+        world: random_scene(seed, options.lights),
+        objects: Vec::new(),
+    };
+    if options.turntable {
+        let (first, last) = options.frames.expect("--turntable needs --frames");
+        turntable(
+            &mut scene,
+            first as Real / options.fps,
+            (last + 1) as Real / options.fps,
+        );
+    }
+
+    let sampler = sampler(&options);
+
+    let aperture_shape = match &options.aperture_mask {
+        Some(path) => Aperture::Mask(ApertureMask::open(path).unwrap()),
+        None if options.blades > 0 => Aperture::Polygon {
+            blades: options.blades,
+            rotation: options.blade_rotation,
+        },
+        None => Aperture::Circle,
+    };
+    let projection = match options.projection.as_str() {
+        "perspective" => Projection::Perspective,
+        "orthographic" => Projection::Orthographic,
+        "fisheye" => Projection::Fisheye {
+            fov: options.fisheye_fov,
+            mapping: FisheyeMapping::Equidistant,
+        },
+        "fisheye-equisolid" => Projection::Fisheye {
+            fov: options.fisheye_fov,
+            mapping: FisheyeMapping::Equisolid,
+        },
+        "equirectangular" => Projection::Equirectangular,
+        "cylindrical" => Projection::Cylindrical,
+        projection => panic!("unknown projection: {}", projection),
+    };
+    let camera_at = |time: Real, world: &HittableVec<Real>| {
+        let mut cam = scene
+            .camera_at(time)
+            .with_aperture(aperture_shape.clone())
+            .with_projection(projection.clone());
+        if let Some((u, v)) = options.autofocus {
+            match cam.autofocus(world, u, v) {
+                Some(distance) => println!("focus distance: {:.3}", distance),
+                None => println!("autofocus: nothing at ({}, {})", u, v),
+            }
+        }
+        cam
+    };
+    let new_film = |width: u32, height: u32, cam: &Camera<Real>| {
+        let mut film = Film::new(width, height);
+        film.exposure = cam.exposure().as_();
+        // Each eye of a stereo pair is filtered on its own
+        film.viewport = (image_width, image_height);
+        film.filter = match options.filter_radius {
+            Some(radius) => options.filter.with_radius(radius),
+            None => options.filter,
+        };
+        film
+    };
+
+    let first_time = options
+        .frames
+        .map_or(0.0, |(first, _)| first as Real / options.fps);
+    let world = scene.world_at(first_time);
+    let cam = camera_at(first_time, &world);
+    let (frame_width, frame_height) = match stereo_rig(&options, &cam) {
+        Some(rig) => rig.image_size(image_width, image_height),
+        None => (image_width, image_height),
+    };
+
+    let mut film = new_film(frame_width, frame_height, &cam);
+    let settings = SampleSettings {
+        seed: options.seed,
+        sampler: options.sampler.clone(),
+        samples_per_pixel,
+        pass_samples: options.pass_samples.max(1),
+        filter: film.filter,
+    };
+    if options.resume && options.frames.is_none() {
+        let (mut checkpoint, recorded) = Film::load_checkpoint(&options.checkpoint).unwrap();
+        assert!(
+            checkpoint.width == frame_width && checkpoint.height == frame_height,
+            "checkpoint is {}x{}, but the render is {}x{}",
+            checkpoint.width,
+            checkpoint.height,
+            frame_width,
+            frame_height
+        );
+        assert!(
+            recorded == settings,
+            "checkpoint was rendered with {}, but the render uses {}",
+            recorded,
+            settings
+        );
+        checkpoint.exposure = film.exposure;
+        checkpoint.viewport = film.viewport;
+        film = checkpoint;
+    }
+    let film = Arc::new(Mutex::new(film));
+
+    if let Some(port) = options.preview_port {
+        let refresh = Duration::from_secs(options.preview_refresh);
+        let server = PreviewServer::start(port, film.clone(), refresh).unwrap();
+        println!("preview at http://{}/", server.address);
+    }
+
+    let (first, last) = match options.frames {
+        Some(frames) => frames,
+        None => {
+            let checkpoint_interval = Duration::from_secs(options.checkpoint_interval);
+            let mut last_checkpoint = Instant::now();
+            let stats = render_frame(
+                &options,
+                &cam,
+                &world,
+                &sampler,
+                &film,
+                (image_width, image_height),
+                |film| {
+                    if last_checkpoint.elapsed() >= checkpoint_interval
+                        && film.samples < samples_per_pixel
+                    {
+                        film.to_image().save(&options.output).unwrap();
+                        film.save_checkpoint(&options.checkpoint, &settings)
+                            .unwrap();
+                        last_checkpoint = Instant::now();
+                    }
+                },
+            );
+
+            let film = film.lock().unwrap();
+            film.to_image().save(&options.output).unwrap();
+            film.save_checkpoint(&options.checkpoint, &settings)
+                .unwrap();
+            report_stats(&options, &stats);
+            return;
+        }
+    };
+
+    // Finished frames are kept on disk, so `--resume` picks a sequence up at
+    // the first frame that is missing.
+    fs::create_dir_all(&options.frame_dir).unwrap();
+    let mut stats = RenderStats::default();
+    for frame in first..=last {
+        let path = Path::new(&options.frame_dir).join(format!("frame_{:04}.png", frame));
+        if options.resume && path.exists() {
+            continue;
+        }
+        println!("frame {} of {}..{}", frame, first, last);
+
+        let time = frame as Real / options.fps;
+        let world = scene.world_at(time);
+        let cam = camera_at(time, &world);
+        *film.lock().unwrap() = new_film(frame_width, frame_height, &cam);
+        stats.merge(&render_frame(
+            &options,
+            &cam,
+            &world,
+            &sampler,
+            &film,
+            (image_width, image_height),
+            |_| {},
+        ));
+        film.lock().unwrap().to_image().save(&path).unwrap();
+    }
+    report_stats(&options, &stats);
+}
+
+fn sampler(options: &Options) -> Samplers {
+    let seed = options.seed;
+    match options.sampler.as_str() {
+        "independent" => Samplers::Independent(IndependentSampler::new(seed)),
+        "stratified" => {
+            Samplers::Stratified(StratifiedSampler::new(options.samples_per_pixel, seed))
+        }
+        "halton" => Samplers::Halton(HaltonSampler::new(seed)),
+        "sobol" => Samplers::Sobol(SobolSampler::new(seed)),
+        name => panic!("unknown sampler: {}", name),
+    }
+}
+
+// Bakes `--mesh` into its uv layout and writes the result to `--output`: a
+// greyscale image, or for lightmaps an HDR file laid out by the lightmap
+// uvs. Everything is baked from the mesh on its own; lightmaps are lit by the
+// sky and, with `--lights`, the lamp of the demo scene.
+fn bake(options: &Options, mode: BakeMode) {
+    let path = options.mesh.as_ref().expect("--bake requires --mesh");
+    let mesh = Mesh::load_obj(
+        path,
+        Materials::Lambertian(Lambertian {
+            albedo: Color([0.5, 0.5, 0.5].into()),
+        }),
+    )
+    .unwrap();
+    let mut baker = Baker::new(
+        options.bake_size,
+        options.bake_size,
+        options.samples_per_pixel,
+    );
+    baker.distance = options.ao_distance;
+    if mode == BakeMode::Lightmap {
+        baker.uv_set = UvSet::Lightmap;
+        baker.dilation = options.bake_dilation;
+    }
+
+    let texels = baker.rasterize(&mesh);
+    let values = match mode {
+        BakeMode::AmbientOcclusion => {
+            let world = HittableVec {
+                objects: vec![Hittable::Mesh(mesh)],
+            };
+            baker.ambient_occlusion(&texels, &world, &sampler(options))
+        }
+        BakeMode::Curvature => baker.curvature(&texels, &mesh),
+        BakeMode::Lightmap => {
+            assert!(
+                texels.iter().any(Option::is_some),
+                "--bake lightmap requires a mesh with lightmap uvs: `vl <u> <v>` lines, \
+                 indexed by a fourth face corner field, `f v/vt/vn/vl`"
+            );
+            let mut world = HittableVec {
+                objects: vec![Hittable::Mesh(mesh)],
+            };
+            if options.lights {
+                world.push(lamp());
+            }
+            let bvh = Bvh::new(&world);
+            let path = PathIntegrator {
+                max_depth: options.max_depth,
+                roulette: options.roulette,
+            };
+            let mut irradiance = baker.irradiance(&texels, &path, &bvh, &sampler(options));
+            baker.dilate(&mut irradiance);
+            baker.save_hdr(&irradiance, &options.output).unwrap();
+            return;
+        }
+    };
+    baker.to_image(&values).save(&options.output).unwrap();
+}
+
+fn integrator(
+    options: &Options,
+    cam: &Camera<Real>,
+    world: &HittableVec<Real>,
+    film: &Mutex<Film>,
+) -> Integrators {
+    let path = PathIntegrator {
+        max_depth: options.max_depth,
+        roulette: options.roulette,
+    };
+    match options.integrator.as_str() {
+        "path" => Integrators::Path(path),
+        "recursive" => Integrators::Recursive(path),
+        "ao" => Integrators::AmbientOcclusion(AmbientOcclusionIntegrator {
+            distance: options.ao_distance,
+            samples: options.ao_samples,
+        }),
+        "direct" => {
+            Integrators::DirectLighting(DirectLightingIntegrator::new(options.max_depth, world))
+        }
+        "normals" => Integrators::Normals(NormalsIntegrator),
+        "bdpt" => {
+            // Light subpaths are splatted through `cam`, which a stereo
+            // rig would not match
+            assert!(options.stereo.is_none(), "bdpt does not support stereo");
+            Integrators::Bidirectional(Box::new(BdptIntegrator::new(
+                options.max_depth,
+                cam,
+                world,
+                &film.lock().unwrap(),
+            )))
+        }
+        "photon" => Integrators::PhotonMap(PhotonMapIntegrator::new(
+            options.max_depth,
+            options.roulette,
+            options.photons,
+            options.photon_radius,
+            options.progressive,
+            world,
+        )),
+        name => panic!("unknown integrator: {}", name),
+    }
+}
+
+fn stereo_rig(options: &Options, cam: &Camera<Real>) -> Option<StereoRig<Real>> {
+    options
+        .stereo
+        .map(|layout| StereoRig::new(cam, options.interaxial, options.convergence, layout))
+}
+
+fn render_frame<P: FnMut(&Film)>(
+    options: &Options,
+    cam: &Camera<Real>,
+    world: &HittableVec<Real>,
+    sampler: &Samplers,
+    film: &Mutex<Film>,
+    (image_width, image_height): (u32, u32),
+    mut on_pass: P,
+) -> RenderStats {
+    let integrator = integrator(options, cam, world, film);
+    let bvh = Bvh::new(world);
+    if options.mlt {
+        return render_metropolis(options, cam, &bvh, sampler, film, &integrator, on_pass);
+    }
+    let rig = stereo_rig(options, cam);
+    let (frame_width, frame_height, done_samples) = {
+        let film = film.lock().unwrap();
+        (film.width, film.height, film.samples)
+    };
+
+    let mut renderer = Renderer::new(frame_width, frame_height, options.samples_per_pixel);
+    renderer.pass_samples = options.pass_samples;
+    renderer.order = options.tile_order;
+    renderer.tile_size = options.tile_size;
+    let done_passes = done_samples.div_ceil(renderer.pass_samples.max(1));
+
+    let tiles = renderer.tiles().len() as u64;
+    let progress_bar = ProgressBar::new(tiles * renderer.passes() as u64);
     let pb_style = ProgressStyle::default_bar()
         .template("[{elapsed_precise}] {bar:40.cyan/blue} {pos:>7}/{len:7} ({eta})")
         .progress_chars("##-");
     progress_bar.set_style(pb_style);
+    progress_bar.set_position(tiles * done_passes as u64);
 
-    img.enumerate_rows_mut().par_bridge().into_par_iter().for_each(
-        |(_, chunk)| {
-            let mut rng = thread_rng();
-            chunk.for_each(
-            |(x, y, pixel)| {
-                let mut color = Color::default();
-                for _ in 0..samples_per_pixel {
-                    let u = (x as f64 + rng.gen_range(0.0, 1.0)) / image_width as f64;
-                    let v = (y as f64 + rng.gen_range(0.0, 1.0)) / image_height as f64;
-
-                    let ray = cam.get_ray(u, v);
-                    color += &ray_color(&ray, &world, max_depth);
+    // Workers drain their thread counters after every tile. Photons are
+    // traced outside the tiles, so every thread is drained after each photon
+    // pass and once more when `render` returns, leaving nothing counted
+    // during this frame behind.
+    let stats = Mutex::new(RenderStats::default());
+    let start = Instant::now();
+    let mut pass = done_passes;
+    integrator.start_pass(pass, &bvh, sampler);
+    stats.lock().unwrap().merge(&RenderStats::take_all());
+    renderer.render(
+        film,
+        sampler,
+        |samplers, positions| {
+            let mut rays: [Option<Ray<Real>>; 4] = Default::default();
+            for (lane, sampler) in samplers.iter_mut().enumerate() {
+                rays[lane] = positions[lane].and_then(|(x, y)| match &rig {
+                    Some(rig) => rig.get_ray(x, y, image_width, image_height, sampler),
+                    None => cam.get_ray(x / image_width as Real, y / image_height as Real, sampler),
+                });
+            }
+            // Whole blocks of camera rays are coherent enough to trace as a
+            // packet; blocks cut by the tile edge or the lens go one by one.
+            let mut hits = match &rays {
+                [Some(a), Some(b), Some(c), Some(d)] => {
+                    let packet = [a.clone(), b.clone(), c.clone(), d.clone()];
+                    bvh.hit_packet(&packet, 0.0, Real::INFINITY)
                 }
-                *pixel = color.as_rgb(samples_per_pixel);
-            });
-            progress_bar.inc(1);
-        }
+                _ => rays.each_ref().map(|ray| {
+                    ray.as_ref()
+                        .and_then(|ray| (&bvh).hit(ray, 0.0, Real::INFINITY))
+                }),
+            };
+            let mut colors: [Color<Real>; 4] = Default::default();
+            for (lane, sampler) in samplers.iter_mut().enumerate() {
+                if let Some(ray) = &rays[lane] {
+                    colors[lane] = integrator.radiance_from(ray, hits[lane].take(), &bvh, sampler);
+                }
+            }
+            colors
+        },
+        |_, _| {
+            stats.lock().unwrap().merge(&RenderStats::take());
+            progress_bar.inc(1)
+        },
+        |samples| {
+            pass += 1;
+            if samples < options.samples_per_pixel {
+                integrator.start_pass(pass, &bvh, sampler);
+                stats.lock().unwrap().merge(&RenderStats::take_all());
+            }
+            on_pass(&film.lock().unwrap());
+        },
     );
+    progress_bar.finish();
 
-    img.save("image.png").unwrap();
+    let mut stats = stats.into_inner().unwrap();
+    stats.merge(&RenderStats::take_all());
+    stats.elapsed = start.elapsed();
+    stats
 }
 
-fn random_scene() -> HittableVec<f64> {
+// Chains wander over the whole image, so there are no tiles; progress is
+// counted in mutations.
+fn render_metropolis<P: FnMut(&Film)>(
+    options: &Options,
+    cam: &Camera<Real>,
+    world: &Bvh<Real>,
+    sampler: &Samplers,
+    film: &Mutex<Film>,
+    integrator: &Integrators,
+    on_pass: P,
+) -> RenderStats {
+    assert!(options.stereo.is_none(), "mlt does not support stereo");
+    // Splats from the base integrator would bypass the chains
+    assert!(
+        !matches!(integrator, Integrators::Bidirectional(_)),
+        "mlt cannot run on top of bdpt"
+    );
+    let metropolis = Metropolis {
+        bootstrap_samples: options.mlt_bootstrap,
+        chains: options.mlt_chains,
+        sigma: options.mlt_sigma,
+        large_step_probability: options.mlt_large_step,
+        seed: options.seed,
+    };
+    let (pixels, done_samples) = {
+        let film = film.lock().unwrap();
+        (film.width as u64 * film.height as u64, film.samples)
+    };
+    let progress_bar = ProgressBar::new(pixels * options.samples_per_pixel as u64);
+    let pb_style = ProgressStyle::default_bar()
+        .template("[{elapsed_precise}] {bar:40.cyan/blue} {pos:>7}/{len:7} ({eta})")
+        .progress_chars("##-");
+    progress_bar.set_style(pb_style);
+    progress_bar.set_position(pixels * done_samples as u64);
+
+    // The chains would not notice a photon map changing under them, so it
+    // is traced once up front.
+    integrator.start_pass(0, world, sampler);
+    let stats = Mutex::new(RenderStats::take_all());
+    let start = Instant::now();
+    metropolis.render(
+        integrator,
+        cam,
+        world,
+        film,
+        options.samples_per_pixel,
+        options.pass_samples.max(1),
+        |mutations| {
+            stats.lock().unwrap().merge(&RenderStats::take());
+            progress_bar.inc(mutations)
+        },
+        on_pass,
+    );
+    progress_bar.finish();
+
+    let mut stats = stats.into_inner().unwrap();
+    stats.merge(&RenderStats::take_all());
+    stats.elapsed = start.elapsed();
+    stats
+}
+
+fn report_stats(options: &Options, stats: &RenderStats) {
+    if options.stats {
+        println!("{}", stats);
+    }
+    if let Some(path) = &options.stats_json {
+        fs::write(path, stats.to_json()).unwrap();
+    }
+}
+
+// One orbit of the camera around the scene between `start` and `end`, plus a
+// metal sphere bobbing next to the glass one while its polish comes and goes.
+fn turntable(scene: &mut SceneAnimation, start: Real, end: Real) {
+    let keys = 8;
+    let look_from = scene.camera.look_from.at(start);
+    let radius = look_from[0].hypot(look_from[2]);
+    let start_angle = look_from[2].atan2(look_from[0]);
+    let mut orbit = Track::new(Interpolation::Cubic);
+    for key in 0..=keys {
+        let fraction = key as Real / keys as Real;
+        let angle = start_angle + fraction * 2.0 * consts::PI;
+        orbit = orbit.key(
+            start + fraction * (end - start),
+            Point3([radius * angle.cos(), look_from[1], radius * angle.sin()].into()),
+        );
+    }
+    scene.camera.look_from = orbit;
+
+    let middle = (start + end) / 2.0;
+    let mut bobbing = ObjectAnimation::new(Hittable::Sphere(Sphere::new(
+        Point3([0.0, 0.0, 0.0].into()),
+        0.4,
+        Materials::Metal(Metal {
+            albedo: Color([0.9, 0.9, 0.9].into()),
+            fuzz: 0.0,
+        }),
+    )));
+    bobbing.translation = Track::new(Interpolation::Cubic)
+        .key(start, Vec3([0.0, 0.9, 2.2].into()))
+        .key(middle, Vec3([0.0, 1.6, 2.2].into()))
+        .key(end, Vec3([0.0, 0.9, 2.2].into()));
+    bobbing.fuzz = Some(
+        Track::new(Interpolation::Linear)
+            .key(start, 0.0)
+            .key(middle, 0.5)
+            .key(end, 0.0),
+    );
+    scene.objects.push(bobbing);
+}
+
+fn random_scene(seed: u64, lights: bool) -> HittableVec<Real> {
     let mut world = HittableVec {
         objects: Vec::new(),
     };
     let mut spheres = HittableVec {
         objects: Vec::new(),
     };
-    spheres.push(Hittable::Sphere(Sphere {
-        center: Point3([0.0, -1000.0, 0.0].into()),
-        radius: 1000.0,
-        material: Materials::Lambertian(Lambertian {
+    spheres.push(Hittable::Sphere(Sphere::new(
+        Point3([0.0, -1000.0, 0.0].into()),
+        1000.0,
+        Materials::Lambertian(Lambertian {
             albedo: Color([0.5, 0.5, 0.5].into()),
         }),
-    }));
-
-    spheres.push(Hittable::Sphere(Sphere {
-        center: Point3([0.0, 1.0, 0.0].into()),
-        radius: 1.0,
-        material: Materials::Dielectric(Dielectric { ref_idx: 1.5 }),
-    }));
-    spheres.push(Hittable::Sphere(Sphere {
-        center: Point3([-4.0, 1.0, 0.0].into()),
-        radius: 1.0,
-        material: Materials::Lambertian(Lambertian {
+    )));
+
+    spheres.push(Hittable::Sphere(Sphere::new(
+        Point3([0.0, 1.0, 0.0].into()),
+        1.0,
+        Materials::Dielectric(Dielectric { ref_idx: 1.5 }),
+    )));
+    spheres.push(Hittable::Sphere(Sphere::new(
+        Point3([-4.0, 1.0, 0.0].into()),
+        1.0,
+        Materials::Lambertian(Lambertian {
             albedo: Color([0.4, 0.2, 0.1].into()),
         }),
-    }));
-    spheres.push(Hittable::Sphere(Sphere {
-        center: Point3([4.0, 1.0, 0.0].into()),
-        radius: 1.0,
-        material: Materials::Metal(Metal {
+    )));
+    spheres.push(Hittable::Sphere(Sphere::new(
+        Point3([4.0, 1.0, 0.0].into()),
+        1.0,
+        Materials::Metal(Metal {
             albedo: Color([0.8, 0.6, 0.5].into()),
             fuzz: 0.0,
         }),
-    }));
+    )));
 
-    let mut rng = thread_rng();
+    let mut rng = StdRng::seed_from_u64(seed);
     for a in -11..11 {
         for b in -11..11 {
             let choose_material = rng.gen_range(0.0, 1.0);
             let center = Point3(
                 [
-                    a as f64 + 0.9 * rng.gen_range(0.0, 1.0),
+                    a as Real + 0.9 * rng.gen_range(0.0, 1.0),
                     0.2,
-                    b as f64 + 0.9 * rng.gen_range(0.0, 1.0),
+                    b as Real + 0.9 * rng.gen_range(0.0, 1.0),
                 ]
                 .into(),
             );
             if choose_material < 0.8 {
                 //diffuse
-                let albedo = Color::random(0.0, 1.0);
-                spheres.push(Hittable::Sphere(Sphere {
+                let albedo = Color::random(0.0, 1.0, &mut rng);
+                spheres.push(Hittable::Sphere(Sphere::new(
                     center,
-                    radius: 0.2,
-                    material: Materials::Lambertian(Lambertian { albedo }),
-                }));
+                    0.2,
+                    Materials::Lambertian(Lambertian { albedo }),
+                )));
             } else if choose_material < 0.95 {
                 //metal
-                let albedo = Color::random(0.5, 1.0);
+                let albedo = Color::random(0.5, 1.0, &mut rng);
                 let fuzz = rng.gen_range(0.0, 0.5);
-                spheres.push(Hittable::Sphere(Sphere {
+                spheres.push(Hittable::Sphere(Sphere::new(
                     center,
-                    radius: 0.2,
-                    material: Materials::Metal(Metal { albedo, fuzz }),
-                }));
+                    0.2,
+                    Materials::Metal(Metal { albedo, fuzz }),
+                )));
             } else {
                 //glass
-                spheres.push(Hittable::Sphere(Sphere {
+                spheres.push(Hittable::Sphere(Sphere::new(
                     center,
-                    radius: 0.2,
-                    material: Materials::Dielectric(Dielectric { ref_idx: 1.5 }),
-                }));
+                    0.2,
+                    Materials::Dielectric(Dielectric { ref_idx: 1.5 }),
+                )));
             }
         }
     }
 
     world.push(Hittable::HittableVec(spheres));
 
+    if lights {
+        world.push(lamp());
+    }
+
     world
 }
+
+// A small lamp behind the glass sphere of `random_scene`, focused by it onto
+// the ground.
+fn lamp() -> Hittable<Real> {
+    Hittable::Sphere(Sphere::new(
+        Point3([-1.5, 3.0, 1.5].into()),
+        0.2,
+        Materials::DiffuseLight(DiffuseLight {
+            emit: Color([40.0, 36.0, 30.0].into()),
+        }),
+    ))
+}
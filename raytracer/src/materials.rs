@@ -1,9 +1,10 @@
-use rand::prelude::*;
-
 use crate::base::color::Color;
+use crate::base::frame::Frame;
 use crate::base::vec3::{Vec3, Vec3Operations};
+use crate::base::Real;
 use crate::hittable::HitRecord;
 use crate::ray::Ray;
+use crate::sampler::Sampler;
 
 #[derive(Debug, Clone)]
 pub struct Lambertian<T> {
@@ -21,46 +22,52 @@ pub struct Dielectric<T> {
     pub ref_idx: T,
 }
 
+// Emits `emit` from the outside of its surface and scatters nothing.
+#[derive(Debug, Clone)]
+pub struct DiffuseLight<T> {
+    pub emit: Color<T>,
+}
+
 #[derive(Debug, Clone)]
 pub enum Materials<T> {
     Lambertian(Lambertian<T>),
     Metal(Metal<T>),
     Dielectric(Dielectric<T>),
+    DiffuseLight(DiffuseLight<T>),
 }
 
 pub trait Scatter<T> {
-    fn scatter(&self, ray_in: &Ray<T>, hit_record: &HitRecord<T>) -> Option<(Ray<T>, Color<T>)>;
+    fn scatter<S: Sampler>(
+        &self,
+        ray_in: &Ray<T>,
+        hit_record: &HitRecord<T>,
+        sampler: &mut S,
+    ) -> Option<(Ray<T>, Color<T>)>;
 }
 
-impl Scatter<f64> for Lambertian<f64> {
-    fn scatter(
+impl Scatter<Real> for Lambertian<Real> {
+    fn scatter<S: Sampler>(
         &self,
-        _ray_in: &Ray<f64>,
-        hit_record: &HitRecord<f64>,
-    ) -> Option<(Ray<f64>, Color<f64>)> {
-        let scatter_direction = &hit_record.normal + &Vec3::random_unit();
-        Some((
-            Ray {
-                origin: hit_record.point.clone(),
-                direction: scatter_direction,
-            },
-            self.albedo.clone(),
-        ))
+        _ray_in: &Ray<Real>,
+        hit_record: &HitRecord<Real>,
+        sampler: &mut S,
+    ) -> Option<(Ray<Real>, Color<Real>)> {
+        let frame = Frame::from_normal(&hit_record.normal);
+        let scatter_direction = frame.to_world(&Vec3::random_cosine(sampler));
+        Some((hit_record.spawn_ray(scatter_direction), self.albedo.clone()))
     }
 }
 
-impl Scatter<f64> for Metal<f64> {
-    fn scatter(
+impl Scatter<Real> for Metal<Real> {
+    fn scatter<S: Sampler>(
         &self,
-        ray_in: &Ray<f64>,
-        hit_record: &HitRecord<f64>,
-    ) -> Option<(Ray<f64>, Color<f64>)> {
+        ray_in: &Ray<Real>,
+        hit_record: &HitRecord<Real>,
+        sampler: &mut S,
+    ) -> Option<(Ray<Real>, Color<Real>)> {
         let reflected = ray_in.direction.unit().reflect(&hit_record.normal);
-        let scattered = Ray {
-            origin: hit_record.point.clone(),
-            direction: reflected + Vec3::random_unit() * self.fuzz,
-        };
-        if scattered.direction.dot(&hit_record.normal) > 0.0 {
+        let scattered = hit_record.spawn_ray(reflected + Vec3::random_unit(sampler) * self.fuzz);
+        if hit_record.normal.dot_vector(&scattered.direction) > 0.0 {
             Some((scattered, self.albedo.clone()))
         } else {
             None
@@ -68,12 +75,13 @@ impl Scatter<f64> for Metal<f64> {
     }
 }
 
-impl Scatter<f64> for Dielectric<f64> {
-    fn scatter(
+impl Scatter<Real> for Dielectric<Real> {
+    fn scatter<S: Sampler>(
         &self,
-        ray_in: &Ray<f64>,
-        hit_record: &HitRecord<f64>,
-    ) -> Option<(Ray<f64>, Color<f64>)> {
+        ray_in: &Ray<Real>,
+        hit_record: &HitRecord<Real>,
+        sampler: &mut S,
+    ) -> Option<(Ray<Real>, Color<Real>)> {
         let etai_over_etat = if hit_record.front_face {
             1.0 / self.ref_idx
         } else {
@@ -81,40 +89,60 @@ impl Scatter<f64> for Dielectric<f64> {
         };
 
         let unit_direction = ray_in.direction.unit();
-        let cos_theta = (-&unit_direction).dot(&hit_record.normal).min(1.0);
+        let cos_theta = hit_record.normal.dot_vector(&-&unit_direction).min(1.0);
         let sin_theta = (1.0 - cos_theta.powi(2)).sqrt();
-        let mut rng = thread_rng();
         let reflect_prob = shlick(cos_theta, etai_over_etat);
         let new_direction =
-            if (etai_over_etat * sin_theta > 1.0) | (reflect_prob > rng.gen_range(0.0, 1.0)) {
+            if (etai_over_etat * sin_theta > 1.0) | (reflect_prob > sampler.next_1d()) {
                 unit_direction.reflect(&hit_record.normal)
             } else {
                 unit_direction.refract(&hit_record.normal, etai_over_etat)
             };
 
-        let scattered = Ray {
-            origin: hit_record.point.clone(),
-            direction: new_direction,
-        };
-        Some((scattered, Color([1.0, 1.0, 1.0].into())))
+        Some((
+            hit_record.spawn_ray(new_direction),
+            Color([1.0, 1.0, 1.0].into()),
+        ))
+    }
+}
+
+impl Scatter<Real> for DiffuseLight<Real> {
+    fn scatter<S: Sampler>(
+        &self,
+        _ray_in: &Ray<Real>,
+        _hit_record: &HitRecord<Real>,
+        _sampler: &mut S,
+    ) -> Option<(Ray<Real>, Color<Real>)> {
+        None
     }
 }
 
-impl Scatter<f64> for Materials<f64> {
-    fn scatter(
+impl Scatter<Real> for Materials<Real> {
+    fn scatter<S: Sampler>(
         &self,
-        ray_in: &Ray<f64>,
-        hit_record: &HitRecord<f64>,
-    ) -> Option<(Ray<f64>, Color<f64>)> {
+        ray_in: &Ray<Real>,
+        hit_record: &HitRecord<Real>,
+        sampler: &mut S,
+    ) -> Option<(Ray<Real>, Color<Real>)> {
+        match self {
+            Materials::Lambertian(lam) => lam.scatter(ray_in, hit_record, sampler),
+            Materials::Metal(metal) => metal.scatter(ray_in, hit_record, sampler),
+            Materials::Dielectric(dielectric) => dielectric.scatter(ray_in, hit_record, sampler),
+            Materials::DiffuseLight(light) => light.scatter(ray_in, hit_record, sampler),
+        }
+    }
+}
+
+impl Materials<Real> {
+    pub fn emitted(&self, hit_record: &HitRecord<Real>) -> Color<Real> {
         match self {
-            Materials::Lambertian(lam) => lam.scatter(ray_in, hit_record),
-            Materials::Metal(metal) => metal.scatter(ray_in, hit_record),
-            Materials::Dielectric(dielectric) => dielectric.scatter(ray_in, hit_record),
+            Materials::DiffuseLight(light) if hit_record.front_face => light.emit.clone(),
+            _ => Color::default(),
         }
     }
 }
 
-fn shlick(cosine: f64, rex_idx: f64) -> f64 {
+fn shlick(cosine: Real, rex_idx: Real) -> Real {
     let r0 = ((1.0 - rex_idx) / (1.0 + rex_idx)).powi(2);
     r0 + (1.0 - r0) * (1.0 - cosine).powi(5)
 }
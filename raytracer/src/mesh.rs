@@ -0,0 +1,673 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::base::aabb::Aabb;
+use crate::base::normal3::Normal3;
+use crate::base::point3::Point3;
+use crate::base::simd::{Mask4, Vec3x4, F4};
+use crate::base::vec3::{Length, Vec3, Vec3Operations};
+use crate::base::Real;
+use crate::bvh::BvhTree;
+use crate::hittable::{shape_id, Hit, HitRecord, Primitive};
+use crate::materials::Materials;
+use crate::ray::{gamma, offset_origin, Ray, RayPacket};
+use crate::stats::{self, Counter};
+
+// Corners index each attribute list on their own, as faces do in OBJ files.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MeshTriangle {
+    pub positions: [usize; 3],
+    pub normals: Option<[usize; 3]>,
+    pub uvs: Option<[usize; 3]>,
+    pub lightmap_uvs: Option<[usize; 3]>,
+}
+
+// Meshes carry a texture layout and a second one for lightmaps, where no two
+// triangles may share texels.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum UvSet {
+    Texture,
+    Lightmap,
+}
+
+#[derive(Debug, Clone)]
+pub struct Mesh<T> {
+    pub(crate) positions: Vec<Point3<T>>,
+    pub(crate) normals: Vec<Vec3<T>>,
+    pub(crate) uvs: Vec<(T, T)>,
+    pub(crate) lightmap_uvs: Vec<(T, T)>,
+    pub(crate) triangles: Vec<MeshTriangle>,
+    bounds: Aabb<T>,
+    tree: BvhTree,
+    pub material: Materials<T>,
+    pub(crate) id: u64,
+}
+
+// Where a point lies on a triangle, with the weights of its three corners.
+#[derive(Debug, Clone)]
+pub struct SurfacePoint {
+    pub triangle: usize,
+    pub barycentric: [Real; 3],
+    pub point: Point3<Real>,
+    // Bound on how far rounding may have moved `point` along each axis
+    pub error: Vec3<Real>,
+    pub normal: Normal3<Real>,
+    pub geometric_normal: Normal3<Real>,
+}
+
+impl SurfacePoint {
+    pub fn spawn_ray(&self, direction: Vec3<Real>) -> Ray<Real> {
+        Ray {
+            origin: offset_origin(&self.point, &self.error, &self.geometric_normal, &direction),
+            direction,
+        }
+    }
+}
+
+impl Mesh<Real> {
+    pub fn new(
+        positions: Vec<Point3<Real>>,
+        normals: Vec<Vec3<Real>>,
+        uvs: Vec<(Real, Real)>,
+        lightmap_uvs: Vec<(Real, Real)>,
+        triangles: Vec<MeshTriangle>,
+        material: Materials<Real>,
+    ) -> Self {
+        let bounds = positions
+            .iter()
+            .map(|position| Aabb::new(position.clone(), position.clone()))
+            .reduce(|a, b| a.union(&b))
+            .unwrap_or_default();
+        let boxes: Vec<Aabb<Real>> = triangles
+            .iter()
+            .map(|triangle| {
+                let [a, b, c] = triangle.positions.map(|index| &positions[index]);
+                Aabb::new(a.clone(), a.clone())
+                    .union(&Aabb::new(b.clone(), b.clone()))
+                    .union(&Aabb::new(c.clone(), c.clone()))
+            })
+            .collect();
+        Self {
+            positions,
+            normals,
+            uvs,
+            lightmap_uvs,
+            triangles,
+            bounds,
+            tree: BvhTree::new(&boxes),
+            material,
+            id: shape_id(),
+        }
+    }
+
+    // Reads the `v`, `vt`, `vn` and `f` lines of an OBJ file; polygons are
+    // split into fans and everything else is ignored. Lightmap coordinates
+    // are not part of the format, so they come from `vl u v` lines and a
+    // fourth index in the face corners, `v/vt/vn/vl`.
+    pub fn load_obj<P: AsRef<Path>>(path: P, material: Materials<Real>) -> io::Result<Self> {
+        Self::parse_obj(&fs::read_to_string(path)?, material)
+    }
+
+    fn parse_obj(source: &str, material: Materials<Real>) -> io::Result<Self> {
+        let invalid = |line: usize, message: &str| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("line {}: {}", line + 1, message),
+            )
+        };
+        let mut positions = Vec::new();
+        let mut normals = Vec::new();
+        let mut uvs = Vec::new();
+        let mut lightmap_uvs = Vec::new();
+        let mut triangles = Vec::new();
+
+        for (number, line) in source.lines().enumerate() {
+            let mut fields = line.split_whitespace();
+            let values = |fields: std::str::SplitWhitespace, count: usize| {
+                let values: Vec<Real> = fields
+                    .take(count)
+                    .map(|field| field.parse().map_err(|_| invalid(number, "invalid number")))
+                    .collect::<io::Result<_>>()?;
+                if values.len() < count {
+                    return Err(invalid(number, "missing coordinates"));
+                }
+                Ok(values)
+            };
+            match fields.next() {
+                Some("v") => {
+                    let v = values(fields, 3)?;
+                    positions.push(Point3([v[0], v[1], v[2]].into()));
+                }
+                Some("vn") => {
+                    let v = values(fields, 3)?;
+                    normals.push(Vec3([v[0], v[1], v[2]].into()));
+                }
+                Some("vt") => {
+                    let v = values(fields, 2)?;
+                    uvs.push((v[0], v[1]));
+                }
+                Some("vl") => {
+                    let v = values(fields, 2)?;
+                    lightmap_uvs.push((v[0], v[1]));
+                }
+                Some("f") => {
+                    let corners = fields
+                        .map(|corner| {
+                            let counts = [
+                                positions.len(),
+                                uvs.len(),
+                                normals.len(),
+                                lightmap_uvs.len(),
+                            ];
+                            parse_corner(corner, counts)
+                                .ok_or_else(|| invalid(number, "invalid face corner"))
+                        })
+                        .collect::<io::Result<Vec<_>>>()?;
+                    if corners.len() < 3 {
+                        return Err(invalid(number, "face with fewer than three corners"));
+                    }
+                    for i in 1..corners.len() - 1 {
+                        let [a, b, c] = [corners[0], corners[i], corners[i + 1]];
+                        triangles.push(MeshTriangle {
+                            positions: [a.0, b.0, c.0],
+                            uvs: all_corners([a.1[0], b.1[0], c.1[0]]),
+                            normals: all_corners([a.1[1], b.1[1], c.1[1]]),
+                            lightmap_uvs: all_corners([a.1[2], b.1[2], c.1[2]]),
+                        });
+                    }
+                }
+                _ => {}
+            }
+        }
+        Ok(Self::new(
+            positions,
+            normals,
+            uvs,
+            lightmap_uvs,
+            triangles,
+            material,
+        ))
+    }
+
+    pub fn triangles(&self) -> &[MeshTriangle] {
+        &self.triangles
+    }
+
+    pub fn uv(&self, set: UvSet, triangle: usize, corner: usize) -> Option<(Real, Real)> {
+        let triangle = &self.triangles[triangle];
+        match set {
+            UvSet::Texture => triangle.uvs.map(|uvs| self.uvs[uvs[corner]]),
+            UvSet::Lightmap => triangle
+                .lightmap_uvs
+                .map(|uvs| self.lightmap_uvs[uvs[corner]]),
+        }
+    }
+
+    // Point and shading normal at `barycentric` on `triangle`; the normal
+    // falls back to the face normal when the triangle has none.
+    pub fn surface_point(&self, triangle: usize, barycentric: [Real; 3]) -> SurfacePoint {
+        let corners = &self.triangles[triangle];
+        let p = |corner: usize| &self.positions[corners.positions[corner]];
+        let point = Point3(
+            [0, 1, 2]
+                .map(|axis| {
+                    (0..3)
+                        .map(|corner| p(corner)[axis] * barycentric[corner])
+                        .sum()
+                })
+                .into(),
+        );
+        let error = [0, 1, 2].map(|axis| {
+            (0..3)
+                .map(|corner| (p(corner)[axis] * barycentric[corner]).abs())
+                .sum::<Real>()
+                * gamma(7)
+        });
+        let geometric_normal = Normal3::from(self.face_normal(triangle));
+        let interpolated = corners.normals.map(|normals| {
+            (0..3)
+                .map(|corner| &self.normals[normals[corner]] * barycentric[corner])
+                .fold(Vec3::default(), |sum, normal| sum + normal)
+        });
+        // Exporters write zero normals for faces they could not shade, and
+        // opposing corner normals can cancel out; the face stands in for both
+        let normal = match interpolated {
+            Some(normal) if normal.length_squared() > 0.0 => normal.unit().into(),
+            _ => geometric_normal.clone(),
+        };
+        SurfacePoint {
+            triangle,
+            barycentric,
+            point,
+            error: Vec3(error.into()),
+            normal,
+            geometric_normal,
+        }
+    }
+
+    pub fn face_normal(&self, triangle: usize) -> Vec3<Real> {
+        let corners = &self.triangles[triangle].positions;
+        let p0 = &self.positions[corners[0]];
+        let e1 = self.positions[corners[1]].vec_from(p0);
+        let e2 = self.positions[corners[2]].vec_from(p0);
+        e1.cross(&e2).unit()
+    }
+
+    // Area-weighted average of the face normals around each position.
+    // Positions at the same place share one normal, so uv and normal seams
+    // that split a vertex in the file still read as edges.
+    pub fn vertex_normals(&self) -> Vec<Vec3<Real>> {
+        let welded = self.welded();
+        let mut normals = vec![Vec3::default(); self.positions.len()];
+        for triangle in &self.triangles {
+            let [a, b, c] = triangle.positions;
+            let p0 = &self.positions[a];
+            let area_normal = self.positions[b]
+                .vec_from(p0)
+                .cross(&self.positions[c].vec_from(p0));
+            for &corner in &triangle.positions {
+                normals[welded[corner]] += &area_normal;
+            }
+        }
+        welded
+            .iter()
+            .map(|&index| {
+                let normal = &normals[index];
+                if normal.length_squared() > 0.0 {
+                    normal.unit()
+                } else {
+                    normal.clone()
+                }
+            })
+            .collect()
+    }
+
+    // Mean over the edges at each position of how fast the normal turns
+    // along them: 1/r on a sphere of radius r, negative where the surface
+    // is concave.
+    pub fn vertex_curvature(&self) -> Vec<Real> {
+        let welded = self.welded();
+        let normals = self.vertex_normals();
+        let mut sums = vec![(0.0, 0); self.positions.len()];
+        for triangle in &self.triangles {
+            for corner in 0..3 {
+                let i = triangle.positions[corner];
+                let j = triangle.positions[(corner + 1) % 3];
+                let edge = self.positions[j].vec_from(&self.positions[i]);
+                let length_squared = edge.length_squared();
+                if length_squared == 0.0 {
+                    continue;
+                }
+                let turn = (&normals[j] - &normals[i]).dot(&edge) / length_squared;
+                for &vertex in &[welded[i], welded[j]] {
+                    sums[vertex].0 += turn;
+                    sums[vertex].1 += 1;
+                }
+            }
+        }
+        welded
+            .iter()
+            .map(|&index| match sums[index] {
+                (sum, count) if count > 0 => sum / count as Real,
+                _ => 0.0,
+            })
+            .collect()
+    }
+
+    // First position with exactly the same coordinates as each position.
+    fn welded(&self) -> Vec<usize> {
+        let mut first = HashMap::new();
+        self.positions
+            .iter()
+            .enumerate()
+            .map(|(index, position)| {
+                let key = [position[0], position[1], position[2]].map(Real::to_bits);
+                *first.entry(key).or_insert(index)
+            })
+            .collect()
+    }
+
+    // Möller-Trumbore; returns the distance and the weights of the second
+    // and third corner.
+    fn hit_triangle(
+        &self,
+        triangle: &MeshTriangle,
+        ray: &Ray<Real>,
+        t_min: Real,
+        t_max: Real,
+    ) -> Option<(Real, Real, Real)> {
+        let p0 = &self.positions[triangle.positions[0]];
+        let e1 = self.positions[triangle.positions[1]].vec_from(p0);
+        let e2 = self.positions[triangle.positions[2]].vec_from(p0);
+        let p = ray.direction.cross(&e2);
+        let determinant = e1.dot(&p);
+        // Rays in the plane of the triangle, or close enough that rounding
+        // could flip the sign of the determinant, cannot hit it
+        let determinant_error = gamma(7) * triple_magnitude(&e1, &ray.direction, &e2);
+        if determinant.abs() <= determinant_error {
+            return None;
+        }
+        let inverse = 1.0 / determinant;
+        let s = ray.origin.vec_from(p0);
+        let u = s.dot(&p) * inverse;
+        if !(0.0..=1.0).contains(&u) {
+            return None;
+        }
+        let q = s.cross(&e1);
+        let v = ray.direction.dot(&q) * inverse;
+        if v < 0.0 || u + v > 1.0 {
+            return None;
+        }
+        let t = e2.dot(&q) * inverse;
+        if t <= t_min || t > t_max {
+            return None;
+        }
+        // Hits that rounding alone could have moved across either end of the
+        // range are dropped, like pbrt's deltaT: `t` is a ratio of two triple
+        // products, each off by at most a few roundings of its magnitude.
+        let numerator_error = gamma(8) * triple_magnitude(&e2, &s, &e1);
+        let t_error = (numerator_error + t * determinant_error) * inverse.abs();
+        if t - t_error <= t_min || t + t_error > t_max {
+            return None;
+        }
+        Some((t, u, v))
+    }
+
+    // Hit record for the point with weights `u` and `v` on the second and
+    // third corner of `triangle`.
+    fn record(
+        &self,
+        triangle: usize,
+        ray: &Ray<Real>,
+        t: Real,
+        u: Real,
+        v: Real,
+    ) -> HitRecord<Real> {
+        let surface = self.surface_point(triangle, [1.0 - u - v, u, v]);
+        // Shading normals decide the direction, but the face decides which
+        // side the ray came from
+        let normal = if surface.normal.dot(&surface.geometric_normal) < 0.0 {
+            -surface.normal
+        } else {
+            surface.normal
+        };
+        let mut rec = HitRecord::new(
+            surface.point,
+            surface.error,
+            t,
+            ray,
+            surface.geometric_normal,
+            &self.material,
+            Primitive {
+                shape: self.id,
+                triangle: triangle as u32,
+            },
+        );
+        rec.normal = if rec.front_face { normal } else { -normal };
+        rec
+    }
+
+    // Closest hits of the `lanes` of `packet` that are nearer than
+    // `closest`, written to `records`; `closest` is updated to match.
+    pub(crate) fn hit_packet(
+        &self,
+        packet: &RayPacket,
+        rays: &[Ray<Real>; 4],
+        t_min: Real,
+        lanes: Mask4,
+        closest: &mut F4,
+        records: &mut [Option<HitRecord<Real>>; 4],
+    ) {
+        let mut hits = [None; 4];
+        let mut tests = 0;
+        self.tree.traverse_packet(
+            packet,
+            F4::splat(t_min),
+            closest,
+            lanes,
+            |index, lanes, closest| {
+                tests += 1;
+                let (t, u, v) = self.hit_triangle_packet(index, packet, F4::splat(t_min), *closest);
+                let hit = t.lt(*closest) & lanes;
+                let (u, v) = (u.to_array(), v.to_array());
+                for (lane, hit_lane) in hits.iter_mut().enumerate() {
+                    if hit.lane(lane) {
+                        *hit_lane = Some((index, u[lane], v[lane]));
+                    }
+                }
+                *closest = t.select(hit, *closest);
+            },
+        );
+        stats::add(Counter::IntersectionTests, tests);
+
+        let distances = closest.to_array();
+        for (lane, hit) in hits.iter().enumerate() {
+            if let Some((triangle, u, v)) = *hit {
+                records[lane] = Some(self.record(triangle, &rays[lane], distances[lane], u, v));
+            }
+        }
+    }
+
+    // Möller-Trumbore against four rays; misses are at infinity.
+    fn hit_triangle_packet(
+        &self,
+        triangle: usize,
+        packet: &RayPacket,
+        t_min: F4,
+        t_max: F4,
+    ) -> (F4, F4, F4) {
+        let corners = &self.triangles[triangle].positions;
+        let p0 = &self.positions[corners[0]];
+        let e1 = Vec3x4::splat(&self.positions[corners[1]].vec_from(p0).0);
+        let e2 = Vec3x4::splat(&self.positions[corners[2]].vec_from(p0).0);
+        let p = packet.direction.cross(&e2);
+        let determinant = e1.dot(&p);
+        let inverse = F4::splat(1.0) / determinant;
+        let s = &packet.origin - &Vec3x4::splat(&p0.0);
+        let u = s.dot(&p) * inverse;
+        let q = s.cross(&e1);
+        let v = packet.direction.dot(&q) * inverse;
+        let t = e2.dot(&q) * inverse;
+        // Same bound on the error of `t` as `hit_triangle`
+        let numerator_error = F4::splat(gamma(8)) * e2.triple_magnitude(&s, &e1);
+        let determinant_error = F4::splat(gamma(7)) * e1.triple_magnitude(&packet.direction, &e2);
+        let t_error = (numerator_error + t.abs() * determinant_error) * inverse.abs();
+
+        let (zero, one) = (F4::splat(0.0), F4::splat(1.0));
+        let valid = determinant.abs().gt(determinant_error)
+            & u.ge(zero)
+            & u.le(one)
+            & v.ge(zero)
+            & (u + v).le(one)
+            & (t - t_error).gt(t_min)
+            & (t + t_error).le(t_max);
+        (t.select(valid, F4::splat(Real::INFINITY)), u, v)
+    }
+}
+
+impl Hit<Real> for &Mesh<Real> {
+    fn hit(self, ray: &Ray<Real>, t_min: Real, t_max: Real) -> Option<HitRecord<Real>> {
+        let mut closest = None;
+        let mut tests = 0;
+        self.tree.traverse(ray, t_min, t_max, |index, t_max| {
+            tests += 1;
+            let (t, u, v) = self.hit_triangle(&self.triangles[index], ray, t_min, t_max)?;
+            closest = Some((index, t, u, v));
+            Some(t)
+        });
+        stats::add(Counter::IntersectionTests, tests);
+
+        let (index, t, u, v) = closest?;
+        Some(self.record(index, ray, t, u, v))
+    }
+
+    fn bounding_box(self) -> Option<Aabb<Real>> {
+        Some(self.bounds.clone())
+    }
+}
+
+// `a · (b × c)` with every term made positive, the size the rounding error
+// of that triple product scales with.
+fn triple_magnitude(a: &Vec3<Real>, b: &Vec3<Real>, c: &Vec3<Real>) -> Real {
+    (0..3)
+        .map(|i| {
+            let (j, k) = ((i + 1) % 3, (i + 2) % 3);
+            a[i].abs() * ((b[j] * c[k]).abs() + (b[k] * c[j]).abs())
+        })
+        .sum()
+}
+
+fn all_corners(indices: [Option<usize>; 3]) -> Option<[usize; 3]> {
+    match indices {
+        [Some(a), Some(b), Some(c)] => Some([a, b, c]),
+        _ => None,
+    }
+}
+
+// `v`, `v/vt`, `v//vn`, `v/vt/vn` or `v/vt/vn/vl`, one-based or negative
+// from the end. Returns the position and the uv, normal and lightmap uv
+// indices; `counts` holds the length of each list in the same order.
+fn parse_corner(corner: &str, counts: [usize; 4]) -> Option<(usize, [Option<usize>; 3])> {
+    let resolve = |field: &str, count: usize| -> Option<usize> {
+        let index: i64 = field.parse().ok()?;
+        let index = if index < 0 {
+            count as i64 + index
+        } else {
+            index - 1
+        };
+        if index < 0 || index >= count as i64 {
+            return None;
+        }
+        Some(index as usize)
+    };
+    let mut fields = corner.split('/');
+    let position = resolve(fields.next()?, counts[0])?;
+    let mut attributes = [None; 3];
+    for (attribute, &count) in attributes.iter_mut().zip(&counts[1..]) {
+        *attribute = match fields.next() {
+            Some("") | None => None,
+            Some(field) => Some(resolve(field, count)?),
+        };
+    }
+    Some((position, attributes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::base::color::Color;
+    use crate::materials::Lambertian;
+
+    fn material() -> Materials<Real> {
+        Materials::Lambertian(Lambertian {
+            albedo: Color([0.5, 0.5, 0.5].into()),
+        })
+    }
+
+    #[test]
+    fn zero_vertex_normals_fall_back_to_the_face() {
+        let positions = vec![
+            Point3([0.0, 0.0, 0.0].into()),
+            Point3([1.0, 0.0, 0.0].into()),
+            Point3([0.0, 1.0, 0.0].into()),
+        ];
+        let triangle = MeshTriangle {
+            positions: [0, 1, 2],
+            normals: Some([0, 0, 0]),
+            ..MeshTriangle::default()
+        };
+        let mesh = Mesh::new(
+            positions,
+            vec![Vec3::default()],
+            Vec::new(),
+            Vec::new(),
+            vec![triangle],
+            material(),
+        );
+        let surface = mesh.surface_point(0, [0.2, 0.3, 0.5]);
+        assert_eq!(
+            surface.normal.iter().collect::<Vec<_>>(),
+            surface.geometric_normal.iter().collect::<Vec<_>>()
+        );
+        assert!((surface.normal[2] - 1.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn parses_every_attribute_of_obj_faces() {
+        let source = "\
+# a unit square
+v 0 0 0
+v 1 0 0
+v 1 1 0
+v 0 1 0
+vt 0 0
+vt 1 0
+vt 1 1
+vt 0 1
+vn 0 0 1
+vl 0.5 0.5
+vl 0.75 0.5
+vl 0.75 0.75
+vl 0.5 0.75
+o square
+f 1/1/1/1 2/2/1/2 3/3/1/3 4/4/1/4
+";
+        let mesh = Mesh::parse_obj(source, material()).unwrap();
+        // The quad is split into a fan around its first corner
+        let triangles = mesh.triangles();
+        assert_eq!(triangles.len(), 2);
+        assert_eq!(triangles[0].positions, [0, 1, 2]);
+        assert_eq!(triangles[1].positions, [0, 2, 3]);
+        assert_eq!(triangles[1].uvs, Some([0, 2, 3]));
+        assert_eq!(triangles[1].normals, Some([0, 0, 0]));
+        assert_eq!(mesh.uv(UvSet::Texture, 1, 1), Some((1.0, 1.0)));
+        assert_eq!(mesh.uv(UvSet::Lightmap, 1, 1), Some((0.75, 0.75)));
+    }
+
+    #[test]
+    fn resolves_negative_obj_indices_from_the_end() {
+        let source = "\
+v 0 0 0
+v 1 0 0
+v 0 1 0
+vt 0 0
+vt 1 0
+vt 0 1
+f -3/-3 -2/-2 -1/-1
+v 0 0 1
+f -4// -3 -1
+";
+        let mesh = Mesh::parse_obj(source, material()).unwrap();
+        let triangles = mesh.triangles();
+        assert_eq!(triangles[0].positions, [0, 1, 2]);
+        assert_eq!(triangles[0].uvs, Some([0, 1, 2]));
+        // Negative indices count from the lines read so far
+        assert_eq!(triangles[1].positions, [0, 1, 3]);
+        assert_eq!(triangles[1].uvs, None);
+        assert_eq!(triangles[1].normals, None);
+        assert_eq!(triangles[1].lightmap_uvs, None);
+    }
+
+    #[test]
+    fn rejects_malformed_obj_lines() {
+        let vertices = "v 0 0 0\nv 1 0 0\nv 0 1 0\n";
+        for (line, message) in [
+            ("v 1 2", "line 4: missing coordinates"),
+            ("vt 1 x", "line 4: invalid number"),
+            ("vl 0.5", "line 4: missing coordinates"),
+            ("f 1 2", "line 4: face with fewer than three corners"),
+            ("f 1 2 4", "line 4: invalid face corner"),
+            ("f 1 2 -4", "line 4: invalid face corner"),
+            ("f 1 2 0", "line 4: invalid face corner"),
+            ("f 1/1 2/1 3/1", "line 4: invalid face corner"),
+            ("f 1/x 2 3", "line 4: invalid face corner"),
+        ] {
+            let source = format!("{}{}\n", vertices, line);
+            let error = Mesh::parse_obj(&source, material()).unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidData, "{}", line);
+            assert_eq!(error.to_string(), message, "{}", line);
+        }
+    }
+}
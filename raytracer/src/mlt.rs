@@ -0,0 +1,430 @@
+use std::sync::Mutex;
+
+use rayon::prelude::*;
+
+use crate::base::color::Color;
+use crate::base::consts::PI;
+use crate::base::Real;
+use crate::camera::Camera;
+use crate::film::{Film, SplatBuffer};
+use crate::hittable::Hit;
+use crate::integrator::Integrator;
+use crate::sampler::{to_unit_float, Sampler};
+use crate::utils::hash;
+
+// Bounds the splats held back for ordering to a couple per mutation.
+const SPLAT_BATCH: u64 = 1024;
+
+#[derive(Debug, Clone, Default)]
+struct PrimarySample {
+    value: Real,
+    last_modified: u64,
+    backup: Real,
+    backup_modified: u64,
+}
+
+// Every value an integrator draws is one coordinate of a point in the unit
+// hypercube. `start_iteration` proposes a mutation of that point and
+// `reject` restores it; coordinates are only mutated once they are read, so
+// a path pays for the dimensions it uses.
+//
+// A fresh sampler starts with a large step, so two samplers with the same
+// seed replay the same path.
+#[derive(Debug, Clone)]
+pub struct MltSampler {
+    seed: u64,
+    draws: u64,
+    sigma: Real,
+    large_step_probability: Real,
+    samples: Vec<PrimarySample>,
+    iteration: u64,
+    large_step: bool,
+    last_large_step: u64,
+    dimension: usize,
+}
+
+// Primary sample space Metropolis light transport on top of another
+// integrator. A bootstrap of independent samples estimates the mean
+// luminance of the image, which normalises it, and picks the starting
+// points of the chains in proportion to their contribution. Each mutation
+// is either a small gaussian step of every coordinate or, with
+// `large_step_probability`, a fresh independent sample.
+#[derive(Debug, Clone)]
+pub struct Metropolis {
+    pub bootstrap_samples: u32,
+    pub chains: u32,
+    pub sigma: Real,
+    pub large_step_probability: Real,
+    pub seed: u64,
+}
+
+#[derive(Debug)]
+struct Chain {
+    sampler: MltSampler,
+    raster: (Real, Real),
+    color: Color<Real>,
+}
+
+impl MltSampler {
+    pub fn new(seed: u64, sigma: Real, large_step_probability: Real) -> Self {
+        Self {
+            seed,
+            draws: 0,
+            sigma,
+            large_step_probability,
+            samples: Vec::new(),
+            iteration: 0,
+            large_step: true,
+            last_large_step: 0,
+            dimension: 0,
+        }
+    }
+
+    pub fn start_iteration(&mut self) {
+        self.iteration += 1;
+        self.large_step = self.uniform() < self.large_step_probability;
+        self.dimension = 0;
+    }
+
+    pub fn accept(&mut self) {
+        if self.large_step {
+            self.last_large_step = self.iteration;
+        }
+    }
+
+    pub fn reject(&mut self) {
+        for sample in self.samples.iter_mut() {
+            if sample.last_modified == self.iteration {
+                sample.value = sample.backup;
+                sample.last_modified = sample.backup_modified;
+            }
+        }
+        self.iteration -= 1;
+    }
+
+    // Switches to another random stream without touching the current point.
+    fn reseed(&mut self, seed: u64) {
+        self.seed = seed;
+        self.draws = 0;
+    }
+
+    fn uniform(&mut self) -> Real {
+        let value = hash(&[self.seed, self.draws]);
+        self.draws += 1;
+        to_unit_float(value)
+    }
+
+    fn ensure_ready(&mut self, index: usize) {
+        if index >= self.samples.len() {
+            self.samples.resize(index + 1, PrimarySample::default());
+        }
+        // A coordinate nobody read since the last accepted large step would
+        // have been replaced by it
+        if self.samples[index].last_modified < self.last_large_step {
+            let value = self.uniform();
+            let sample = &mut self.samples[index];
+            sample.value = value;
+            sample.last_modified = self.last_large_step;
+        }
+
+        let sample = &self.samples[index];
+        let (mut value, last_modified) = (sample.value, sample.last_modified);
+        if self.large_step {
+            value = self.uniform();
+        } else {
+            // The small steps this coordinate missed add up to one step with
+            // a wider gaussian
+            let steps = (self.iteration - last_modified) as Real;
+            let (u1, u2) = (self.uniform(), self.uniform());
+            let normal = (-2.0 * (1.0 - u1).ln()).sqrt() * (2.0 * PI * u2).cos();
+            value += normal * self.sigma * steps.sqrt();
+            value -= value.floor();
+        }
+
+        let iteration = self.iteration;
+        let sample = &mut self.samples[index];
+        sample.backup = sample.value;
+        sample.backup_modified = sample.last_modified;
+        sample.value = value;
+        sample.last_modified = iteration;
+    }
+}
+
+impl Sampler for MltSampler {
+    // The film position is part of the primary sample, so there is no pixel
+    // to start; this only rewinds to the first coordinate.
+    fn start_pixel_sample(&mut self, _x: u32, _y: u32, _index: u32) {
+        self.dimension = 0;
+    }
+
+    fn next_1d(&mut self) -> Real {
+        let index = self.dimension;
+        self.dimension += 1;
+        self.ensure_ready(index);
+        self.samples[index].value
+    }
+
+    fn next_2d(&mut self) -> (Real, Real) {
+        (self.next_1d(), self.next_1d())
+    }
+}
+
+impl Metropolis {
+    // Runs passes of `pass_mutations` mutations per pixel until the film
+    // holds `mutations_per_pixel`, splatting every mutation to the film.
+    // Chains work through their share of a pass in batches of `SPLAT_BATCH`
+    // mutations and their splats are added in chain order after each batch,
+    // so the film sums the same way on any number of threads. `on_chain` is
+    // called on the worker thread with the mutations a chain just ran.
+    #[allow(clippy::too_many_arguments)]
+    pub fn render<'a, T, I, C, P>(
+        &self,
+        integrator: &I,
+        camera: &Camera<Real>,
+        world: &'a T,
+        film: &Mutex<Film>,
+        mutations_per_pixel: u32,
+        pass_mutations: u32,
+        on_chain: C,
+        mut on_pass: P,
+    ) where
+        T: Sync,
+        &'a T: Hit<Real>,
+        I: Integrator + Sync,
+        C: Fn(u64) + Sync,
+        P: FnMut(&Film),
+    {
+        let (width, height, first_mutation, splats) = {
+            let film = film.lock().unwrap();
+            (film.width, film.height, film.samples, film.splats())
+        };
+        let size = (width as Real, height as Real);
+        let sample = |sampler: &mut MltSampler| {
+            let (u, v) = sampler.next_2d();
+            let color = match camera.get_ray(u, v, sampler) {
+                Some(ray) => integrator.radiance(&ray, world, sampler),
+                None => Color::default(),
+            };
+            ((u * size.0, v * size.1), color)
+        };
+
+        let weights: Vec<Real> = (0..self.bootstrap_samples)
+            .into_par_iter()
+            .map(|index| contribution(&sample(&mut self.sampler(index as u64)).1))
+            .collect();
+        let mut cdf = Vec::with_capacity(weights.len());
+        let mut total = 0.0;
+        for weight in &weights {
+            total += weight;
+            cdf.push(total);
+        }
+        let mean = total / self.bootstrap_samples.max(1) as Real;
+
+        let mut chains: Vec<Chain> = if mean > 0.0 {
+            (0..self.chains.max(1))
+                .into_par_iter()
+                .map(|chain| {
+                    let choice = hash(&[self.seed, chain as u64]) as Real / u64::MAX as Real;
+                    let index = cdf
+                        .partition_point(|&weight| weight <= choice * total)
+                        .min(cdf.len() - 1);
+                    let mut sampler = self.sampler(index as u64);
+                    let (raster, color) = sample(&mut sampler);
+                    // Chains that start from the same bootstrap sample must
+                    // not mutate it the same way
+                    sampler.reseed(hash(&[self.seed, chain as u64, first_mutation as u64]));
+                    Chain {
+                        sampler,
+                        raster,
+                        color,
+                    }
+                })
+                .collect()
+        } else {
+            Vec::new()
+        };
+
+        let mut done = first_mutation;
+        while done < mutations_per_pixel {
+            let mutations = pass_mutations.max(1).min(mutations_per_pixel - done);
+            let total_mutations = mutations as u64 * width as u64 * height as u64;
+            let chain_count = chains.len() as u64;
+            let counts: Vec<u64> = (0..chain_count)
+                .map(|index| {
+                    total_mutations / chain_count + (index < total_mutations % chain_count) as u64
+                })
+                .collect();
+            let batches = counts
+                .first()
+                .map_or(0, |count| count.div_ceil(SPLAT_BATCH));
+            for batch in 0..batches {
+                let start = batch * SPLAT_BATCH;
+                let recorded: Vec<_> = chains
+                    .par_iter_mut()
+                    .zip(&counts)
+                    .map(|(chain, &count)| {
+                        let end = count.min(start + SPLAT_BATCH);
+                        for _ in start..end {
+                            chain.sampler.start_iteration();
+                            let (raster, color) = sample(&mut chain.sampler);
+                            chain.step(raster, color, mean, &splats);
+                        }
+                        on_chain(end.saturating_sub(start));
+                        SplatBuffer::take()
+                    })
+                    .collect();
+                let mut film = film.lock().unwrap();
+                for chain_splats in &recorded {
+                    film.add_splats(chain_splats);
+                }
+            }
+
+            done += mutations;
+            let mut film = film.lock().unwrap();
+            film.samples = done;
+            on_pass(&film);
+        }
+    }
+
+    fn sampler(&self, index: u64) -> MltSampler {
+        MltSampler::new(
+            hash(&[self.seed, index]),
+            self.sigma,
+            self.large_step_probability,
+        )
+    }
+}
+
+impl Chain {
+    // Splats both the proposal and the current state weighted by how likely
+    // the chain is to move, so rejected proposals still count.
+    fn step(&mut self, raster: (Real, Real), color: Color<Real>, mean: Real, splats: &SplatBuffer) {
+        let proposed = contribution(&color);
+        let current = contribution(&self.color);
+        let accept = if current > 0.0 {
+            (proposed / current).min(1.0)
+        } else {
+            1.0
+        };
+        if accept > 0.0 && proposed > 0.0 {
+            splats.add(raster.0, raster.1, &(&color * (accept * mean / proposed)));
+        }
+        if current > 0.0 {
+            splats.add(
+                self.raster.0,
+                self.raster.1,
+                &(&self.color * ((1.0 - accept) * mean / current)),
+            );
+        }
+
+        if self.sampler.uniform() < accept {
+            self.sampler.accept();
+            self.raster = raster;
+            self.color = color;
+        } else {
+            self.sampler.reject();
+        }
+    }
+}
+
+// Scalar the chains are distributed by; paths that came out invalid get none.
+fn contribution(color: &Color<Real>) -> Real {
+    let luminance = color.luminance();
+    if luminance.is_finite() && luminance > 0.0 {
+        luminance
+    } else {
+        0.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::base::point3::Point3;
+    use crate::base::vec3::Vec3;
+    use crate::hittable::{Hittable, HittableVec, Sphere};
+    use crate::integrator::PathIntegrator;
+    use crate::materials::{Lambertian, Materials};
+    use crate::sampler::IndependentSampler;
+    use num_traits::AsPrimitive;
+
+    #[test]
+    fn rejected_mutations_restore_the_point() {
+        let mut sampler = MltSampler::new(5, 0.1, 0.3);
+        let point: Vec<Real> = (0..4).map(|_| sampler.next_1d()).collect();
+        sampler.accept();
+        for _ in 0..16 {
+            sampler.start_iteration();
+            let proposal: Vec<Real> = (0..6).map(|_| sampler.next_1d()).collect();
+            assert_ne!(proposal[..4], point[..]);
+            sampler.reject();
+            let restored: Vec<Real> = sampler.samples[..4]
+                .iter()
+                .map(|sample| sample.value)
+                .collect();
+            assert_eq!(restored, point);
+        }
+    }
+
+    // A grey ground under the sky: large steps alone are independent samples
+    // of the image, so the film must average out to the mean radiance.
+    #[test]
+    fn large_steps_converge_to_the_image_mean() {
+        let world = HittableVec {
+            objects: vec![Hittable::Sphere(Sphere::new(
+                Point3([0.0, -1000.0, 0.0].into()),
+                1000.0,
+                Materials::Lambertian(Lambertian {
+                    albedo: Color([0.5, 0.5, 0.5].into()),
+                }),
+            ))],
+        };
+        let camera = Camera::new(
+            Point3([0.0, 1.0, 0.0].into()),
+            Point3([0.0, 0.8, -1.0].into()),
+            Vec3([0.0, 1.0, 0.0].into()),
+            60.0,
+            2.0,
+            0.0,
+            1.0,
+        );
+        let integrator = PathIntegrator {
+            max_depth: 8,
+            roulette: None,
+        };
+
+        let samples = 1 << 14;
+        let mut expected = Color::default();
+        let mut sampler = IndependentSampler::new(9);
+        for index in 0..samples {
+            sampler.start_pixel_sample(0, 0, index);
+            let (u, v) = sampler.next_2d();
+            let ray = camera.get_ray(u, v, &mut sampler).unwrap();
+            expected += &integrator.radiance(&ray, &world, &mut sampler);
+        }
+        let expected = expected / samples as Real;
+
+        let metropolis = Metropolis {
+            bootstrap_samples: 1 << 14,
+            chains: 64,
+            sigma: 0.01,
+            large_step_probability: 1.0,
+            seed: 3,
+        };
+        let film = Mutex::new(Film::new(32, 16));
+        metropolis.render(&integrator, &camera, &world, &film, 32, 32, |_| {}, |_| {});
+        let film = film.into_inner().unwrap();
+        let mut mean = Color::default();
+        for y in 0..film.height {
+            for x in 0..film.width {
+                mean += &film.pixel(x, y);
+            }
+        }
+        let mean = mean / (film.width * film.height) as f64;
+        for (channel, expected) in mean.iter().zip(expected.iter()) {
+            let expected: f64 = expected.as_();
+            let error = (channel - expected).abs() / expected;
+            assert!(error < 0.03, "{} against {}", channel, expected);
+        }
+    }
+}
@@ -0,0 +1,382 @@
+use std::sync::{Arc, RwLock};
+
+use rayon::prelude::*;
+
+use crate::base::color::Color;
+use crate::base::consts::PI;
+use crate::base::frame::Frame;
+use crate::base::point3::Point3;
+use crate::base::vec3::{Length, Vec3, Vec3Operations};
+use crate::base::Real;
+use crate::hittable::{Hit, HitRecord, HittableVec};
+use crate::integrator::Integrator;
+use crate::lights::Lights;
+use crate::materials::{Lambertian, Materials, Scatter};
+use crate::ray::{offset_origin, sky, Ray, RussianRoulette};
+use crate::sampler::Sampler;
+use crate::stats::{self, Counter};
+
+#[derive(Debug, Clone)]
+pub struct Photon {
+    pub position: Point3<Real>,
+    // Direction the photon was travelling in when it landed
+    pub direction: Vec3<Real>,
+    pub power: Color<Real>,
+}
+
+// Photons kept as an implicit kd-tree: every range of the array is split at
+// its middle photon on `axes[middle]`, with the photons below it on that
+// axis before it and the ones above after.
+#[derive(Debug, Clone, Default)]
+pub struct PhotonMap {
+    photons: Vec<Photon>,
+    axes: Vec<u8>,
+}
+
+// Caustics from `Lights` through metal and glass, estimated from a photon map
+// at the first diffuse surface a camera ray reaches. Everything else is path
+// traced from there, except light that arrives at that surface from an
+// emitter through metal and glass alone, which the photons already carry.
+//
+// With `alpha` set the estimate is progressive: every pass traces a new
+// photon map and gathers with a smaller radius, r² <- r² (i + alpha) / (i + 1),
+// so the average over passes converges instead of staying blurred by a fixed
+// radius.
+#[derive(Debug, Clone)]
+pub struct PhotonMapIntegrator {
+    pub max_depth: u16,
+    pub roulette: Option<RussianRoulette>,
+    pub photons: u32,
+    pub radius: Real,
+    pub alpha: Option<Real>,
+    lights: Lights,
+    pass: Arc<RwLock<PhotonPass>>,
+}
+
+#[derive(Debug, Default)]
+struct PhotonPass {
+    map: Option<PhotonMap>,
+    radius: Real,
+}
+
+impl PhotonMap {
+    pub fn new(mut photons: Vec<Photon>) -> Self {
+        let mut axes = vec![0; photons.len()];
+        build(&mut photons, &mut axes);
+        Self { photons, axes }
+    }
+
+    pub fn len(&self) -> usize {
+        self.photons.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.photons.is_empty()
+    }
+
+    pub fn for_each_within<F: FnMut(&Photon)>(&self, point: &Point3<Real>, radius: Real, mut f: F) {
+        self.search(0, self.photons.len(), point, radius * radius, &mut f);
+    }
+
+    fn search<F: FnMut(&Photon)>(
+        &self,
+        start: usize,
+        end: usize,
+        point: &Point3<Real>,
+        radius_squared: Real,
+        f: &mut F,
+    ) {
+        if start >= end {
+            return;
+        }
+        let middle = start + (end - start) / 2;
+        let photon = &self.photons[middle];
+        if photon.position.vec_from(point).length_squared() <= radius_squared {
+            f(photon);
+        }
+
+        let axis = self.axes[middle] as usize;
+        let delta = point[axis] - photon.position[axis];
+        let (near, far) = if delta < 0.0 {
+            ((start, middle), (middle + 1, end))
+        } else {
+            ((middle + 1, end), (start, middle))
+        };
+        self.search(near.0, near.1, point, radius_squared, f);
+        if delta * delta <= radius_squared {
+            self.search(far.0, far.1, point, radius_squared, f);
+        }
+    }
+}
+
+impl PhotonMapIntegrator {
+    pub fn new(
+        max_depth: u16,
+        roulette: Option<RussianRoulette>,
+        photons: u32,
+        radius: Real,
+        alpha: Option<Real>,
+        world: &HittableVec<Real>,
+    ) -> Self {
+        Self {
+            max_depth,
+            roulette,
+            photons,
+            radius,
+            alpha,
+            lights: Lights::new(world),
+            pass: Arc::new(RwLock::new(PhotonPass::default())),
+        }
+    }
+
+    fn radius_at(&self, pass: u32) -> Real {
+        let alpha = match self.alpha {
+            Some(alpha) => alpha,
+            None => return self.radius,
+        };
+        let radius_squared = (1..=pass).fold(self.radius * self.radius, |radius_squared, i| {
+            radius_squared * (i as Real + alpha) / (i as Real + 1.0)
+        });
+        radius_squared.sqrt()
+    }
+
+    // Photons that reach a diffuse surface through at least one specular
+    // bounce. Every photon has its own sample stream, so the map does not
+    // depend on how the work is split between threads.
+    fn trace_photons<'a, T, S>(&self, world: &'a T, sampler: &S, pass: u32) -> PhotonMap
+    where
+        T: Sync,
+        &'a T: Hit<Real>,
+        S: Sampler + Clone + Send + Sync,
+    {
+        if self.lights.is_empty() {
+            return PhotonMap::default();
+        }
+        let photons = (0..self.photons)
+            .into_par_iter()
+            .map_init(
+                || sampler.clone(),
+                |sampler, index| {
+                    sampler.start_pixel_sample(index, u32::MAX, pass);
+                    self.trace_photon(world, sampler)
+                },
+            )
+            .flatten()
+            .collect();
+        PhotonMap::new(photons)
+    }
+
+    fn trace_photon<'a, T, S: Sampler>(&self, world: &'a T, sampler: &mut S) -> Option<Photon>
+    where
+        &'a T: Hit<Real>,
+    {
+        let light = self.lights.sample(sampler);
+        let frame = Frame::from_normal(&light.normal);
+        let direction = frame.to_world(&Vec3::random_cosine(sampler));
+        // emit * cos / (pdf * pdf_direction) with a cosine-distributed
+        // direction, shared between all photons of the pass
+        let mut power = &light.emit * (PI / (light.pdf * self.photons as Real));
+        let mut ray = Ray {
+            origin: offset_origin(&light.point, &light.error, &light.normal, &direction),
+            direction,
+        };
+
+        for bounce in 0..self.max_depth {
+            stats::count(Counter::PhotonRays);
+            let rec = world.hit(&ray, 0.0, Real::INFINITY)?;
+            match rec.material {
+                Materials::Lambertian(_) if bounce > 0 => {
+                    return Some(Photon {
+                        position: rec.point,
+                        direction: ray.direction.unit(),
+                        power,
+                    })
+                }
+                Materials::Lambertian(_) | Materials::DiffuseLight(_) => return None,
+                Materials::Metal(_) | Materials::Dielectric(_) => {}
+            }
+            let (scattered, attenuation) = rec.material.scatter(&ray, &rec, sampler)?;
+            power = &power * &attenuation;
+            ray = scattered;
+        }
+        None
+    }
+
+    // Density estimate of the photons landing on the surface at `rec`.
+    // Photons on other surfaces within the radius would bleed into this
+    // one, so only those close to its tangent plane count.
+    fn caustics(
+        &self,
+        pass: &PhotonPass,
+        rec: &HitRecord<Real>,
+        lambertian: &Lambertian<Real>,
+    ) -> Color<Real> {
+        let map = match &pass.map {
+            Some(map) if !map.is_empty() => map,
+            _ => return Color::default(),
+        };
+        let mut power = Color::default();
+        map.for_each_within(&rec.point, pass.radius, |photon| {
+            let offset = rec.normal.dot_vector(&photon.position.vec_from(&rec.point));
+            if rec.normal.dot_vector(&photon.direction) < 0.0 && offset.abs() < 0.25 * pass.radius {
+                power += &photon.power;
+            }
+        });
+        &(&lambertian.albedo / PI) * &power / (PI * pass.radius * pass.radius)
+    }
+}
+
+impl Integrator for PhotonMapIntegrator {
+    fn radiance<'a, T, S: Sampler>(
+        &self,
+        ray: &Ray<Real>,
+        world: &'a T,
+        sampler: &mut S,
+    ) -> Color<Real>
+    where
+        &'a T: Hit<Real>,
+    {
+        let pass = self.pass.read().unwrap();
+        let mut radiance = Color::default();
+        let mut throughput = Color([1.0, 1.0, 1.0].into());
+        let mut ray = ray.clone();
+        let mut gathered = false;
+        // Metal and glass vertices since the gather point, while there has
+        // been no other diffuse one
+        let mut specular_chain = None;
+
+        for bounce in 0..self.max_depth {
+            let rec = match world.hit(&ray, 0.0, Real::INFINITY) {
+                Some(rec) => rec,
+                None => {
+                    radiance += &(&throughput * &sky(&ray));
+                    break;
+                }
+            };
+            let caustic = matches!(specular_chain, Some(vertices) if vertices > 0);
+            if !caustic || self.lights.find(&rec).is_none() {
+                radiance += &(&throughput * &rec.material.emitted(&rec));
+            }
+            match &rec.material {
+                Materials::Lambertian(lambertian) if !gathered => {
+                    radiance += &(&throughput * &self.caustics(&pass, &rec, lambertian));
+                    gathered = true;
+                    specular_chain = Some(0);
+                }
+                Materials::Lambertian(_) => specular_chain = None,
+                _ => specular_chain = specular_chain.map(|vertices| vertices + 1),
+            }
+            let (scattered, attenuation) = match rec.material.scatter(&ray, &rec, sampler) {
+                Some(scatter) => scatter,
+                None => break,
+            };
+
+            throughput = &throughput * &attenuation;
+            if let Some(roulette) = self.roulette {
+                let max = throughput.iter().cloned().fold(0.0, Real::max);
+                if bounce >= roulette.min_depth && max < 1.0 {
+                    let survival = max.min(0.95);
+                    if sampler.next_1d() >= survival {
+                        stats::count(Counter::RouletteTerminations);
+                        break;
+                    }
+                    throughput /= survival;
+                }
+            }
+            stats::count(Counter::BounceRays);
+            ray = scattered;
+        }
+        radiance
+    }
+
+    fn start_pass<'a, T, S>(&self, pass: u32, world: &'a T, sampler: &S)
+    where
+        T: Sync,
+        &'a T: Hit<Real>,
+        S: Sampler + Clone + Send + Sync,
+    {
+        if self.alpha.is_none() && self.pass.read().unwrap().map.is_some() {
+            return;
+        }
+        let map = self.trace_photons(world, sampler, pass);
+        *self.pass.write().unwrap() = PhotonPass {
+            map: Some(map),
+            radius: self.radius_at(pass),
+        };
+    }
+}
+
+// Splits every range at its middle photon along the axis the range is
+// widest on.
+fn build(photons: &mut [Photon], axes: &mut [u8]) {
+    if photons.len() <= 1 {
+        return;
+    }
+    let mut min = [Real::INFINITY; 3];
+    let mut max = [Real::NEG_INFINITY; 3];
+    for photon in photons.iter() {
+        for axis in 0..3 {
+            min[axis] = min[axis].min(photon.position[axis]);
+            max[axis] = max[axis].max(photon.position[axis]);
+        }
+    }
+    let axis = (0..3)
+        .max_by(|&a, &b| (max[a] - min[a]).partial_cmp(&(max[b] - min[b])).unwrap())
+        .unwrap();
+
+    let middle = photons.len() / 2;
+    photons.select_nth_unstable_by(middle, |a, b| {
+        a.position[axis].partial_cmp(&b.position[axis]).unwrap()
+    });
+    axes[middle] = axis as u8;
+    let (below, above) = photons.split_at_mut(middle);
+    let (below_axes, above_axes) = axes.split_at_mut(middle);
+    build(below, below_axes);
+    build(&mut above[1..], &mut above_axes[1..]);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    #[test]
+    fn range_search_matches_brute_force() {
+        let mut rng = StdRng::seed_from_u64(5);
+        let point = |rng: &mut StdRng| Point3([0, 1, 2].map(|_| rng.gen_range(-1.0, 1.0)).into());
+        // Snapped to a coarse grid, so many photons tie on the split axes
+        let snap = |point: Point3<Real>| {
+            Point3(
+                [0, 1, 2]
+                    .map(|axis| (point[axis] * 10.0).round() / 10.0)
+                    .into(),
+            )
+        };
+        let photons: Vec<Photon> = (0..2000)
+            .map(|index| Photon {
+                position: snap(point(&mut rng)),
+                direction: Vec3::default(),
+                power: Color([index as Real, 0.0, 0.0].into()),
+            })
+            .collect();
+        let map = PhotonMap::new(photons.clone());
+        assert_eq!(map.len(), photons.len());
+
+        for _ in 0..100 {
+            let center = point(&mut rng);
+            let radius = rng.gen_range(0.0, 0.5);
+            let mut found = Vec::new();
+            map.for_each_within(&center, radius, |photon| {
+                found.push(photon.power[0] as usize)
+            });
+            found.sort_unstable();
+            let expected: Vec<usize> = (0..photons.len())
+                .filter(|&index| {
+                    photons[index].position.vec_from(&center).length_squared() <= radius * radius
+                })
+                .collect();
+            assert_eq!(found, expected);
+        }
+    }
+}
@@ -0,0 +1,125 @@
+use image::{DynamicImage, ImageOutputFormat};
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{Ipv4Addr, SocketAddr, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::film::Film;
+
+#[derive(Debug)]
+pub struct PreviewServer {
+    pub address: SocketAddr,
+}
+
+struct PreviewState {
+    film: Arc<Mutex<Film>>,
+    refresh: Duration,
+    png: Vec<u8>,
+    encoded_at: Option<Instant>,
+}
+
+impl PreviewServer {
+    // Binds to the loopback interface only: the preview exposes the render
+    // to anyone who can connect, so it is never served on other interfaces.
+    pub fn start(port: u16, film: Arc<Mutex<Film>>, refresh: Duration) -> io::Result<Self> {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, port))?;
+        let address = listener.local_addr()?;
+        let mut state = PreviewState {
+            film,
+            refresh,
+            png: Vec::new(),
+            encoded_at: None,
+        };
+
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                // A client hanging up mid-response is not worth stopping for
+                let _ = state.handle(stream);
+            }
+        });
+
+        Ok(Self { address })
+    }
+}
+
+impl PreviewState {
+    fn handle(&mut self, mut stream: TcpStream) -> io::Result<()> {
+        // One stalled client would otherwise hold up every later one
+        stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+        stream.set_write_timeout(Some(Duration::from_secs(5)))?;
+        let mut request_line = String::new();
+        let mut reader = BufReader::new(stream.try_clone()?);
+        reader.read_line(&mut request_line)?;
+        let mut header = String::new();
+        while reader.read_line(&mut header)? > 2 {
+            header.clear();
+        }
+
+        let mut parts = request_line.split_whitespace();
+        let method = parts.next().unwrap_or("");
+        let path = parts.next().unwrap_or("").split('?').next().unwrap_or("");
+        match (method, path) {
+            ("GET", "/") => {
+                let page = self.page();
+                respond(&mut stream, "200 OK", "text/html", page.as_bytes())
+            }
+            ("GET", "/image.png") => {
+                self.encode()?;
+                respond(&mut stream, "200 OK", "image/png", &self.png)
+            }
+            _ => respond(&mut stream, "404 Not Found", "text/plain", b"not found"),
+        }
+    }
+
+    fn encode(&mut self) -> io::Result<()> {
+        if let Some(encoded_at) = self.encoded_at {
+            if encoded_at.elapsed() < self.refresh {
+                return Ok(());
+            }
+        }
+        // Only the copy holds up the render; tone mapping and encoding
+        // work on it after the lock is released.
+        let film = self.film.lock().unwrap().clone();
+        let image = film.to_image();
+        self.png.clear();
+        DynamicImage::ImageRgb8(image)
+            .write_to(&mut self.png, ImageOutputFormat::Png)
+            .map_err(io::Error::other)?;
+        self.encoded_at = Some(Instant::now());
+        Ok(())
+    }
+
+    fn page(&self) -> String {
+        format!(
+            "<!DOCTYPE html>\n\
+             <html><head><title>raytracer preview</title></head>\n\
+             <body style=\"margin:0;background:#222\">\n\
+             <img id=\"frame\" src=\"/image.png\" style=\"max-width:100%\">\n\
+             <script>\n\
+             setInterval(function() {{\n\
+             document.getElementById('frame').src = '/image.png?' + Date.now();\n\
+             }}, {});\n\
+             </script>\n\
+             </body></html>\n",
+            self.refresh.as_millis()
+        )
+    }
+}
+
+fn respond(
+    stream: &mut TcpStream,
+    status: &str,
+    content_type: &str,
+    body: &[u8],
+) -> io::Result<()> {
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nCache-Control: no-store\r\nConnection: close\r\n\r\n",
+        status,
+        content_type,
+        body.len()
+    )?;
+    stream.write_all(body)?;
+    stream.flush()
+}
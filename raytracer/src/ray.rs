@@ -1,11 +1,16 @@
-use std::ops::{Mul, Add};
+use std::ops::{Add, Mul};
 
 use crate::base::color::Color;
+use crate::base::normal3::Normal3;
 use crate::base::point3::Point3;
+use crate::base::simd::Vec3x4;
 use crate::base::vec3::{Vec3, Vec3Operations};
+use crate::base::Real;
 use crate::base::XYZ;
-use crate::hittable::Hit;
+use crate::hittable::{Hit, HitRecord};
 use crate::materials::Scatter;
+use crate::sampler::Sampler;
+use crate::stats::{self, Counter};
 
 #[derive(Debug, Default, Clone)]
 pub struct Ray<T> {
@@ -13,31 +18,432 @@ pub struct Ray<T> {
     pub direction: Vec3<T>,
 }
 impl<T> Ray<T>
-where T: Mul<T, Output = T> + Add<T, Output = T> + Copy
+where
+    T: Mul<T, Output = T> + Add<T, Output = T> + Copy,
 {
     pub fn at(&self, t: T) -> Point3<T> {
         &self.origin + &(&self.direction * t)
     }
 }
 
-pub fn ray_color<'a, T>(ray: &Ray<f64>, world: &'a T, depth: u16) -> Color<f64>
+// Bound on the relative error of `n` chained floating point operations.
+pub(crate) fn gamma(n: i32) -> Real {
+    let epsilon = Real::EPSILON * 0.5;
+    n as Real * epsilon / (1.0 - n as Real * epsilon)
+}
+
+// Start of a ray leaving `point` along `direction`: pushed along the
+// geometric `normal` past the rounding `error` of the point, on the side the
+// ray leaves through, then rounded away from the surface. Rays spawned this
+// way cannot find the surface they start on, whatever the scale of the scene.
+pub(crate) fn offset_origin(
+    point: &Point3<Real>,
+    error: &Vec3<Real>,
+    normal: &Normal3<Real>,
+    direction: &Vec3<Real>,
+) -> Point3<Real> {
+    let distance: Real = (0..3).map(|axis| normal[axis].abs() * error[axis]).sum();
+    let mut offset = Vec3::from(normal * distance);
+    if normal.dot_vector(direction) < 0.0 {
+        offset = -offset;
+    }
+    let origin = point + &offset;
+    Point3(
+        [0, 1, 2]
+            .map(|axis| {
+                if offset[axis] > 0.0 {
+                    origin[axis].next_up()
+                } else if offset[axis] < 0.0 {
+                    origin[axis].next_down()
+                } else {
+                    origin[axis]
+                }
+            })
+            .into(),
+    )
+}
+
+// Four rays tested together, with the reciprocal directions box tests use.
+#[derive(Debug, Clone, Copy)]
+pub struct RayPacket {
+    pub origin: Vec3x4,
+    pub direction: Vec3x4,
+    pub inverse: Vec3x4,
+}
+
+impl RayPacket {
+    pub fn new(rays: &[Ray<Real>; 4]) -> Self {
+        let origin = Vec3x4::new([
+            &rays[0].origin.0,
+            &rays[1].origin.0,
+            &rays[2].origin.0,
+            &rays[3].origin.0,
+        ]);
+        let direction = Vec3x4::new([
+            &rays[0].direction.0,
+            &rays[1].direction.0,
+            &rays[2].direction.0,
+            &rays[3].direction.0,
+        ]);
+        Self {
+            origin,
+            direction,
+            inverse: direction.recip(),
+        }
+    }
+}
+
+// Paths are only rouletted once they are `min_depth` bounces long and their
+// throughput has dropped below one; survivors are reweighted by the survival
+// probability so the estimate stays unbiased.
+#[derive(Debug, Clone, Copy)]
+pub struct RussianRoulette {
+    pub min_depth: u16,
+}
+
+pub fn ray_color<'a, T, S: Sampler>(
+    ray: &Ray<Real>,
+    world: &'a T,
+    depth: u16,
+    roulette: Option<RussianRoulette>,
+    sampler: &mut S,
+) -> Color<Real>
 where
-    &'a T: Hit<f64>,
+    &'a T: Hit<Real>,
+{
+    trace(
+        ray,
+        world,
+        depth,
+        0,
+        Color([1.0, 1.0, 1.0].into()),
+        roulette,
+        sampler,
+    )
+}
+
+fn trace<'a, T, S: Sampler>(
+    ray: &Ray<Real>,
+    world: &'a T,
+    depth: u16,
+    bounce: u16,
+    throughput: Color<Real>,
+    roulette: Option<RussianRoulette>,
+    sampler: &mut S,
+) -> Color<Real>
+where
+    &'a T: Hit<Real>,
 {
     if depth == 0 {
         return Color::default();
     }
 
-    match world.hit(&ray, 0.0001, f64::INFINITY) {
-        Some(rec) => match rec.material.scatter(ray, &rec) {
-            Some((scattered, attenuation)) => {
-                attenuation * ray_color(&scattered, &world, depth - 1)
+    let rec = match world.hit(ray, 0.0, Real::INFINITY) {
+        Some(rec) => rec,
+        None => return sky(ray),
+    };
+    let emitted = rec.material.emitted(&rec);
+    match rec.material.scatter(ray, &rec, sampler) {
+        Some((scattered, mut attenuation)) => {
+            let mut throughput = &throughput * &attenuation;
+            if let Some(roulette) = roulette {
+                let max = throughput.iter().cloned().fold(0.0, Real::max);
+                if bounce >= roulette.min_depth && max < 1.0 {
+                    let survival = max.min(0.95);
+                    if sampler.next_1d() >= survival {
+                        stats::count(Counter::RouletteTerminations);
+                        return emitted;
+                    }
+                    attenuation /= survival;
+                    throughput /= survival;
+                }
+            }
+            stats::count(Counter::BounceRays);
+            emitted
+                + attenuation
+                    * trace(
+                        &scattered,
+                        world,
+                        depth - 1,
+                        bounce + 1,
+                        throughput,
+                        roulette,
+                        sampler,
+                    )
+        }
+        None => emitted,
+    }
+}
+
+// Same estimate as `ray_color`, but walks the path in a loop and carries
+// throughput and radiance explicitly, so the depth is not bounded by the
+// stack.
+pub fn path_trace<'a, T, S: Sampler>(
+    ray: &Ray<Real>,
+    world: &'a T,
+    max_depth: u16,
+    roulette: Option<RussianRoulette>,
+    sampler: &mut S,
+) -> Color<Real>
+where
+    &'a T: Hit<Real>,
+{
+    if max_depth == 0 {
+        return Color::default();
+    }
+    path_trace_from(
+        ray,
+        world.hit(ray, 0.0, Real::INFINITY),
+        world,
+        max_depth,
+        roulette,
+        sampler,
+    )
+}
+
+// `path_trace` for a ray whose closest hit, `hit`, is already known.
+pub fn path_trace_from<'a, T, S: Sampler>(
+    ray: &Ray<Real>,
+    hit: Option<HitRecord<Real>>,
+    world: &'a T,
+    max_depth: u16,
+    roulette: Option<RussianRoulette>,
+    sampler: &mut S,
+) -> Color<Real>
+where
+    &'a T: Hit<Real>,
+{
+    let mut radiance = Color::default();
+    let mut throughput = Color([1.0, 1.0, 1.0].into());
+    let mut ray = ray.clone();
+    let mut hit = hit;
+
+    for bounce in 0..max_depth {
+        if bounce > 0 {
+            hit = world.hit(&ray, 0.0, Real::INFINITY);
+        }
+        let rec = match hit.take() {
+            Some(rec) => rec,
+            None => {
+                radiance += &(&throughput * &sky(&ray));
+                break;
+            }
+        };
+        radiance += &(&throughput * &rec.material.emitted(&rec));
+        let (scattered, attenuation) = match rec.material.scatter(&ray, &rec, sampler) {
+            Some(scatter) => scatter,
+            None => break,
+        };
+
+        throughput = &throughput * &attenuation;
+        if let Some(roulette) = roulette {
+            let max = throughput.iter().cloned().fold(0.0, Real::max);
+            if bounce >= roulette.min_depth && max < 1.0 {
+                let survival = max.min(0.95);
+                if sampler.next_1d() >= survival {
+                    stats::count(Counter::RouletteTerminations);
+                    break;
+                }
+                throughput /= survival;
+            }
+        }
+        stats::count(Counter::BounceRays);
+        ray = scattered;
+    }
+    radiance
+}
+
+pub(crate) fn sky(ray: &Ray<Real>) -> Color<Real> {
+    let t = 0.5 * (ray.direction.unit().y() + 1.0);
+    Color([1.0, 1.0, 1.0].into()) * (1.0 - t) + Color([0.5, 0.7, 1.0].into()) * t
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::base::vec3::Length;
+    use crate::hittable::{Hittable, HittableVec, Sphere};
+    use crate::materials::{Dielectric, DiffuseLight, Lambertian, Materials, Metal};
+    use crate::mesh::{Mesh, MeshTriangle};
+    use crate::sampler::IndependentSampler;
+
+    fn material() -> Materials<Real> {
+        Materials::Lambertian(Lambertian {
+            albedo: Color([0.5, 0.5, 0.5].into()),
+        })
+    }
+
+    #[test]
+    fn roulette_leaves_the_mean_unchanged() {
+        // Straight down onto the top of the sphere, so every path is one
+        // cosine-sampled bounce into the sky. The sky is linear in the height
+        // of the direction, whose cosine-weighted mean is 2/3.
+        let sphere = Sphere::new(Point3([0.0, -1000.0, 0.0].into()), 1000.0, material());
+        let ray = Ray {
+            origin: Point3([0.0, 1.0, 0.0].into()),
+            direction: Vec3([0.0, -1.0, 0.0].into()),
+        };
+        let expected = sky(&Ray {
+            origin: Point3::default(),
+            direction: Vec3([(5.0 as Real).sqrt() / 3.0, 2.0 / 3.0, 0.0].into()),
+        }) * 0.5;
+
+        let count = 40000;
+        for roulette in [None, Some(RussianRoulette { min_depth: 0 })] {
+            let mut sampler = IndependentSampler::new(17);
+            let mut sum = Color::default();
+            let mut terminated = 0;
+            for index in 0..count {
+                sampler.start_pixel_sample(0, 0, index);
+                let color = ray_color(&ray, &sphere, 8, roulette, &mut sampler);
+                if color.iter().all(|&value| value == 0.0) {
+                    terminated += 1;
+                }
+                sum += &color;
+            }
+            // Half the paths survive at a throughput of 0.5
+            assert_eq!(terminated > count / 3, roulette.is_some());
+            let mean = sum / count as Real;
+            for (actual, wanted) in mean.iter().zip(expected.iter()) {
+                assert!(
+                    (actual - wanted).abs() < 0.01,
+                    "{:?} against {:?} with {:?}",
+                    mean,
+                    expected,
+                    roulette
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn path_trace_matches_ray_color() {
+        let sphere = |center: [Real; 3], radius: Real, material: Materials<Real>| {
+            Hittable::Sphere(Sphere::new(Point3(center.into()), radius, material))
+        };
+        let world = HittableVec {
+            objects: vec![
+                sphere([0.0, -1000.0, 0.0], 1000.0, material()),
+                sphere(
+                    [-1.2, 0.5, 0.0],
+                    0.5,
+                    Materials::Metal(Metal {
+                        albedo: Color([0.9, 0.6, 0.3].into()),
+                        fuzz: 0.2,
+                    }),
+                ),
+                sphere(
+                    [0.0, 0.5, 0.0],
+                    0.5,
+                    Materials::Dielectric(Dielectric { ref_idx: 1.5 }),
+                ),
+                sphere(
+                    [1.2, 0.5, 0.0],
+                    0.5,
+                    Materials::DiffuseLight(DiffuseLight {
+                        emit: Color([4.0, 4.0, 4.0].into()),
+                    }),
+                ),
+            ],
+        };
+
+        for roulette in [None, Some(RussianRoulette { min_depth: 2 })] {
+            // Same seed, so both walk the same paths
+            let mut recursive = IndependentSampler::new(19);
+            let mut iterative = IndependentSampler::new(19);
+            for index in 0..512 {
+                recursive.start_pixel_sample(0, 0, index);
+                iterative.start_pixel_sample(0, 0, index);
+                let (x, y) = recursive.next_2d();
+                iterative.next_2d();
+                let ray = Ray {
+                    origin: Point3([0.0, 1.0, 3.0].into()),
+                    direction: Vec3([3.0 * x - 1.5, -y, -1.0].into()),
+                };
+                let expected = ray_color(&ray, &world, 16, roulette, &mut recursive);
+                let actual = path_trace(&ray, &world, 16, roulette, &mut iterative);
+                for (a, b) in actual.iter().zip(expected.iter()) {
+                    assert!(
+                        (a - b).abs() <= 1e-4 * (1.0 + b.abs()),
+                        "{:?} against {:?} for sample {}",
+                        actual,
+                        expected,
+                        index
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn spawned_rays_miss_the_ground_sphere() {
+        let sphere = Sphere::new(Point3([0.0, -1000.0, 0.0].into()), 1000.0, material());
+        let mut sampler = IndependentSampler::new(11);
+        for index in 0..256 {
+            sampler.start_pixel_sample(0, 0, index);
+            // Down onto the top of the sphere from a little way off
+            let (x, z) = sampler.next_2d();
+            let origin = Point3([40.0 * x - 20.0, 3.0, 40.0 * z - 20.0].into());
+            let direction = &Vec3::random_unit(&mut sampler) - &Vec3([0.0, 2.0, 0.0].into());
+            let ray = Ray { origin, direction };
+            let rec = (&sphere).hit(&ray, 0.0, Real::INFINITY).unwrap();
+
+            for _ in 0..16 {
+                let direction = Vec3::random_unit(&mut sampler);
+                let cosine = rec.geometric_normal.dot_vector(&direction);
+                let hit = (&sphere).hit(&rec.spawn_ray(direction), 0.0, Real::INFINITY);
+                if cosine > 0.0 {
+                    assert!(hit.is_none(), "left the sphere but hit it at {:?}", hit);
+                } else {
+                    // Into the sphere, where only its far side can be hit
+                    let chord = -2.0 * cosine * sphere.radius;
+                    let distance = hit.unwrap().point.vec_from(&rec.point).length();
+                    assert!(
+                        distance > 0.5 * chord,
+                        "{} into a chord of {}",
+                        distance,
+                        chord
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn spawned_rays_miss_a_tiny_triangle() {
+        // A millimetre across, far from the origin
+        let corner = |x: Real, y: Real| Point3([1000.3 + x, 2.1 + y, -7.7 + 0.5 * x].into());
+        let mesh = Mesh::new(
+            vec![corner(0.0, 0.0), corner(1e-3, 0.0), corner(0.0, 1e-3)],
+            Vec::new(),
+            Vec::new(),
+            Vec::new(),
+            vec![MeshTriangle {
+                positions: [0, 1, 2],
+                ..MeshTriangle::default()
+            }],
+            material(),
+        );
+        let mut sampler = IndependentSampler::new(13);
+        for index in 0..256 {
+            sampler.start_pixel_sample(0, 0, index);
+            let (u, v) = sampler.next_2d();
+            let target = &corner(0.0, 0.0) + &Vec3([u * 2e-4, v * 2e-4, u * 1e-4].into());
+            let direction = Vec3::random_unit(&mut sampler);
+            let ray = Ray {
+                origin: &target - &direction,
+                direction,
+            };
+            let rec = match (&mesh).hit(&ray, 0.0, Real::INFINITY) {
+                Some(rec) => rec,
+                None => continue,
+            };
+
+            for _ in 0..16 {
+                let direction = Vec3::random_unit(&mut sampler);
+                let hit = (&mesh).hit(&rec.spawn_ray(direction), 0.0, Real::INFINITY);
+                assert!(hit.is_none(), "left the triangle but hit it at {:?}", hit);
             }
-            None => Color::default(),
-        },
-        None => {
-            let t = 0.5 * (ray.direction.unit().y() + 1.0);
-            Color([1.0, 1.0, 1.0].into()) * (1.0 - t) + Color([0.5, 0.7, 1.0].into()) * t
         }
     }
 }
@@ -0,0 +1,336 @@
+use std::collections::BTreeMap;
+use std::ops::Range;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+use crate::base::color::Color;
+use crate::base::consts::PI;
+use crate::base::Real;
+use crate::film::{Film, FilmTile};
+use crate::sampler::Sampler;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TileOrder {
+    Scanline,
+    Spiral,
+    Hilbert,
+}
+
+#[derive(Debug, Clone)]
+pub struct Tile {
+    pub index: usize,
+    pub x0: u32,
+    pub y0: u32,
+    pub x1: u32,
+    pub y1: u32,
+}
+
+#[derive(Debug, Clone)]
+pub struct Renderer {
+    pub width: u32,
+    pub height: u32,
+    pub samples_per_pixel: u32,
+    pub pass_samples: u32,
+    pub tile_size: u32,
+    pub order: TileOrder,
+}
+
+impl Tile {
+    pub fn width(&self) -> u32 {
+        self.x1 - self.x0
+    }
+
+    pub fn height(&self) -> u32 {
+        self.y1 - self.y0
+    }
+}
+
+impl Renderer {
+    pub fn new(width: u32, height: u32, samples_per_pixel: u32) -> Self {
+        Self {
+            width,
+            height,
+            samples_per_pixel,
+            pass_samples: samples_per_pixel,
+            tile_size: 32,
+            order: TileOrder::Spiral,
+        }
+    }
+
+    pub fn tiles(&self) -> Vec<Tile> {
+        let tile_size = self.tile_size.max(1);
+        let tiles_x = self.width.div_ceil(tile_size);
+        let tiles_y = self.height.div_ceil(tile_size);
+
+        let mut coords: Vec<(u32, u32)> = (0..tiles_y)
+            .flat_map(|ty| (0..tiles_x).map(move |tx| (tx, ty)))
+            .collect();
+        match self.order {
+            TileOrder::Scanline => {}
+            TileOrder::Spiral => {
+                let cx = (tiles_x as Real - 1.0) / 2.0;
+                let cy = (tiles_y as Real - 1.0) / 2.0;
+                let key = |&(tx, ty): &(u32, u32)| {
+                    let dx = tx as Real - cx;
+                    let dy = ty as Real - cy;
+                    let ring = dx.abs().max(dy.abs()).round();
+                    let angle = dy.atan2(dx).rem_euclid(2.0 * PI);
+                    (ring, angle)
+                };
+                coords.sort_by(|a, b| key(a).partial_cmp(&key(b)).unwrap());
+            }
+            TileOrder::Hilbert => {
+                let n = tiles_x.max(tiles_y).next_power_of_two();
+                coords.sort_by_key(|&(tx, ty)| hilbert_index(n, tx, ty));
+            }
+        }
+
+        coords
+            .into_iter()
+            .enumerate()
+            .map(|(index, (tx, ty))| Tile {
+                index,
+                x0: tx * tile_size,
+                y0: ty * tile_size,
+                x1: ((tx + 1) * tile_size).min(self.width),
+                y1: ((ty + 1) * tile_size).min(self.height),
+            })
+            .collect()
+    }
+
+    pub fn passes(&self) -> u32 {
+        self.samples_per_pixel.div_ceil(self.pass_samples.max(1))
+    }
+
+    // Renders pass after pass of `pass_samples` over the whole frame until
+    // the film holds `samples_per_pixel`, so a film restored from a
+    // checkpoint just continues where it stopped. Tiles are handed out in
+    // `order` from a shared counter, so the image fills in the chosen
+    // pattern no matter how rayon schedules the workers. Film tiles overlap
+    // by the filter radius, so finished tiles are merged in tile order, with
+    // the splats recorded while rendering them, to sum every pixel the same
+    // way on any number of threads. Pixels are
+    // handed to `radiance` in 2x2 blocks, one sampler per pixel, so their
+    // camera rays can be traced as a packet; pixels of the block outside the
+    // tile are None. `on_pass` gets the samples per pixel done after each pass,
+    // with the film unlocked, so work between passes does not hold up
+    // anyone else reading it.
+    pub fn render<S, F, C, P>(
+        &self,
+        film: &Mutex<Film>,
+        sampler: &S,
+        radiance: F,
+        on_tile: C,
+        mut on_pass: P,
+    ) where
+        S: Sampler + Clone + Sync,
+        F: Fn(&mut [S; 4], [Option<(Real, Real)>; 4]) -> [Color<Real>; 4] + Sync,
+        C: Fn(&Tile, &FilmTile) + Sync,
+        P: FnMut(u32),
+    {
+        let tiles = self.tiles();
+        let mut first_sample = film.lock().unwrap().samples;
+
+        while first_sample < self.samples_per_pixel {
+            let samples = self
+                .pass_samples
+                .max(1)
+                .min(self.samples_per_pixel - first_sample);
+            let next_tile = AtomicUsize::new(0);
+            let finished = Mutex::new((0, BTreeMap::new()));
+
+            rayon::scope(|scope| {
+                for _ in 0..rayon::current_num_threads() {
+                    scope.spawn(|_| {
+                        let mut samplers = [(); 4].map(|_| sampler.clone());
+                        while let Some(tile) = tiles.get(next_tile.fetch_add(1, Ordering::Relaxed))
+                        {
+                            let mut film_tile = film.lock().unwrap().film_tile(tile);
+                            self.render_tile(
+                                tile,
+                                &mut film_tile,
+                                first_sample..first_sample + samples,
+                                &mut samplers,
+                                &radiance,
+                            );
+                            film_tile.collect_splats();
+                            on_tile(tile, &film_tile);
+
+                            let mut finished = finished.lock().unwrap();
+                            let (next_merge, pending) = &mut *finished;
+                            pending.insert(tile.index, film_tile);
+                            while let Some(film_tile) = pending.remove(next_merge) {
+                                film.lock().unwrap().merge_tile(&film_tile);
+                                *next_merge += 1;
+                            }
+                        }
+                    });
+                }
+            });
+
+            first_sample += samples;
+            film.lock().unwrap().samples = first_sample;
+            on_pass(first_sample);
+        }
+    }
+
+    fn render_tile<S, F>(
+        &self,
+        tile: &Tile,
+        film_tile: &mut FilmTile,
+        samples: Range<u32>,
+        samplers: &mut [S; 4],
+        radiance: &F,
+    ) where
+        S: Sampler,
+        F: Fn(&mut [S; 4], [Option<(Real, Real)>; 4]) -> [Color<Real>; 4],
+    {
+        const BLOCK: [(u32, u32); 4] = [(0, 0), (1, 0), (0, 1), (1, 1)];
+        for y in (tile.y0..tile.y1).step_by(2) {
+            for x in (tile.x0..tile.x1).step_by(2) {
+                for i in samples.clone() {
+                    let mut positions = [None; 4];
+                    for (lane, &(bx, by)) in BLOCK.iter().enumerate() {
+                        let (px, py) = (x + bx, y + by);
+                        if px < tile.x1 && py < tile.y1 {
+                            let sampler = &mut samplers[lane];
+                            sampler.start_pixel_sample(px, py, i);
+                            let (dx, dy) = sampler.next_2d();
+                            positions[lane] = Some((px as Real + dx, py as Real + dy));
+                        }
+                    }
+                    let colors = radiance(samplers, positions);
+                    for (position, color) in positions.iter().zip(colors.iter()) {
+                        if let Some((film_x, film_y)) = position {
+                            film_tile.add_sample(*film_x, *film_y, color);
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn hilbert_index(n: u32, mut x: u32, mut y: u32) -> u64 {
+    let mut index = 0;
+    let mut s = n / 2;
+    while s > 0 {
+        let rx = (x & s > 0) as u32;
+        let ry = (y & s > 0) as u32;
+        index += s as u64 * s as u64 * ((3 * rx) ^ ry) as u64;
+        if ry == 0 {
+            if rx == 1 {
+                x = n - 1 - x;
+                y = n - 1 - y;
+            }
+            std::mem::swap(&mut x, &mut y);
+        }
+        s /= 2;
+    }
+    index
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filter::Filter;
+    use crate::sampler::IndependentSampler;
+
+    fn render_on_threads(renderer: &Renderer, threads: usize) -> Film {
+        let mut film = Film::new(renderer.width, renderer.height);
+        film.filter = Filter::Gaussian {
+            radius: 1.5,
+            sigma: 0.5,
+        };
+        let film = Mutex::new(film);
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .build()
+            .unwrap();
+        pool.install(|| {
+            renderer.render(
+                &film,
+                &IndependentSampler::new(7),
+                |samplers, positions| {
+                    let mut colors: [Color<Real>; 4] = Default::default();
+                    for (lane, sampler) in samplers.iter_mut().enumerate() {
+                        if let Some((x, y)) = positions[lane] {
+                            let u = sampler.next_1d();
+                            colors[lane] = Color([x * 1e3 + u, y.sin() * 1e-3, u].into());
+                        }
+                    }
+                    colors
+                },
+                |_, _| {},
+                |_| {},
+            )
+        });
+        film.into_inner().unwrap()
+    }
+
+    #[test]
+    fn films_match_across_thread_counts() {
+        let mut renderer = Renderer::new(37, 21, 4);
+        renderer.pass_samples = 2;
+        renderer.tile_size = 8;
+        let single = render_on_threads(&renderer, 1);
+        // Workers race for tiles, so give them a few chances to finish out
+        // of order
+        for _ in 0..4 {
+            let parallel = render_on_threads(&renderer, 8);
+            for y in 0..renderer.height {
+                for x in 0..renderer.width {
+                    let channels =
+                        |film: &Film| film.pixel(x, y).iter().cloned().collect::<Vec<_>>();
+                    assert_eq!(
+                        channels(&single),
+                        channels(&parallel),
+                        "pixel ({}, {})",
+                        x,
+                        y
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn tiles_cover_every_pixel_once() {
+        for order in [TileOrder::Scanline, TileOrder::Spiral, TileOrder::Hilbert] {
+            let mut renderer = Renderer::new(37, 21, 1);
+            renderer.tile_size = 8;
+            renderer.order = order;
+            let mut covered = vec![0; 37 * 21];
+            for (index, tile) in renderer.tiles().iter().enumerate() {
+                assert_eq!(tile.index, index);
+                for y in tile.y0..tile.y1 {
+                    for x in tile.x0..tile.x1 {
+                        covered[(y * 37 + x) as usize] += 1;
+                    }
+                }
+            }
+            assert!(covered.iter().all(|&count| count == 1), "{:?}", order);
+        }
+    }
+
+    #[test]
+    fn hilbert_index_is_a_permutation() {
+        for n in [1, 2, 4, 8, 16] {
+            let mut indices: Vec<u64> = (0..n)
+                .flat_map(|y| (0..n).map(move |x| hilbert_index(n, x, y)))
+                .collect();
+            indices.sort_unstable();
+            assert!(indices.iter().copied().eq(0..(n * n) as u64), "n = {}", n);
+        }
+    }
+
+    #[test]
+    fn passes_round_up() {
+        let mut renderer = Renderer::new(1, 1, 10);
+        assert_eq!(renderer.passes(), 1);
+        for (pass_samples, passes) in [(0, 10), (1, 10), (3, 4), (5, 2), (10, 1), (16, 1)] {
+            renderer.pass_samples = pass_samples;
+            assert_eq!(renderer.passes(), passes, "pass_samples = {}", pass_samples);
+        }
+    }
+}
@@ -0,0 +1,416 @@
+use crate::base::Real;
+use crate::utils::{hash, mix_bits};
+
+const ONE_MINUS_EPSILON: Real = 1.0 - Real::EPSILON / 2.0;
+
+const PRIMES: [u64; 64] = [
+    2, 3, 5, 7, 11, 13, 17, 19, 23, 29, 31, 37, 41, 43, 47, 53, 59, 61, 67, 71, 73, 79, 83, 89, 97,
+    101, 103, 107, 109, 113, 127, 131, 137, 139, 149, 151, 157, 163, 167, 173, 179, 181, 191, 193,
+    197, 199, 211, 223, 227, 229, 233, 239, 241, 251, 257, 263, 269, 271, 277, 281, 283, 293, 307,
+    311,
+];
+
+pub trait Sampler {
+    fn start_pixel_sample(&mut self, x: u32, y: u32, index: u32);
+    fn next_1d(&mut self) -> Real;
+    fn next_2d(&mut self) -> (Real, Real);
+}
+
+#[derive(Debug, Clone)]
+pub struct IndependentSampler {
+    seed: u64,
+    pixel: u64,
+    index: u32,
+    dimension: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct StratifiedSampler {
+    samples_per_pixel: u32,
+    x_strata: u32,
+    seed: u64,
+    pixel: u64,
+    index: u32,
+    dimension: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct HaltonSampler {
+    seed: u64,
+    pixel: u64,
+    index: u32,
+    dimension: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct SobolSampler {
+    seed: u64,
+    pixel: u64,
+    index: u32,
+    dimension: u64,
+}
+
+#[derive(Debug, Clone)]
+pub enum Samplers {
+    Independent(IndependentSampler),
+    Stratified(StratifiedSampler),
+    Halton(HaltonSampler),
+    Sobol(SobolSampler),
+}
+
+impl IndependentSampler {
+    pub fn new(seed: u64) -> Self {
+        Self {
+            seed,
+            pixel: 0,
+            index: 0,
+            dimension: 0,
+        }
+    }
+}
+
+impl Sampler for IndependentSampler {
+    fn start_pixel_sample(&mut self, x: u32, y: u32, index: u32) {
+        self.pixel = pixel_hash(x, y);
+        self.index = index;
+        self.dimension = 0;
+    }
+
+    // Every value is derived from (seed, pixel, sample, dimension) alone, so
+    // the result does not depend on which thread renders the pixel.
+    fn next_1d(&mut self) -> Real {
+        let value = hash(&[self.pixel, self.index as u64, self.dimension, self.seed]);
+        self.dimension += 1;
+        to_unit_float(value)
+    }
+
+    fn next_2d(&mut self) -> (Real, Real) {
+        (self.next_1d(), self.next_1d())
+    }
+}
+
+impl StratifiedSampler {
+    pub fn new(samples_per_pixel: u32, seed: u64) -> Self {
+        let samples_per_pixel = samples_per_pixel.max(1);
+        // The largest divisor of the sample count up to its square root, so
+        // every sample has a 2D stratum of its own even when the count is not
+        // a square; a prime count gets a single column of strata.
+        let x_strata = (1..=(samples_per_pixel as Real).sqrt() as u32)
+            .rev()
+            .find(|&strata| samples_per_pixel.is_multiple_of(strata))
+            .unwrap_or(1);
+        Self {
+            samples_per_pixel,
+            x_strata,
+            seed,
+            pixel: 0,
+            index: 0,
+            dimension: 0,
+        }
+    }
+
+    fn jitter(&self, hash_value: u64, offset: u64) -> Real {
+        to_unit_float(mix_bits(hash_value ^ ((self.index as u64) << 8) ^ offset))
+    }
+}
+
+impl Sampler for StratifiedSampler {
+    fn start_pixel_sample(&mut self, x: u32, y: u32, index: u32) {
+        self.pixel = pixel_hash(x, y);
+        self.index = index;
+        self.dimension = 0;
+    }
+
+    fn next_1d(&mut self) -> Real {
+        let h = hash(&[self.pixel, self.dimension, self.seed]);
+        self.dimension += 1;
+        let stratum = permutation_element(
+            self.index % self.samples_per_pixel,
+            self.samples_per_pixel,
+            h as u32,
+        );
+        (stratum as Real + self.jitter(h, 0)) / self.samples_per_pixel as Real
+    }
+
+    fn next_2d(&mut self) -> (Real, Real) {
+        let h = hash(&[self.pixel, self.dimension, self.seed]);
+        self.dimension += 2;
+        let count = self.samples_per_pixel;
+        let (x_strata, y_strata) = (self.x_strata, count / self.x_strata);
+        let stratum = permutation_element(self.index % count, count, h as u32);
+        (
+            ((stratum % x_strata) as Real + self.jitter(h, 1)) / x_strata as Real,
+            ((stratum / x_strata) as Real + self.jitter(h, 2)) / y_strata as Real,
+        )
+    }
+}
+
+impl HaltonSampler {
+    pub fn new(seed: u64) -> Self {
+        Self {
+            seed,
+            pixel: 0,
+            index: 0,
+            dimension: 0,
+        }
+    }
+}
+
+impl Sampler for HaltonSampler {
+    fn start_pixel_sample(&mut self, x: u32, y: u32, index: u32) {
+        self.pixel = pixel_hash(x, y);
+        self.index = index;
+        self.dimension = 0;
+    }
+
+    fn next_1d(&mut self) -> Real {
+        let h = hash(&[self.pixel, self.dimension, self.seed]);
+        let dimension = self.dimension as usize;
+        self.dimension += 1;
+        match PRIMES.get(dimension) {
+            // Cranley-Patterson rotation decorrelates the pixels sharing the sequence
+            Some(&base) => {
+                let value = radical_inverse(base, self.index as u64) + to_unit_float(h);
+                (value - value.floor()).min(ONE_MINUS_EPSILON)
+            }
+            None => to_unit_float(mix_bits(h ^ self.index as u64)),
+        }
+    }
+
+    fn next_2d(&mut self) -> (Real, Real) {
+        (self.next_1d(), self.next_1d())
+    }
+}
+
+impl SobolSampler {
+    pub fn new(seed: u64) -> Self {
+        Self {
+            seed,
+            pixel: 0,
+            index: 0,
+            dimension: 0,
+        }
+    }
+}
+
+impl Sampler for SobolSampler {
+    fn start_pixel_sample(&mut self, x: u32, y: u32, index: u32) {
+        self.pixel = pixel_hash(x, y);
+        self.index = index;
+        self.dimension = 0;
+    }
+
+    // Every dimension gets its own shuffled and Owen-scrambled copy of the
+    // first Sobol dimensions, so the sequence never runs out of dimensions.
+    fn next_1d(&mut self) -> Real {
+        let h = hash(&[self.pixel, self.dimension, self.seed]);
+        self.dimension += 1;
+        let index = nested_uniform_scramble(self.index, h as u32);
+        to_unit_float_u32(nested_uniform_scramble(
+            index.reverse_bits(),
+            (h >> 32) as u32,
+        ))
+    }
+
+    fn next_2d(&mut self) -> (Real, Real) {
+        let h = hash(&[self.pixel, self.dimension, self.seed]);
+        self.dimension += 2;
+        let index = nested_uniform_scramble(self.index, h as u32);
+        let h2 = mix_bits(h);
+        (
+            to_unit_float_u32(nested_uniform_scramble(
+                index.reverse_bits(),
+                (h >> 32) as u32,
+            )),
+            to_unit_float_u32(nested_uniform_scramble(sobol_second(index), h2 as u32)),
+        )
+    }
+}
+
+impl Sampler for Samplers {
+    fn start_pixel_sample(&mut self, x: u32, y: u32, index: u32) {
+        match self {
+            Samplers::Independent(sampler) => sampler.start_pixel_sample(x, y, index),
+            Samplers::Stratified(sampler) => sampler.start_pixel_sample(x, y, index),
+            Samplers::Halton(sampler) => sampler.start_pixel_sample(x, y, index),
+            Samplers::Sobol(sampler) => sampler.start_pixel_sample(x, y, index),
+        }
+    }
+
+    fn next_1d(&mut self) -> Real {
+        match self {
+            Samplers::Independent(sampler) => sampler.next_1d(),
+            Samplers::Stratified(sampler) => sampler.next_1d(),
+            Samplers::Halton(sampler) => sampler.next_1d(),
+            Samplers::Sobol(sampler) => sampler.next_1d(),
+        }
+    }
+
+    fn next_2d(&mut self) -> (Real, Real) {
+        match self {
+            Samplers::Independent(sampler) => sampler.next_2d(),
+            Samplers::Stratified(sampler) => sampler.next_2d(),
+            Samplers::Halton(sampler) => sampler.next_2d(),
+            Samplers::Sobol(sampler) => sampler.next_2d(),
+        }
+    }
+}
+
+fn pixel_hash(x: u32, y: u32) -> u64 {
+    (x as u64) << 32 | y as u64
+}
+
+pub(crate) fn to_unit_float(value: u64) -> Real {
+    ((value >> 11) as Real * (1.0 / (1u64 << 53) as Real)).min(ONE_MINUS_EPSILON)
+}
+
+fn to_unit_float_u32(value: u32) -> Real {
+    (value as Real * (1.0 / 4_294_967_296.0)).min(ONE_MINUS_EPSILON)
+}
+
+fn radical_inverse(base: u64, mut index: u64) -> Real {
+    let inv_base = 1.0 / base as Real;
+    let mut inv_base_n = 1.0;
+    let mut reversed = 0;
+    while index > 0 {
+        let next = index / base;
+        reversed = reversed * base + (index - next * base);
+        inv_base_n *= inv_base;
+        index = next;
+    }
+    (reversed as Real * inv_base_n).min(ONE_MINUS_EPSILON)
+}
+
+fn sobol_second(mut index: u32) -> u32 {
+    let mut v = 1 << 31;
+    let mut result = 0;
+    while index != 0 {
+        if index & 1 != 0 {
+            result ^= v;
+        }
+        index >>= 1;
+        v ^= v >> 1;
+    }
+    result
+}
+
+fn laine_karras_permutation(mut x: u32, seed: u32) -> u32 {
+    x = x.wrapping_add(seed);
+    x ^= x.wrapping_mul(0x6c50_b47c);
+    x ^= x.wrapping_mul(0xb82f_1e52);
+    x ^= x.wrapping_mul(0xc7af_e638);
+    x ^= x.wrapping_mul(0x8d22_f6e6);
+    x
+}
+
+fn nested_uniform_scramble(x: u32, seed: u32) -> u32 {
+    laine_karras_permutation(x.reverse_bits(), seed).reverse_bits()
+}
+
+fn permutation_element(mut i: u32, length: u32, p: u32) -> u32 {
+    let mut w = length - 1;
+    w |= w >> 1;
+    w |= w >> 2;
+    w |= w >> 4;
+    w |= w >> 8;
+    w |= w >> 16;
+    loop {
+        i ^= p;
+        i = i.wrapping_mul(0xe170_893d);
+        i ^= p >> 16;
+        i ^= (i & w) >> 4;
+        i ^= p >> 8;
+        i = i.wrapping_mul(0x0929_eb3f);
+        i ^= p >> 23;
+        i ^= (i & w) >> 1;
+        i = i.wrapping_mul(1 | p >> 27);
+        i = i.wrapping_mul(0x6935_fa69);
+        i ^= (i & w) >> 11;
+        i = i.wrapping_mul(0x74dc_b303);
+        i ^= (i & w) >> 2;
+        i = i.wrapping_mul(0x9e50_1cc3);
+        i ^= (i & w) >> 2;
+        i = i.wrapping_mul(0xc860_a3df);
+        i &= w;
+        i ^= i >> 5;
+        if i < length {
+            break;
+        }
+    }
+    i.wrapping_add(p) % length
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Every cell of a `columns` by `rows` grid over the unit square holds
+    // exactly one of `points`.
+    fn assert_one_per_cell(points: &[(Real, Real)], columns: u32, rows: u32) {
+        let mut counts = vec![0; (columns * rows) as usize];
+        for &(x, y) in points {
+            assert!((0.0..1.0).contains(&x) && (0.0..1.0).contains(&y));
+            let cell = (y * rows as Real) as u32 * columns + (x * columns as Real) as u32;
+            counts[cell as usize] += 1;
+        }
+        assert!(
+            counts.iter().all(|&count| count == 1),
+            "{}x{}: {:?}",
+            columns,
+            rows,
+            counts
+        );
+    }
+
+    // The first `count` values of `dimension` at one pixel, in 1D or 2D.
+    fn draw<S: Sampler>(sampler: &mut S, count: u32, dimension: u32) -> Vec<(Real, Real)> {
+        (0..count)
+            .map(|index| {
+                sampler.start_pixel_sample(5, 9, index);
+                for _ in 0..dimension {
+                    sampler.next_1d();
+                }
+                sampler.next_2d()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn stratified_gives_every_sample_its_own_stratum() {
+        // A prime, a count that is not a square, and a square
+        for spp in [7, 10, 16] {
+            let mut sampler = StratifiedSampler::new(spp, 3);
+            let x_strata = sampler.x_strata;
+            assert_one_per_cell(&draw(&mut sampler, spp, 0), x_strata, spp / x_strata);
+            let values: Vec<_> = (0..spp)
+                .map(|index| {
+                    sampler.start_pixel_sample(5, 9, index);
+                    (sampler.next_1d(), 0.0)
+                })
+                .collect();
+            assert_one_per_cell(&values, spp, 1);
+        }
+    }
+
+    #[test]
+    fn sobol_points_fill_every_elementary_interval() {
+        let mut sampler = SobolSampler::new(11);
+        for dimension in [0, 3] {
+            let points = draw(&mut sampler, 16, dimension);
+            for columns in [1, 2, 4, 8, 16] {
+                assert_one_per_cell(&points, columns, 16 / columns);
+            }
+        }
+    }
+
+    #[test]
+    fn halton_dimensions_are_stratified_in_their_bases() {
+        let mut sampler = HaltonSampler::new(11);
+        let points = draw(&mut sampler, 16, 0);
+        let base_two: Vec<_> = points.iter().map(|&(x, _)| (x, 0.0)).collect();
+        assert_one_per_cell(&base_two, 16, 1);
+        let points = draw(&mut sampler, 27, 0);
+        let base_three: Vec<_> = points.iter().map(|&(_, y)| (y, 0.0)).collect();
+        assert_one_per_cell(&base_three, 27, 1);
+    }
+}
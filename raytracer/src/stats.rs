@@ -0,0 +1,163 @@
+use std::cell::Cell;
+use std::fmt;
+use std::time::Duration;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Counter {
+    CameraRays,
+    BounceRays,
+    ShadowRays,
+    PhotonRays,
+    IntersectionTests,
+    NodeVisits,
+    RouletteTerminations,
+}
+
+const COUNTERS: usize = 7;
+
+#[derive(Debug, Default, Clone)]
+pub struct RenderStats {
+    pub camera_rays: u64,
+    pub bounce_rays: u64,
+    pub shadow_rays: u64,
+    pub photon_rays: u64,
+    pub intersection_tests: u64,
+    pub node_visits: u64,
+    pub roulette_terminations: u64,
+    pub elapsed: Duration,
+}
+
+// Counters live per thread so the hot paths never contend on shared state;
+// `RenderStats::take` drains the calling thread's counters and
+// `RenderStats::take_all` those of every thread.
+thread_local! {
+    static THREAD_COUNTERS: [Cell<u64>; COUNTERS] = const { [const { Cell::new(0) }; COUNTERS] };
+}
+
+pub(crate) fn count(counter: Counter) {
+    add(counter, 1);
+}
+
+pub(crate) fn add(counter: Counter, value: u64) {
+    THREAD_COUNTERS.with(|counters| {
+        let cell = &counters[counter as usize];
+        cell.set(cell.get() + value);
+    });
+}
+
+impl RenderStats {
+    pub fn take() -> Self {
+        THREAD_COUNTERS.with(|counters| {
+            let value = |counter: Counter| counters[counter as usize].take();
+            Self {
+                camera_rays: value(Counter::CameraRays),
+                bounce_rays: value(Counter::BounceRays),
+                shadow_rays: value(Counter::ShadowRays),
+                photon_rays: value(Counter::PhotonRays),
+                intersection_tests: value(Counter::IntersectionTests),
+                node_visits: value(Counter::NodeVisits),
+                roulette_terminations: value(Counter::RouletteTerminations),
+                elapsed: Duration::default(),
+            }
+        })
+    }
+
+    // Drains the counters of every rayon worker and of the calling thread,
+    // which work outside the tiles, such as tracing photons, leaves behind.
+    pub fn take_all() -> Self {
+        let mut stats = Self::take();
+        for thread in rayon::broadcast(|_| Self::take()) {
+            stats.merge(&thread);
+        }
+        stats
+    }
+
+    pub fn merge(&mut self, other: &RenderStats) {
+        self.camera_rays += other.camera_rays;
+        self.bounce_rays += other.bounce_rays;
+        self.shadow_rays += other.shadow_rays;
+        self.photon_rays += other.photon_rays;
+        self.intersection_tests += other.intersection_tests;
+        self.node_visits += other.node_visits;
+        self.roulette_terminations += other.roulette_terminations;
+        self.elapsed += other.elapsed;
+    }
+
+    pub fn total_rays(&self) -> u64 {
+        self.camera_rays + self.bounce_rays + self.shadow_rays + self.photon_rays
+    }
+
+    // Segments per camera ray, counting the camera ray itself.
+    pub fn average_path_length(&self) -> f64 {
+        if self.camera_rays == 0 {
+            return 0.0;
+        }
+        (self.camera_rays + self.bounce_rays) as f64 / self.camera_rays as f64
+    }
+
+    pub fn rays_per_second(&self) -> f64 {
+        let seconds = self.elapsed.as_secs_f64();
+        if seconds == 0.0 {
+            return 0.0;
+        }
+        self.total_rays() as f64 / seconds
+    }
+
+    pub fn to_json(&self) -> String {
+        format!(
+            "{{\n  \"camera_rays\": {},\n  \"bounce_rays\": {},\n  \"shadow_rays\": {},\n  \
+             \"photon_rays\": {},\n  \"total_rays\": {},\n  \"intersection_tests\": {},\n  \
+             \"node_visits\": {},\n  \"average_path_length\": {},\n  \
+             \"roulette_terminations\": {},\n  \"elapsed_seconds\": {},\n  \
+             \"rays_per_second\": {}\n}}\n",
+            self.camera_rays,
+            self.bounce_rays,
+            self.shadow_rays,
+            self.photon_rays,
+            self.total_rays(),
+            self.intersection_tests,
+            self.node_visits,
+            self.average_path_length(),
+            self.roulette_terminations,
+            self.elapsed.as_secs_f64(),
+            self.rays_per_second()
+        )
+    }
+}
+
+impl fmt::Display for RenderStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "render statistics")?;
+        writeln!(f, "  camera rays            {:>16}", self.camera_rays)?;
+        writeln!(f, "  bounce rays            {:>16}", self.bounce_rays)?;
+        writeln!(f, "  shadow rays            {:>16}", self.shadow_rays)?;
+        writeln!(f, "  photon rays            {:>16}", self.photon_rays)?;
+        writeln!(f, "  total rays             {:>16}", self.total_rays())?;
+        writeln!(
+            f,
+            "  intersection tests     {:>16}",
+            self.intersection_tests
+        )?;
+        writeln!(f, "  node visits            {:>16}", self.node_visits)?;
+        writeln!(
+            f,
+            "  average path length    {:>16.3}",
+            self.average_path_length()
+        )?;
+        writeln!(
+            f,
+            "  roulette terminations  {:>16}",
+            self.roulette_terminations
+        )?;
+        writeln!(
+            f,
+            "  elapsed                {:>15.3}s",
+            self.elapsed.as_secs_f64()
+        )?;
+        write!(
+            f,
+            "  rays per second        {:>16.0}",
+            self.rays_per_second()
+        )
+    }
+}
@@ -0,0 +1,58 @@
+use crate::base::Real;
+use crate::camera::{Camera, Convergence};
+use crate::ray::Ray;
+use crate::sampler::Sampler;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StereoLayout {
+    SideBySide,
+    TopBottom,
+}
+
+#[derive(Debug, Clone)]
+pub struct StereoRig<T> {
+    pub left: Camera<T>,
+    pub right: Camera<T>,
+    pub layout: StereoLayout,
+}
+
+impl StereoRig<Real> {
+    pub fn new(
+        camera: &Camera<Real>,
+        interaxial: Real,
+        convergence: Convergence,
+        layout: StereoLayout,
+    ) -> Self {
+        Self {
+            left: camera.stereo_eye(-interaxial / 2.0, convergence),
+            right: camera.stereo_eye(interaxial / 2.0, convergence),
+            layout,
+        }
+    }
+
+    pub fn image_size(&self, eye_width: u32, eye_height: u32) -> (u32, u32) {
+        match self.layout {
+            StereoLayout::SideBySide => (eye_width * 2, eye_height),
+            StereoLayout::TopBottom => (eye_width, eye_height * 2),
+        }
+    }
+
+    // `x` and `y` are raster coordinates in the combined image; the left eye
+    // is placed on the left or on top.
+    pub fn get_ray<S: Sampler>(
+        &self,
+        x: Real,
+        y: Real,
+        eye_width: u32,
+        eye_height: u32,
+        sampler: &mut S,
+    ) -> Option<Ray<Real>> {
+        let (width, height) = (eye_width as Real, eye_height as Real);
+        let (camera, x, y) = match self.layout {
+            StereoLayout::SideBySide if x >= width => (&self.right, x - width, y),
+            StereoLayout::TopBottom if y >= height => (&self.right, x, y - height),
+            _ => (&self.left, x, y),
+        };
+        camera.get_ray(x / width, y / height, sampler)
+    }
+}
@@ -7,3 +7,18 @@ pub fn clamp<T: PartialOrd>(x: T, min: T, max: T) -> T {
     };
     x
 }
+
+pub fn mix_bits(mut v: u64) -> u64 {
+    v ^= v >> 31;
+    v = v.wrapping_mul(0x7fb5_d329_728e_a185);
+    v ^= v >> 27;
+    v = v.wrapping_mul(0x81da_def4_bc2d_d44d);
+    v ^= v >> 33;
+    v
+}
+
+pub fn hash(values: &[u64]) -> u64 {
+    values.iter().fold(0x9e37_79b9_7f4a_7c15, |h, &v| {
+        mix_bits(h.rotate_left(7) ^ v.wrapping_add(0x9e37_79b9_7f4a_7c15))
+    })
+}
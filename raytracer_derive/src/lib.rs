@@ -167,8 +167,8 @@ fn base3_ops_impl(tokens: TokenStream) -> TokenStream {
         }
 
         impl<T: SampleUniform + Copy> #name<T> {
-            pub fn random(min: T, max: T) -> Self {
-                #name(Base3::random(min, max).into())
+            pub fn random<R: rand::Rng>(min: T, max: T, rng: &mut R) -> Self {
+                #name(Base3::random(min, max, rng))
             }
         }
     };